@@ -0,0 +1,1206 @@
+use axum::{
+    body::Body,
+    extract::{Json, Path, Query, State},
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::{delete, get, post},
+    Router,
+};
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tower_http::cors::{Any, CorsLayer};
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, warn};
+
+use crate::db::{self, DbPool, Manifest};
+use crate::importer;
+use crate::units::{self, UnitSystem};
+use crate::{dry_run, jobs, migrations, parser, quarantine, uploads};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum JobStatus {
+    Processing {
+        progress: usize,
+        total: Option<usize>, // estimated from the share of the file read so far
+        percent: Option<f64>,
+        bytes_read: Option<u64>,
+        total_bytes: Option<u64>,
+        records_per_second: Option<f64>,
+        eta_seconds: Option<u64>,
+        tables: HashMap<String, usize>,
+    },
+    Completed {
+        records_processed: usize,
+        records_new: usize,
+        records_skipped: usize,
+    },
+    // Some elements were quarantined, or malformed XML cut the file short
+    #[serde(rename = "completed_with_warnings")]
+    CompletedWithWarnings {
+        records_processed: usize,
+        records_new: usize,
+        records_skipped: usize,
+        records_quarantined: usize,
+        parse_error: Option<String>,
+    },
+    Failed {
+        error: String,
+    },
+    Cancelled {
+        records_processed: usize,
+        records_new: usize,
+        rolled_back: bool,
+    },
+}
+
+impl From<&jobs::JobRecord> for JobStatus {
+    fn from(job: &jobs::JobRecord) -> Self {
+        match job.status.as_str() {
+            "processing" => JobStatus::Processing {
+                progress: job.records_processed as usize,
+                total: None,
+                percent: None,
+                bytes_read: None,
+                total_bytes: None,
+                records_per_second: None,
+                eta_seconds: None,
+                tables: job
+                    .table_counts
+                    .clone()
+                    .and_then(|t| serde_json::from_value(t).ok())
+                    .unwrap_or_default(),
+            },
+            "completed" => JobStatus::Completed {
+                records_processed: job.records_processed as usize,
+                records_new: job.records_new as usize,
+                records_skipped: job.records_skipped as usize,
+            },
+            "completed_with_warnings" => JobStatus::CompletedWithWarnings {
+                records_processed: job.records_processed as usize,
+                records_new: job.records_new as usize,
+                records_skipped: job.records_skipped as usize,
+                records_quarantined: job.records_quarantined as usize,
+                parse_error: job.error.clone(),
+            },
+            "cancelled" => JobStatus::Cancelled {
+                records_processed: job.records_processed as usize,
+                records_new: job.records_new as usize,
+                rolled_back: job.rolled_back,
+            },
+            other => JobStatus::Failed {
+                error: job.error.clone().unwrap_or_else(|| other.to_string()),
+            },
+        }
+    }
+}
+
+// What is pushed to /api/ingest/{id}/events subscribers while a job runs
+#[derive(Debug, Clone)]
+pub enum JobEvent {
+    Progress(JobStatus),
+    Warning(String),
+    File(importer::ImportedFile),
+    Finished(JobStatus), // always the last event
+}
+
+impl JobEvent {
+    fn into_sse(self) -> Event {
+        let (name, data) = match self {
+            JobEvent::Progress(status) => ("progress", serde_json::json!(status)),
+            JobEvent::Warning(message) => ("warning", serde_json::json!({ "message": message })),
+            JobEvent::File(file) => ("file", serde_json::json!(file)),
+            JobEvent::Finished(status) => ("finished", serde_json::json!(status)),
+        };
+        Event::default().event(name).data(data.to_string())
+    }
+}
+
+// A job running in this process
+struct RunningJob {
+    cancel: Option<Arc<jobs::CancelToken>>, // None for jobs that cannot be cancelled
+    progress: Option<JobStatus>,            // latest progress event
+    events: broadcast::Sender<JobEvent>,
+}
+
+pub struct AppState {
+    pool: DbPool,
+    manifest: Manifest,
+    running: Mutex<HashMap<String, RunningJob>>, // job ID -> running job
+    // Held for reading while a job is created and registered, for writing while a schema
+    // migration is applied, so no job starts writing into tables being rebuilt
+    migration: Arc<RwLock<()>>,
+}
+
+impl AppState {
+    pub fn new(pool: DbPool, manifest: Manifest) -> Self {
+        AppState {
+            pool,
+            manifest,
+            running: Mutex::new(HashMap::new()),
+            migration: Arc::default(),
+        }
+    }
+
+    pub fn register_job(&self, id: &str, cancel: Option<Arc<jobs::CancelToken>>) {
+        let (events, _) = broadcast::channel(256);
+        self.running.lock().unwrap().insert(
+            id.to_string(),
+            RunningJob {
+                cancel,
+                progress: None,
+                events,
+            },
+        );
+    }
+
+    pub fn publish(&self, id: &str, event: JobEvent) {
+        if let Some(job) = self.running.lock().unwrap().get_mut(id) {
+            if let JobEvent::Progress(status) = &event {
+                job.progress = Some(status.clone());
+            }
+            // No subscribers is fine
+            let _ = job.events.send(event);
+        }
+    }
+
+    // Announces the outcome stored in the jobs table and forgets the job; both happen under
+    // one lock so a subscriber either gets the final event or finds the job finished
+    pub async fn unregister_job(&self, id: &str) {
+        let finished = match jobs::get_job(&self.pool, id).await {
+            Ok(Some(job)) => Some(JobStatus::from(&job)),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to load outcome of job {}: {:?}", id, e);
+                None
+            }
+        };
+        let mut running = self.running.lock().unwrap();
+        if let (Some(job), Some(status)) = (running.get(id), finished) {
+            let _ = job.events.send(JobEvent::Finished(status));
+        }
+        running.remove(id);
+    }
+}
+
+// Percent and ETA come from bytes read, throughput from records; rates only count this run
+// so a resumed job is not credited with the work done before the restart
+fn processing_status(
+    p: &parser::IngestProgress,
+    started: Instant,
+    start_bytes: u64,
+    start_records: usize,
+) -> JobStatus {
+    let elapsed = started.elapsed().as_secs_f64();
+    let fraction = p.fraction();
+    let byte_rate = p.bytes_read.saturating_sub(start_bytes) as f64 / elapsed;
+    let eta_seconds = p
+        .total_bytes
+        .filter(|_| byte_rate > 0.0)
+        .map(|total| (total.saturating_sub(p.bytes_read) as f64 / byte_rate).round() as u64);
+
+    JobStatus::Processing {
+        progress: p.records_processed,
+        total: fraction
+            .filter(|f| *f > 0.0)
+            .map(|f| (p.records_processed as f64 / f).round() as usize),
+        percent: fraction.map(|f| (f * 1000.0).round() / 10.0),
+        bytes_read: Some(p.bytes_read),
+        total_bytes: p.total_bytes,
+        records_per_second: (elapsed > 0.0)
+            .then(|| (p.records_processed.saturating_sub(start_records) as f64 / elapsed).round()),
+        eta_seconds,
+        tables: p.tables.clone(),
+    }
+}
+
+// Jobs still marked as processing were cut short by a crash or restart
+pub async fn resume_interrupted_jobs(state: &Arc<AppState>) -> anyhow::Result<()> {
+    for id in jobs::fail_interrupted_imports(&state.pool).await? {
+        warn!(
+            "Import job {} was interrupted by a restart and is marked failed",
+            id
+        );
+    }
+    for job in jobs::interrupted_jobs(&state.pool).await? {
+        info!("Resuming interrupted ingestion job {}", job.id);
+        spawn_ingest_job(Arc::clone(state), job);
+    }
+    Ok(())
+}
+
+pub fn router(state: Arc<AppState>) -> Router {
+    // Configure CORS
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([
+            axum::http::Method::GET,
+            axum::http::Method::POST,
+            axum::http::Method::DELETE,
+        ])
+        .allow_headers([axum::http::HeaderName::from_static("content-type")]);
+
+    Router::new()
+        .route("/", get(root))
+        .route("/health", get(health_handler))
+        .route("/ingest", post(ingest_handler))
+        .route("/ingest/dry-run", post(dry_run_handler))
+        .route("/api/ingest/status/{id}", get(get_ingest_status_handler))
+        .route("/api/ingest/{id}", delete(cancel_ingest_handler))
+        .route("/api/ingest/{id}/events", get(job_events_handler))
+        .route("/api/jobs", get(list_jobs_handler))
+        .route("/api/jobs/{id}/quarantine", get(quarantine_handler))
+        .route("/api/migrations", get(migrations_handler))
+        .route("/api/migrations/apply", post(apply_migrations_handler))
+        .route("/api/import/external", post(external_import_handler))
+        .route("/api/uploads", post(upload_handler))
+        .route("/api/ecg/{id}", get(get_ecg_handler))
+        .route("/api/workouts/{id}", get(get_workout_details_handler))
+        .route(
+            "/api/workouts/{id}/intensity",
+            get(get_workout_intensity_handler),
+        )
+        .route("/api/summary", get(get_summary_handler))
+        .route("/api/profile", get(get_profile_handler))
+        .route("/api/export/{table}", get(export_data_handler))
+        .route("/api/trends", get(get_trends_handler))
+        .route("/api/analysis/recovery", get(get_recovery_handler))
+        .route("/api/analysis/sleep", get(get_sleep_analysis_handler))
+        .route(
+            "/api/analysis/categories/{table}",
+            get(get_category_summary_handler),
+        )
+        .route("/api/hrv/{id}/rr", get(get_rr_series_handler))
+        .route("/api/data/{table}", get(get_data_handler))
+        .route("/api/aggregate/{table}", get(aggregate_handler))
+        .layer(TraceLayer::new_for_http())
+        .layer(cors)
+        .with_state(state)
+}
+
+async fn root() -> &'static str {
+    "Digital Physiologist Backend Online"
+}
+
+async fn health_handler() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "status": "healthy",
+        "timestamp": chrono::Utc::now().to_rfc3339()
+    }))
+}
+
+#[derive(Deserialize, Serialize)]
+struct IngestRequest {
+    file_path: String,
+    // Skip records already stored by the previous ingestion, see parser::IngestOptions
+    #[serde(default)]
+    incremental: bool,
+    // Fail on the first malformed record instead of quarantining it
+    #[serde(default)]
+    strict: bool,
+}
+
+#[derive(serde::Serialize)]
+struct IngestResponse {
+    message: String,
+    job_id: String,
+}
+
+// Optional caller-supplied ID stored with the job, so imports can be traced to a request
+fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get("x-request-id").and_then(|v| v.to_str().ok())
+}
+
+async fn ingest_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<IngestRequest>,
+) -> Result<Json<IngestResponse>, String> {
+    info!("Received ingestion request for: {}", payload.file_path);
+
+    let path = uploads::allowed_input_path(&state.manifest, &payload.file_path)
+        .map_err(|e| e.to_string())?;
+
+    let job_id = start_ingest_job(
+        state,
+        &path.to_string_lossy(),
+        parser::IngestOptions {
+            incremental: payload.incremental,
+            strict: payload.strict,
+        },
+        &serde_json::json!(payload),
+        request_id(&headers),
+    )
+    .await?;
+
+    Ok(Json(IngestResponse {
+        message: "Ingestion started in background".to_string(),
+        job_id,
+    }))
+}
+
+#[derive(Deserialize)]
+struct DryRunRequest {
+    file_path: String,
+}
+
+// Reports what an export contains and how the manifest maps it, without writing anything
+async fn dry_run_handler(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<DryRunRequest>,
+) -> Result<Json<dry_run::DryRunReport>, String> {
+    info!("Received dry run request for: {}", payload.file_path);
+
+    let path = uploads::allowed_input_path(&state.manifest, &payload.file_path)
+        .map_err(|e| e.to_string())?;
+
+    dry_run::dry_run(&path, &state.manifest)
+        .await
+        .map(Json)
+        .map_err(|e| format!("Dry run failed: {}", e))
+}
+
+async fn start_ingest_job(
+    state: Arc<AppState>,
+    input_path: &str,
+    options: parser::IngestOptions,
+    request: &serde_json::Value,
+    request_id: Option<&str>,
+) -> Result<String, String> {
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let _migration = Arc::clone(&state.migration).read_owned().await;
+
+    // Persist the job first so a crash mid-way can be resumed
+    jobs::create_job(
+        &state.pool,
+        &job_id,
+        "ingest",
+        input_path,
+        options,
+        request,
+        request_id,
+    )
+    .await
+    .map_err(|e| format!("Failed to create job: {}", e))?;
+
+    spawn_ingest_job(
+        state,
+        jobs::IngestJob {
+            id: job_id.clone(),
+            input_path: input_path.to_string(),
+            options,
+            checkpoint: jobs::Checkpoint::default(),
+            cancel: Arc::default(),
+        },
+    );
+    Ok(job_id)
+}
+
+// Runs a new or interrupted job in the background, from its checkpoint. Progress is
+// persisted with every committed batch and pushed to event subscribers.
+fn spawn_ingest_job(state: Arc<AppState>, job: jobs::IngestJob) {
+    state.register_job(&job.id, Some(Arc::clone(&job.cancel)));
+    tokio::spawn(async move {
+        // Batches report through one channel and a single consumer applies them in order,
+        // so an older update can never overwrite a newer one
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel::<parser::IngestProgress>();
+        let tracker = {
+            let state = Arc::clone(&state);
+            let id = job.id.clone();
+            let (started, start_bytes, start_records) = (
+                Instant::now(),
+                job.checkpoint.offset,
+                job.checkpoint.processed,
+            );
+            tokio::spawn(async move {
+                let mut quarantined = 0;
+                while let Some(p) = progress_rx.recv().await {
+                    if p.quarantined > quarantined {
+                        state.publish(
+                            &id,
+                            JobEvent::Warning(format!(
+                                "{} records quarantined so far, see /api/jobs/{}/quarantine",
+                                p.quarantined, id
+                            )),
+                        );
+                        quarantined = p.quarantined;
+                    }
+                    let status = processing_status(&p, started, start_bytes, start_records);
+                    state.publish(&id, JobEvent::Progress(status));
+                }
+            })
+        };
+        let on_progress = move |p: &parser::IngestProgress| {
+            let _ = progress_tx.send(p.clone());
+        };
+
+        let result =
+            parser::run_ingest_job(&job, &state.pool, &state.manifest, Some(on_progress)).await;
+        // The sender went away with the parser, so the tracker drains and stops
+        let _ = tracker.await;
+        // Side-car files the parser imported from export.zip
+        if let Ok(report) = &result {
+            report
+                .side_cars
+                .iter()
+                .for_each(file_events(&state, &job.id));
+            // A fully read upload is no longer needed; cut short ones are kept to retry
+            if !report.cancelled && report.parse_error.is_none() {
+                uploads::discard_upload(&state.manifest, std::path::Path::new(&job.input_path))
+                    .await;
+            }
+        }
+
+        let persisted = match result {
+            Ok(report) if report.cancelled => cancel_job(&state.pool, &job).await,
+            Ok(report) if report.quarantined > 0 || report.parse_error.is_some() => {
+                jobs::finish_job(
+                    &state.pool,
+                    &job.id,
+                    "completed_with_warnings",
+                    report.parse_error.as_deref(),
+                )
+                .await
+            }
+            Ok(_) => jobs::finish_job(&state.pool, &job.id, "completed", None).await,
+            Err(e) => {
+                error!("Ingestion failed for job {}: {:?}", job.id, e);
+                jobs::finish_job(&state.pool, &job.id, "failed", Some(&e.to_string())).await
+            }
+        };
+        if let Err(e) = persisted {
+            error!("Failed to record outcome of job {}: {:?}", job.id, e);
+        }
+        state.unregister_job(&job.id).await;
+    });
+}
+
+// Publishes imported side-car files; the ones that failed become warnings
+fn file_events(state: &Arc<AppState>, id: &str) -> impl Fn(&importer::ImportedFile) + Send + Sync {
+    let state = Arc::clone(state);
+    let id = id.to_string();
+    move |file: &importer::ImportedFile| {
+        let event = match &file.error {
+            Some(e) => JobEvent::Warning(format!(
+                "Failed to import {} {}: {}",
+                file.kind, file.file_name, e
+            )),
+            None => JobEvent::File(file.clone()),
+        };
+        state.publish(&id, event);
+    }
+}
+
+async fn cancel_job(pool: &DbPool, job: &jobs::IngestJob) -> anyhow::Result<()> {
+    if job.cancel.rollback_requested() {
+        jobs::rollback_job(pool, &job.id).await?;
+    }
+    jobs::finish_job(pool, &job.id, "cancelled", None).await
+}
+
+#[derive(Deserialize)]
+struct CancelParams {
+    rollback: Option<bool>,
+}
+
+async fn cancel_ingest_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(params): Query<CancelParams>,
+) -> Result<Json<serde_json::Value>, String> {
+    let rollback = params.rollback.unwrap_or(false);
+    let token = state
+        .running
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|job| job.cancel.clone());
+    let token = match token {
+        Some(Some(token)) => token,
+        Some(None) => return Err(format!("Job {} cannot be cancelled", id)),
+        None => {
+            return match jobs::get_job(&state.pool, &id).await {
+                Ok(Some(job)) => Err(format!("Job {} is not running ({})", id, job.status)),
+                Ok(None) => Err(format!("Job ID {} not found", id)),
+                Err(e) => Err(format!("Failed to load job {}: {}", id, e)),
+            };
+        }
+    };
+
+    info!("Cancelling job {} (rollback: {})", id, rollback);
+    token.cancel(rollback);
+    Ok(Json(serde_json::json!({
+        "message": "Cancellation requested, the job stops after its current batch",
+        "job_id": id,
+        "rollback": rollback
+    })))
+}
+
+async fn get_ingest_status_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<JobStatus>, String> {
+    match jobs::get_job(&state.pool, &id).await {
+        Ok(Some(job)) => {
+            let live = match job.status.as_str() {
+                "processing" => state
+                    .running
+                    .lock()
+                    .unwrap()
+                    .get(&id)
+                    .and_then(|job| job.progress.clone()),
+                _ => None,
+            };
+            Ok(Json(live.unwrap_or_else(|| JobStatus::from(&job))))
+        }
+        Ok(None) => Err(format!("Job ID {} not found", id)),
+        Err(e) => Err(format!("Failed to load job {}: {}", id, e)),
+    }
+}
+
+// Streams a job's events until it finishes. Subscribers start with the latest progress,
+// a job that already finished yields its final event only.
+async fn job_events_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, String> {
+    let live = state
+        .running
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map(|job| (job.progress.clone(), job.events.subscribe()));
+
+    let (initial, receiver) = match live {
+        Some((progress, receiver)) => (progress.map(JobEvent::Progress), Some(receiver)),
+        None => match jobs::get_job(&state.pool, &id).await {
+            Ok(Some(job)) => (Some(JobEvent::Finished(JobStatus::from(&job))), None),
+            Ok(None) => return Err(format!("Job ID {} not found", id)),
+            Err(e) => return Err(format!("Failed to load job {}: {}", id, e)),
+        },
+    };
+
+    let updates = stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(event @ JobEvent::Finished(_)) => return Some((event, None)),
+                Ok(event) => return Some((event, Some(receiver))),
+                // A slow client skips events it missed rather than holding up the job
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = stream::iter(initial)
+        .chain(updates)
+        .map(|event| Ok(event.into_sse()));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize)]
+struct ListJobsParams {
+    status: Option<String>,
+    #[serde(rename = "type")]
+    job_type: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+async fn list_jobs_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ListJobsParams>,
+) -> Result<Json<serde_json::Value>, String> {
+    let filter = jobs::JobFilter {
+        status: params.status,
+        job_type: params.job_type,
+        since: params.since,
+        until: params.until,
+    };
+    let limit = params.limit.unwrap_or(50).clamp(1, 500);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let (items, total) = jobs::list_jobs(&state.pool, &filter, limit, offset)
+        .await
+        .map_err(|e| format!("Failed to list jobs: {}", e))?;
+
+    Ok(Json(serde_json::json!({
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "jobs": items
+    })))
+}
+
+#[derive(Deserialize)]
+struct QuarantineParams {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+// Error report of a job: what it quarantined, by type and record by record
+async fn quarantine_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(params): Query<QuarantineParams>,
+) -> Result<Json<serde_json::Value>, String> {
+    let job = jobs::get_job(&state.pool, &id)
+        .await
+        .map_err(|e| format!("Failed to load job {}: {}", id, e))?
+        .ok_or_else(|| format!("Job ID {} not found", id))?;
+    let limit = params.limit.unwrap_or(100).clamp(1, 1000);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let counts = quarantine::quarantine_counts(&state.pool, &id)
+        .await
+        .map_err(|e| format!("Failed to load quarantine of job {}: {}", id, e))?;
+    let (records, total) = quarantine::list_quarantine(&state.pool, &id, limit, offset)
+        .await
+        .map_err(|e| format!("Failed to load quarantine of job {}: {}", id, e))?;
+
+    Ok(Json(serde_json::json!({
+        "job_id": id,
+        "status": job.status,
+        "parse_error": job.error.filter(|_| job.status == "completed_with_warnings"),
+        "total": total,
+        "limit": limit,
+        "offset": offset,
+        "by_type": counts,
+        "records": records
+    })))
+}
+
+// Applied schema versions, and the plan that would bring the database to the current manifest
+async fn migrations_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, String> {
+    let versions = migrations::list_versions(&state.pool)
+        .await
+        .map_err(|e| format!("Failed to list schema versions: {}", e))?;
+    let plan = migrations::plan(&state.pool, &state.manifest)
+        .await
+        .map_err(|e| format!("Failed to plan migration: {}", e))?;
+
+    Ok(Json(serde_json::json!({
+        "versions": versions,
+        "plan": plan
+    })))
+}
+
+async fn apply_migrations_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<migrations::AppliedMigration>, String> {
+    // Rebuilt tables would pull rows out from under a running job. New jobs wait until the
+    // migration is done.
+    let _migration = state.migration.write().await;
+    let running = state.running.lock().unwrap().len();
+    if running > 0 {
+        return Err(format!(
+            "{} jobs are running, apply the migration once they finish",
+            running
+        ));
+    }
+
+    migrations::apply(&state.pool, &state.manifest)
+        .await
+        .map(Json)
+        .map_err(|e| format!("Migration failed: {}", e))
+}
+
+async fn external_import_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, String> {
+    info!("Triggering external import scanning...");
+
+    // The ECG and route folders of every unpacked export in import_dirs
+    let import_dirs = uploads::import_dirs(&state.manifest);
+    if import_dirs.is_empty() {
+        return Err("No import_dirs configured to scan".to_string());
+    }
+    let input_path = import_dirs
+        .iter()
+        .map(|dir| dir.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let job_id = start_import_job(
+        state,
+        &input_path,
+        &serde_json::Value::Null,
+        request_id(&headers),
+        move |state, id| async move {
+            for base_dir in &import_dirs {
+                importer::run_external_import(
+                    base_dir,
+                    &state.pool,
+                    &state.manifest,
+                    Some(file_events(&state, &id)),
+                )
+                .await?;
+            }
+            Ok(())
+        },
+    )
+    .await?;
+
+    Ok(Json(serde_json::json!({
+        "message": "External import started in background",
+        "job_id": job_id
+    })))
+}
+
+// Runs an ECG/route import as a background job; unlike ingestions these are short and
+// cannot be cancelled or resumed
+async fn start_import_job<F, Fut>(
+    state: Arc<AppState>,
+    input_path: &str,
+    request: &serde_json::Value,
+    request_id: Option<&str>,
+    import: F,
+) -> Result<String, String>
+where
+    F: FnOnce(Arc<AppState>, String) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let job_id = uuid::Uuid::new_v4().to_string();
+    let _migration = Arc::clone(&state.migration).read_owned().await;
+    jobs::create_job(
+        &state.pool,
+        &job_id,
+        "external_import",
+        input_path,
+        parser::IngestOptions::default(),
+        request,
+        request_id,
+    )
+    .await
+    .map_err(|e| format!("Failed to create job: {}", e))?;
+
+    state.register_job(&job_id, None);
+    let id = job_id.clone();
+    tokio::spawn(async move {
+        let result = import(Arc::clone(&state), id.clone()).await;
+        let persisted = match result {
+            Ok(_) => jobs::finish_job(&state.pool, &id, "completed", None).await,
+            Err(e) => {
+                error!("External import failed for job {}: {:?}", id, e);
+                jobs::finish_job(&state.pool, &id, "failed", Some(&e.to_string())).await
+            }
+        };
+        if let Err(e) = persisted {
+            error!("Failed to record outcome of job {}: {:?}", id, e);
+        }
+        state.unregister_job(&id).await;
+    });
+    Ok(job_id)
+}
+
+#[derive(Deserialize)]
+struct UploadParams {
+    file_name: String,
+    #[serde(default)]
+    incremental: bool,
+    #[serde(default)]
+    strict: bool,
+}
+
+// Streams the request body into the upload directory, then imports it: exports start an
+// ingestion, CSV and GPX files an ECG or route import
+async fn upload_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<serde_json::Value>, String> {
+    info!("Receiving upload: {}", params.file_name);
+
+    let expected_sha256 = headers
+        .get("x-content-sha256")
+        .and_then(|v| v.to_str().ok());
+    let upload = uploads::store_upload(
+        &uploads::upload_dir(&state.manifest),
+        &params.file_name,
+        body.into_data_stream(),
+        uploads::max_upload_bytes(&state.manifest),
+        expected_sha256,
+    )
+    .await
+    .map_err(|e| format!("Upload failed: {}", e))?;
+
+    let input_path = upload.path.to_string_lossy().to_string();
+    let request = serde_json::json!({
+        "upload": upload,
+        "incremental": params.incremental,
+        "strict": params.strict
+    });
+    let job_id = match upload.kind {
+        uploads::UploadKind::Export => {
+            start_ingest_job(
+                state,
+                &input_path,
+                parser::IngestOptions {
+                    incremental: params.incremental,
+                    strict: params.strict,
+                },
+                &request,
+                request_id(&headers),
+            )
+            .await?
+        }
+        uploads::UploadKind::Ecg | uploads::UploadKind::Route => {
+            let stored = upload.clone();
+            start_import_job(
+                state,
+                &input_path,
+                &request,
+                request_id(&headers),
+                move |state, id| async move {
+                    let events = file_events(&state, &id);
+                    let failed = AtomicBool::new(false);
+                    importer::import_side_car(
+                        &stored.path,
+                        &stored.file_name,
+                        &state.pool,
+                        &state.manifest,
+                        Some(|file: &importer::ImportedFile| {
+                            failed.fetch_or(file.error.is_some(), Ordering::Relaxed);
+                            events(file)
+                        }),
+                    )
+                    .await?;
+                    // Keep a file that could not be imported for inspection
+                    if !failed.load(Ordering::Relaxed) {
+                        uploads::discard_upload(&state.manifest, &stored.path).await;
+                    }
+                    Ok(())
+                },
+            )
+            .await?
+        }
+    };
+
+    Ok(Json(serde_json::json!({
+        "message": "Upload stored, import started in background",
+        "job_id": job_id,
+        "upload": upload
+    })))
+}
+
+#[derive(Deserialize)]
+struct EcgQuery {
+    downsample: Option<usize>,
+}
+
+async fn get_ecg_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Query(query): Query<EcgQuery>,
+) -> Result<Json<serde_json::Value>, String> {
+    info!("Fetching ECG recording ID: {}", id);
+
+    let row: (String, String, String, String) = sqlx::query_as(
+        "SELECT recorded_at, classification, sample_rate, voltage_samples FROM ecg_recordings WHERE id = ?",
+    )
+    .bind(id)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|e| format!("ECG not found: {}", e))?;
+
+    let (recorded_at, classification, sample_rate, raw_samples) = row;
+
+    // Parse samples
+    let mut samples: Vec<f64> = raw_samples
+        .split(',')
+        .filter_map(|s| s.parse::<f64>().ok())
+        .collect();
+
+    // Apply downsampling if requested
+    if let Some(factor) = query.downsample {
+        if factor > 1 {
+            samples = samples.into_iter().step_by(factor).collect();
+        }
+    }
+
+    Ok(Json(serde_json::json!({
+        "id": id,
+        "recorded_at": recorded_at,
+        "classification": classification,
+        "sample_rate": sample_rate,
+        "sample_count": samples.len(),
+        "samples": samples
+    })))
+}
+
+async fn get_workout_details_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, String> {
+    info!("Fetching details for workout session: {}", id);
+
+    let details = db::get_workout_details(&state.pool, &state.manifest, &id)
+        .await
+        .map_err(|e| format!("Workout not found: {}", e))?;
+
+    Ok(Json(details))
+}
+
+async fn get_workout_intensity_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, String> {
+    info!("Analyzing intensity for workout session: {}", id);
+
+    let intensity = db::get_workout_intensity(&state.pool, &state.manifest, &id)
+        .await
+        .map_err(|e| format!("Intensity analysis failed: {}", e))?;
+
+    Ok(Json(intensity))
+}
+
+async fn get_summary_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, String> {
+    let summary = db::get_db_summary(&state.pool, &state.manifest)
+        .await
+        .map_err(|e| format!("Failed to generate summary: {}", e))?;
+
+    Ok(Json(summary))
+}
+
+async fn get_profile_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, String> {
+    let profile = db::get_profile(&state.pool, &state.manifest)
+        .await
+        .map_err(|e| format!("Failed to load profile: {}", e))?;
+
+    Ok(Json(profile))
+}
+
+async fn export_data_handler(
+    State(state): State<Arc<AppState>>,
+    Path(table): Path<String>,
+) -> Result<axum::response::Response, String> {
+    // Validate table exists
+    if !state.manifest.tables.contains_key(&table) {
+        return Err(format!("Table '{}' not defined in manifest", table));
+    }
+
+    let csv_data = db::export_table_to_csv(&state.pool, &table)
+        .await
+        .map_err(|e| format!("Export failed: {}", e))?;
+
+    axum::response::Response::builder()
+        .header("content-type", "text/csv")
+        .header(
+            "content-disposition",
+            format!("attachment; filename=\"{}.csv\"", table),
+        )
+        .body(axum::body::Body::from(csv_data))
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Deserialize)]
+struct TrendsQuery {
+    start: String,
+    end: String,
+}
+
+async fn get_trends_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<TrendsQuery>,
+) -> Result<Json<serde_json::Value>, String> {
+    let trends = db::get_biometric_trends(&state.pool, &state.manifest, &query.start, &query.end)
+        .await
+        .map_err(|e| format!("Failed to fetch trends: {}", e))?;
+
+    Ok(Json(trends))
+}
+
+async fn get_recovery_handler(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, String> {
+    let analysis = db::get_recovery_analysis(&state.pool, &state.manifest)
+        .await
+        .map_err(|e| format!("Analysis failed: {}", e))?;
+
+    Ok(Json(analysis))
+}
+
+async fn get_rr_series_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<serde_json::Value>, String> {
+    info!("Fetching RR intervals for HRV record: {}", id);
+
+    let series = db::get_rr_series(&state.pool, &state.manifest, &id)
+        .await
+        .map_err(|e| format!("RR series not found: {}", e))?;
+
+    Ok(Json(series))
+}
+
+#[derive(Deserialize)]
+struct SleepQuery {
+    date: String,
+}
+
+async fn get_sleep_analysis_handler(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SleepQuery>,
+) -> Result<Json<serde_json::Value>, String> {
+    let summary = db::get_sleep_summary(&state.pool, &state.manifest, &query.date)
+        .await
+        .map_err(|e| format!("Sleep analysis failed: {}", e))?;
+
+    Ok(Json(summary))
+}
+
+#[derive(Deserialize)]
+struct CategoryQuery {
+    column: String,
+    start: Option<String>,
+    end: Option<String>,
+    source: Option<String>,
+    #[serde(default)]
+    exclude_user_entered: bool,
+}
+
+async fn get_category_summary_handler(
+    State(state): State<Arc<AppState>>,
+    Path(table): Path<String>,
+    Query(query): Query<CategoryQuery>,
+) -> Result<Json<serde_json::Value>, String> {
+    let filter = db::RowFilter {
+        start: query.start,
+        end: query.end,
+        source: query.source,
+        exclude_user_entered: query.exclude_user_entered,
+    };
+
+    let summary =
+        db::get_category_summary(&state.pool, &state.manifest, &table, &query.column, &filter)
+            .await
+            .map_err(|e| format!("Category analysis failed: {}", e))?;
+
+    Ok(Json(summary))
+}
+
+#[derive(Deserialize)]
+struct GetDataParams {
+    limit: Option<i32>,
+    sort: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    source: Option<String>,
+    #[serde(default)]
+    exclude_user_entered: bool,
+    units: Option<UnitSystem>,
+}
+
+async fn get_data_handler(
+    State(state): State<Arc<AppState>>,
+    Path(table): Path<String>,
+    Query(params): Query<GetDataParams>,
+) -> Result<Json<Vec<serde_json::Value>>, String> {
+    // Validate table exists in manifest (either in tables or external_sources)
+    let exists_in_tables = state.manifest.tables.contains_key(&table);
+    let exists_in_ext = if let Some(ext) = &state.manifest.external_sources {
+        let is_ecg = ext
+            .ecg
+            .as_ref()
+            .map(|e| e.target_table == table)
+            .unwrap_or(false);
+        let is_routes = ext
+            .routes
+            .as_ref()
+            .map(|r| r.target_table == table)
+            .unwrap_or(false);
+        is_ecg || is_routes
+    } else {
+        false
+    };
+
+    if !exists_in_tables && !exists_in_ext {
+        return Err(format!("Table '{}' not defined in manifest", table));
+    }
+
+    let limit = params.limit.unwrap_or(100);
+    let sort_col = params.sort.as_deref();
+    let filter = db::RowFilter {
+        start: params.start,
+        end: params.end,
+        source: params.source,
+        exclude_user_entered: params.exclude_user_entered,
+    };
+
+    let mut data = db::query_table(
+        &state.pool,
+        &state.manifest,
+        &table,
+        limit,
+        sort_col,
+        &filter,
+    )
+    .await
+    .map_err(|e| format!("Query failed: {}", e))?;
+
+    if let Some(system) = params.units {
+        units::convert_rows_for_display(&state.manifest, &table, &mut data, system, false);
+    }
+
+    Ok(Json(data))
+}
+
+#[derive(Deserialize)]
+struct AggregateParams {
+    bucket: String, // "hour", "day", "month"
+    start: Option<String>,
+    end: Option<String>,
+    source: Option<String>,
+    #[serde(default)]
+    exclude_user_entered: bool,
+    units: Option<UnitSystem>,
+}
+
+async fn aggregate_handler(
+    State(state): State<Arc<AppState>>,
+    Path(table): Path<String>,
+    Query(params): Query<AggregateParams>,
+) -> Result<Json<Vec<serde_json::Value>>, String> {
+    if !state.manifest.tables.contains_key(&table) {
+        return Err(format!("Table '{}' not defined in manifest", table));
+    }
+
+    let filter = db::RowFilter {
+        start: params.start,
+        end: params.end,
+        source: params.source,
+        exclude_user_entered: params.exclude_user_entered,
+    };
+
+    let mut data = db::aggregate_table(
+        &state.pool,
+        &state.manifest,
+        &table,
+        &params.bucket,
+        &filter,
+    )
+    .await
+    .map_err(|e| format!("Aggregation failed: {}", e))?;
+
+    if let Some(system) = params.units {
+        units::convert_rows_for_display(&state.manifest, &table, &mut data, system, true);
+    }
+
+    Ok(Json(data))
+}
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+pub type ExportArchive = ZipArchive<File>;
+
+const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+
+// Detects Apple's `export.zip` by its local file header rather than the extension,
+// so renamed uploads are still recognised.
+pub fn is_zip_archive(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map(|_| &magic == ZIP_MAGIC)
+        .unwrap_or(false)
+}
+
+pub fn open_archive(path: &Path) -> Result<ExportArchive> {
+    let file = File::open(path).with_context(|| format!("Failed to open archive {:?}", path))?;
+    ZipArchive::new(file).with_context(|| format!("Failed to read zip archive {:?}", path))
+}
+
+// Index of the main `export.xml` entry (usually `apple_health_export/export.xml`).
+// `export_cda.xml` lives next to it and is deliberately not matched.
+pub fn find_export_xml(archive: &ExportArchive) -> Result<usize> {
+    (0..archive.len())
+        .filter_map(|i| archive.name_for_index(i).map(|name| (i, name)))
+        .filter(|(_, name)| entry_file_name(name) == Some("export.xml"))
+        .min_by_key(|(_, name)| name.matches('/').count())
+        .map(|(i, _)| i)
+        .ok_or_else(|| anyhow::anyhow!("Archive does not contain an export.xml"))
+}
+
+// Entries stored directly inside `folder` (e.g. `electrocardiograms`) with the given
+// extension. Returns the entry index alongside its bare file name.
+pub fn find_folder_entries(
+    archive: &ExportArchive,
+    folder: &str,
+    extension: &str,
+) -> Vec<(usize, String)> {
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let Some(name) = archive.name_for_index(i) else {
+            continue;
+        };
+        let path = Path::new(name);
+        let in_folder = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .map(|p| p == folder)
+            .unwrap_or(false);
+        let has_ext = path.extension().and_then(|s| s.to_str()) == Some(extension);
+
+        if in_folder && has_ext {
+            if let Some(file_name) = entry_file_name(name) {
+                entries.push((i, file_name.to_string()));
+            }
+        }
+    }
+    entries
+}
+
+fn entry_file_name(name: &str) -> Option<&str> {
+    name.rsplit('/').next().filter(|s| !s.is_empty())
+}
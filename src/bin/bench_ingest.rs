@@ -0,0 +1,47 @@
+use backend::{db, parser};
+use std::path::Path;
+use std::time::Instant;
+
+// Ingests an export into a scratch database and reports throughput.
+// Usage: cargo run --release --bin bench_ingest [export.xml|export.zip] [manifest.toml]
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let export = args
+        .next()
+        .unwrap_or_else(|| "large_export.xml".to_string());
+    let manifest_path = args
+        .next()
+        .unwrap_or_else(|| "metrics_manifest.toml".to_string());
+    if !Path::new(&export).exists() {
+        anyhow::bail!(
+            "{} not found, create it with `cargo run --bin generate_data`",
+            export
+        );
+    }
+
+    let db_path = std::env::temp_dir().join(format!("bench_ingest_{}.db", std::process::id()));
+    let db_url = format!("sqlite:{}?mode=rwc", db_path.display());
+    let (pool, manifest) = db::init_db(&db_url, &manifest_path).await?;
+
+    let started = Instant::now();
+    let rows =
+        parser::parse_and_ingest(Path::new(&export), &pool, &manifest, None::<fn(usize)>).await?;
+    let elapsed = started.elapsed().as_secs_f64();
+    pool.close().await;
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", db_path.display(), suffix));
+    }
+
+    let size = std::fs::metadata(&export)?.len() as f64 / (1024.0 * 1024.0);
+    println!(
+        "{} rows from {} ({:.1} MB) in {:.2}s: {:.0} rows/sec, {:.1} MB/sec",
+        rows,
+        export,
+        size,
+        elapsed,
+        rows as f64 / elapsed,
+        size / elapsed
+    );
+    Ok(())
+}
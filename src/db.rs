@@ -1,28 +1,126 @@
 use anyhow::{Context, Result};
-use chrono::DateTime;
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Utc};
+use futures::TryStreamExt;
 use serde::Deserialize;
 use serde_json::{json, Map, Value};
-use sqlx::{sqlite::SqlitePoolOptions, Column, Pool, Row, Sqlite};
+use sqlx::sqlite::{SqlitePoolOptions, SqliteRow};
+use sqlx::{Column, Pool, Row, Sqlite, ValueRef};
 use std::{
     collections::{HashMap, HashSet},
     fs,
 };
 use tracing::info;
 
+use crate::parser::IngestReport;
+use crate::{hrv, jobs, migrations, priority, quarantine};
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Manifest {
     pub settings: Option<Settings>,
     pub user_profile: Option<UserProfile>,
     pub tables: HashMap<String, TableConfig>,
     pub external_sources: Option<ExternalSources>,
+    pub source_priority: Option<SourcePriority>,
+    pub beat_to_beat: Option<BeatToBeatConfig>,
+    pub workout_events: Option<WorkoutEventsConfig>,
+    pub workout_activities: Option<WorkoutActivitiesConfig>,
+}
+
+impl Manifest {
+    // The table and column an analysis reads for a role
+    pub fn role(&self, role: &str) -> Result<(&str, &ColumnDefinition)> {
+        self.tables
+            .iter()
+            .find_map(|(table_name, config)| {
+                config
+                    .columns
+                    .iter()
+                    .find(|col| col.role.as_deref() == Some(role))
+                    .map(|col| (table_name.as_str(), col))
+            })
+            .with_context(|| {
+                format!(
+                    "Analysis role '{}' is not configured, set `role = \"{}\"` on a manifest column",
+                    role, role
+                )
+            })
+    }
+
+    // The single table fed from <Workout>, which events and activities link to
+    pub fn workout_table(&self) -> Option<(&str, &TableConfig)> {
+        self.tables
+            .iter()
+            .find(|(_, config)| config.element() == "Workout")
+            .map(|(name, config)| (name.as_str(), config))
+    }
+
+    // Ordered source patterns for an HK type, falling back to the manifest-wide default
+    pub fn source_priority_for(&self, hk_identifier: Option<&str>) -> &[String] {
+        let Some(sp) = &self.source_priority else {
+            return &[];
+        };
+        hk_identifier
+            .and_then(|hk| sp.types.get(hk))
+            .unwrap_or(&sp.default)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SourcePriority {
+    #[serde(default)]
+    pub default: Vec<String>,
+    #[serde(default)]
+    pub types: HashMap<String, Vec<String>>,
+}
+
+// Instantaneous heart beats of HRV records, one row per beat linked to the parent row's uuid
+#[derive(Debug, Deserialize, Clone)]
+pub struct BeatToBeatConfig {
+    pub target_table: String,
+}
+
+// <WorkoutEvent> children of workouts (pauses, laps, segments, markers), one row per event
+// linked to the workout's primary key
+#[derive(Debug, Deserialize, Clone)]
+pub struct WorkoutEventsConfig {
+    pub target_table: String,
+}
+
+// <WorkoutActivity> legs of multisport workouts, one row per leg linked to the workout's
+// primary key and carrying the workouts table's statistics and metadata columns
+#[derive(Debug, Deserialize, Clone)]
+pub struct WorkoutActivitiesConfig {
+    pub target_table: String,
 }
 
+// Manual overrides; anything left out is derived from the imported <Me> profile
 #[derive(Debug, Deserialize, Clone)]
 pub struct UserProfile {
     pub max_heart_rate: Option<i32>,
     pub resting_heart_rate: Option<i32>,
 }
 
+// Characteristics from the export's <Me> element, with the HK enum prefixes stripped
+#[derive(Debug, Default, Clone)]
+pub struct MeProfile {
+    pub date_of_birth: Option<String>,
+    pub biological_sex: Option<String>,
+    pub blood_type: Option<String>,
+    pub skin_type: Option<String>,
+}
+
+// Where the last completed ingestion left off: its <ExportDate> and the newest start_date
+// of every type it stored, per table. A type the manifest did not map back then, or one
+// with quarantined elements, has no watermark.
+#[derive(Debug, Default, Clone)]
+pub struct Watermarks {
+    pub export_date: Option<String>,
+    pub types: HashMap<(String, String), String>, // (table, type) -> newest start_date
+}
+
+// Used when neither the manifest nor the profile gives a maximum heart rate
+const FALLBACK_MAX_HEART_RATE: f64 = 190.0;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ExternalSources {
     pub ecg: Option<EcgConfig>,
@@ -72,14 +170,79 @@ pub struct Settings {
     pub batch_size: Option<usize>,
     pub timezone: Option<String>,
     pub import_dirs: Option<Vec<String>>,
+    pub upload_dir: Option<String>, // where uploads are spooled, default "uploads"
+    pub max_upload_bytes: Option<u64>, // default 4 GiB
+    pub backup_dir: Option<String>, // database copies taken before migrations, default "backups"
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct TableConfig {
     pub description: Option<String>,
+    // Provenance columns persisted with every row, see PROVENANCE_ATTRIBUTES
+    #[serde(default)]
+    pub provenance: Vec<String>,
+    // HKCorrelationType mapped to this table: one row per <Correlation>, one column per
+    // child quantity (extraction_source = "correlation_member")
+    pub correlation_type: Option<String>,
+    // XML element whose occurrences become rows. "Record" (the default) maps record types to
+    // columns; any other element ("Workout", "ActivitySummary", "Audiogram"...) gives one row
+    // per element, its attributes read by columns with an `hk_attribute`
+    pub element: Option<String>,
     pub columns: Vec<ColumnDefinition>,
 }
 
+impl TableConfig {
+    pub fn element(&self) -> &str {
+        match (&self.element, &self.correlation_type) {
+            (Some(element), _) => element,
+            (None, Some(_)) => "Correlation",
+            (None, None) => "Record",
+        }
+    }
+
+    // Column rows are keyed by; tables without one get a content-hash uuid
+    pub fn primary_key(&self) -> &str {
+        self.columns
+            .iter()
+            .find(|c| c.is_primary_key)
+            .map(|c| c.field_name.as_str())
+            .unwrap_or("uuid")
+    }
+}
+
+// Provenance column -> XML attribute it is read from on <Record>/<Workout>. Records converted
+// to a column's canonical unit store that unit in `unit` and the exported one in `source_unit`.
+pub const PROVENANCE_ATTRIBUTES: [(&str, &str); 5] = [
+    ("source_name", "sourceName"),
+    ("source_version", "sourceVersion"),
+    ("device", "device"),
+    ("unit", "unit"),
+    ("source_unit", "unit"),
+];
+
+pub fn provenance_attribute(column: &str) -> Option<&'static str> {
+    PROVENANCE_ATTRIBUTES
+        .iter()
+        .find(|(col, _)| *col == column)
+        .map(|(_, attr)| *attr)
+}
+
+// Workout statistics extraction source -> aggregate attribute of <WorkoutStatistics>
+pub const STATISTICS_ATTRIBUTES: [(&str, &str); 4] = [
+    ("statistics_sum", "sum"),
+    ("statistics_average", "average"),
+    ("statistics_min", "minimum"),
+    ("statistics_max", "maximum"),
+];
+
+pub fn statistics_attribute(extraction_source: Option<&str>) -> Option<&'static str> {
+    let source = extraction_source?;
+    STATISTICS_ATTRIBUTES
+        .iter()
+        .find(|(src, _)| *src == source)
+        .map(|(_, attr)| *attr)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ColumnDefinition {
     #[serde(alias = "name")]
@@ -101,6 +264,33 @@ pub struct ColumnDefinition {
 
     pub data_type: String,
     pub expression: Option<String>,
+
+    // Canonical unit (e.g. "kg", "km", "degC"); incoming values are converted into it
+    pub unit: Option<String>,
+
+    // Category values (e.g. "HKCategoryValueSleepAnalysisAsleepCore") stored as `code`
+    #[serde(default)]
+    pub categories: Vec<CategoryValue>,
+
+    // What analyses look this column up as (heart_rate, hrv, resting_hr, sleep_stage, steps...);
+    // each role belongs to at most one column
+    pub role: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CategoryValue {
+    pub hk_value: String,
+    pub code: i64,
+    pub label: String,
+}
+
+impl ColumnDefinition {
+    pub fn category_label(&self, code: i64) -> Option<&str> {
+        self.categories
+            .iter()
+            .find(|c| c.code == code)
+            .map(|c| c.label.as_str())
+    }
 }
 
 fn default_aggregate() -> String {
@@ -118,33 +308,184 @@ pub async fn init_db(db_url: &str, manifest_path: &str) -> Result<(DbPool, Manif
 
     let manifest_content =
         fs::read_to_string(manifest_path).context("Failed to read metrics_manifest.toml")?;
-    let manifest: Manifest =
+    let mut manifest: Manifest =
         toml::from_str(&manifest_content).context("Failed to parse metrics_manifest.toml")?;
+    resolve_elements(&mut manifest)?;
+    resolve_roles(&mut manifest)?;
 
     ensure_schema(&pool, &manifest).await?;
     ensure_indices(&pool, &manifest).await?;
     ensure_external_schema(&pool, &manifest).await?;
+    ensure_beat_schema(&pool, &manifest).await?;
+    ensure_workout_event_schema(&pool, &manifest).await?;
+    ensure_workout_activity_schema(&pool, &manifest).await?;
+    ensure_profile_schema(&pool).await?;
+    ensure_ingestion_schema(&pool).await?;
+    jobs::ensure_jobs_schema(&pool).await?;
+    quarantine::ensure_quarantine_schema(&pool).await?;
+    migrations::ensure_migration_schema(&pool).await?;
+    migrations::check(&pool, &manifest).await?;
 
     Ok((pool, manifest))
 }
 
+// Checks which element feeds each table. Manifests written before `element` existed relied on
+// the names of their workout and activity summary tables, which still select those elements.
+fn resolve_elements(manifest: &mut Manifest) -> Result<()> {
+    for (table_name, config) in manifest.tables.iter_mut() {
+        if config.element.is_none() && config.correlation_type.is_none() {
+            config.element = match table_name.as_str() {
+                "workouts" => Some("Workout".to_string()),
+                "activity_summaries" => Some("ActivitySummary".to_string()),
+                _ => None,
+            };
+        }
+        match (config.element(), &config.correlation_type) {
+            ("Correlation", None) => anyhow::bail!(
+                "Table {} reads <Correlation> elements but has no correlation_type",
+                table_name
+            ),
+            ("Correlation", Some(_)) | (_, None) => {}
+            (element, Some(_)) => anyhow::bail!(
+                "Table {} has a correlation_type but reads <{}> elements",
+                table_name,
+                element
+            ),
+        }
+    }
+    let workout_tables = manifest
+        .tables
+        .values()
+        .filter(|config| config.element() == "Workout")
+        .count();
+    if workout_tables > 1 {
+        anyhow::bail!(
+            "{} tables read <Workout> elements, at most one may",
+            workout_tables
+        );
+    }
+    Ok(())
+}
+
+// Columns the analyses used before roles existed, for manifests that assign none
+const LEGACY_ROLES: [(&str, &str, &str); 4] = [
+    ("heart_rate", "vitals", "heart_rate"),
+    ("hrv", "vitals", "hrv_sdnn"),
+    ("resting_hr", "vitals", "resting_hr"),
+    ("sleep_stage", "sleep", "sleep_stage"),
+];
+
+fn resolve_roles(manifest: &mut Manifest) -> Result<()> {
+    let mut claimed: HashMap<String, String> = HashMap::new();
+    for (table_name, config) in &manifest.tables {
+        for col in &config.columns {
+            let Some(role) = &col.role else {
+                continue;
+            };
+            let column = format!("{}.{}", table_name, col.field_name);
+            if let Some(other) = claimed.insert(role.clone(), column.clone()) {
+                anyhow::bail!(
+                    "Role '{}' is assigned to both {} and {}",
+                    role,
+                    other,
+                    column
+                );
+            }
+        }
+    }
+    for (role, table_name, field_name) in LEGACY_ROLES {
+        if claimed.contains_key(role) {
+            continue;
+        }
+        if let Some(col) = manifest
+            .tables
+            .get_mut(table_name)
+            .and_then(|t| t.columns.iter_mut().find(|c| c.field_name == field_name))
+            .filter(|c| c.role.is_none())
+        {
+            col.role = Some(role.to_string());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RowFilter {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub source: Option<String>,
+    pub exclude_user_entered: bool,
+}
+
+// Metadata key Apple sets on samples typed in by hand rather than measured
+pub const USER_ENTERED_KEY: &str = "HKWasUserEntered";
+
+fn table_has_column(manifest: &Manifest, table_name: &str, column: &str) -> bool {
+    manifest
+        .tables
+        .get(table_name)
+        .map(|t| {
+            t.provenance.iter().any(|p| p == column)
+                || t.columns.iter().any(|c| c.field_name == column)
+        })
+        .unwrap_or(false)
+}
+
+// SQL condition dropping hand-entered rows, or None when the filter is off. Requires a
+// metadata_value column for HKWasUserEntered, the same way `source` requires source_name.
+fn user_entered_clause(
+    manifest: &Manifest,
+    table_name: &str,
+    filter: &RowFilter,
+) -> Result<Option<String>> {
+    if !filter.exclude_user_entered {
+        return Ok(None);
+    }
+    let column = manifest.tables.get(table_name).and_then(|t| {
+        t.columns.iter().find(|c| {
+            c.extraction_source.as_deref() == Some("metadata_value")
+                && c.hk_identifier.as_deref() == Some(USER_ENTERED_KEY)
+        })
+    });
+    match column {
+        Some(col) => Ok(Some(format!("COALESCE({}, 0) = 0", col.field_name))),
+        None => Err(anyhow::anyhow!(
+            "Table {} does not record {}",
+            table_name,
+            USER_ENTERED_KEY
+        )),
+    }
+}
+
 pub async fn query_table(
     pool: &DbPool,
+    manifest: &Manifest,
     table_name: &str,
     limit: i32,
     sort_col: Option<&str>,
-    start: Option<&str>,
-    end: Option<&str>,
+    filter: &RowFilter,
 ) -> Result<Vec<Value>> {
     let sort_by = sort_col.unwrap_or("start_date");
 
     let mut query_parts = Vec::new();
-    if start.is_some() {
+    if filter.start.is_some() {
         query_parts.push(format!("{} >= ?", sort_by));
     }
-    if end.is_some() {
+    if filter.end.is_some() {
         query_parts.push(format!("{} <= ?", sort_by));
     }
+    if filter.source.is_some() {
+        if !table_has_column(manifest, table_name, "source_name") {
+            return Err(anyhow::anyhow!(
+                "Table {} does not record source_name",
+                table_name
+            ));
+        }
+        query_parts.push("source_name = ?".to_string());
+    }
+    if let Some(clause) = user_entered_clause(manifest, table_name, filter)? {
+        query_parts.push(clause);
+    }
 
     let where_clause = if query_parts.is_empty() {
         "".to_string()
@@ -158,12 +499,15 @@ pub async fn query_table(
     );
 
     let mut q = sqlx::query(&sql);
-    if let Some(s) = start {
+    if let Some(s) = &filter.start {
         q = q.bind(s);
     }
-    if let Some(e) = end {
+    if let Some(e) = &filter.end {
         q = q.bind(e);
     }
+    if let Some(src) = &filter.source {
+        q = q.bind(src);
+    }
     q = q.bind(limit);
 
     let rows = q
@@ -194,27 +538,26 @@ pub async fn query_table(
     Ok(results)
 }
 
-pub async fn get_workout_details(pool: &DbPool, session_id: &str) -> Result<Value> {
+pub async fn get_workout_details(
+    pool: &DbPool,
+    manifest: &Manifest,
+    session_id: &str,
+) -> Result<Value> {
     // 1. Fetch workout
-    let row = sqlx::query("SELECT * FROM workouts WHERE session_id = ?")
-        .bind(session_id)
-        .fetch_one(pool)
-        .await
-        .context("Workout not found")?;
+    let (workout_table, workout_config) = manifest
+        .workout_table()
+        .context("The manifest has no table for <Workout> elements")?;
+    let row = sqlx::query(&format!(
+        "SELECT * FROM {} WHERE {} = ?",
+        workout_table,
+        workout_config.primary_key()
+    ))
+    .bind(session_id)
+    .fetch_one(pool)
+    .await
+    .context("Workout not found")?;
 
-    let mut workout_map = Map::new();
-    for col in row.columns() {
-        let col_name = col.name();
-        if let Ok(val) = row.try_get::<f64, _>(col_name) {
-            workout_map.insert(col_name.to_string(), json!(val));
-        } else if let Ok(val) = row.try_get::<i64, _>(col_name) {
-            workout_map.insert(col_name.to_string(), json!(val));
-        } else if let Ok(val) = row.try_get::<String, _>(col_name) {
-            workout_map.insert(col_name.to_string(), json!(val));
-        } else {
-            workout_map.insert(col_name.to_string(), Value::Null);
-        }
-    }
+    let mut workout_map = row_to_map(&row);
 
     // 2. Fetch route points if linked
     if let Some(route_file) = workout_map.get("route_file").and_then(|v| v.as_str()) {
@@ -266,9 +609,131 @@ pub async fn get_workout_details(pool: &DbPool, session_id: &str) -> Result<Valu
         );
     }
 
+    // 3. Events, and moving time from the pauses among them
+    if let Some(events_config) = &manifest.workout_events {
+        let rows: Vec<(String, String, Option<f64>, Option<String>)> = sqlx::query_as(&format!(
+            "SELECT event_type, date, duration_minutes, metadata FROM {} WHERE session_id = ? ORDER BY event_index ASC",
+            events_config.target_table
+        ))
+        .bind(session_id)
+        .fetch_all(pool)
+        .await
+        .with_context(|| format!("Failed to load events of workout {}", session_id))?;
+
+        let events: Vec<Value> = rows
+            .iter()
+            .map(|(event_type, date, duration_minutes, metadata)| {
+                json!({
+                    "event_type": event_type,
+                    "date": date,
+                    "duration_minutes": duration_minutes,
+                    "metadata": metadata.as_ref().and_then(|m| serde_json::from_str::<Value>(m).ok()),
+                })
+            })
+            .collect();
+        workout_map.insert("events".to_string(), json!(events));
+
+        let date = |key: &str| {
+            workout_map
+                .get(key)
+                .and_then(|v| v.as_str())
+                .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+        };
+        if let (Some(start), Some(end)) = (date("start_date"), date("end_date")) {
+            let pauses: Vec<(&str, DateTime<FixedOffset>)> = rows
+                .iter()
+                .filter_map(|(event_type, date, _, _)| {
+                    Some((
+                        event_type.as_str(),
+                        DateTime::parse_from_rfc3339(date).ok()?,
+                    ))
+                })
+                .collect();
+            let elapsed = (end - start).num_milliseconds() as f64 / 60000.0;
+            let paused = paused_minutes(&pauses, end);
+            workout_map.insert("elapsed_minutes".to_string(), json!(elapsed));
+            workout_map.insert("paused_minutes".to_string(), json!(paused));
+            workout_map.insert(
+                "moving_minutes".to_string(),
+                json!((elapsed - paused).max(0.0)),
+            );
+        }
+    }
+
+    // 4. Legs of a multisport workout, each with its own statistics
+    if let Some(activities_config) = &manifest.workout_activities {
+        let rows = sqlx::query(&format!(
+            "SELECT * FROM {} WHERE session_id = ? ORDER BY activity_index ASC",
+            activities_config.target_table
+        ))
+        .bind(session_id)
+        .fetch_all(pool)
+        .await
+        .with_context(|| format!("Failed to load activities of workout {}", session_id))?;
+        let activities: Vec<Value> = rows
+            .iter()
+            .map(|row| {
+                let mut activity = row_to_map(row);
+                activity.remove("session_id");
+                Value::Object(activity)
+            })
+            .collect();
+        workout_map.insert("activities".to_string(), json!(activities));
+    }
+
     Ok(Value::Object(workout_map))
 }
 
+fn row_to_map(row: &SqliteRow) -> Map<String, Value> {
+    let mut map = Map::new();
+    for col in row.columns() {
+        let col_name = col.name();
+        // SQLite reads NULL as 0 for numeric types
+        if row.try_get_raw(col_name).is_ok_and(|v| v.is_null()) {
+            map.insert(col_name.to_string(), Value::Null);
+        } else if let Ok(val) = row.try_get::<f64, _>(col_name) {
+            map.insert(col_name.to_string(), json!(val));
+        } else if let Ok(val) = row.try_get::<i64, _>(col_name) {
+            map.insert(col_name.to_string(), json!(val));
+        } else if let Ok(val) = row.try_get::<String, _>(col_name) {
+            map.insert(col_name.to_string(), json!(val));
+        } else {
+            map.insert(col_name.to_string(), Value::Null);
+        }
+    }
+    map
+}
+
+// Time between a pause and the next resume, for manual pauses (Pause/Resume) and auto-pause
+// (MotionPaused/MotionResumed) alike; overlapping pauses count once and a workout that ends
+// paused is paused until its end
+fn paused_minutes(events: &[(&str, DateTime<FixedOffset>)], end: DateTime<FixedOffset>) -> f64 {
+    let (mut manual, mut motion) = (false, false);
+    let mut paused_since = None;
+    let mut paused_ms = 0;
+    for (event_type, at) in events {
+        match *event_type {
+            "Pause" => manual = true,
+            "Resume" => manual = false,
+            "MotionPaused" => motion = true,
+            "MotionResumed" => motion = false,
+            _ => continue,
+        }
+        match (manual || motion, paused_since) {
+            (true, None) => paused_since = Some(*at),
+            (false, Some(since)) => {
+                paused_ms += (*at - since).num_milliseconds();
+                paused_since = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(since) = paused_since {
+        paused_ms += (end - since).num_milliseconds().max(0);
+    }
+    paused_ms as f64 / 60000.0
+}
+
 fn calculate_haversine(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
     let r = 6371000.0; // Earth radius in meters
     let phi1 = lat1.to_radians();
@@ -315,6 +780,32 @@ pub async fn get_db_summary(pool: &DbPool, manifest: &Manifest) -> Result<Value>
         }
     }
 
+    if let Some(beats) = &manifest.beat_to_beat {
+        let count: (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", beats.target_table))
+            .fetch_one(pool)
+            .await
+            .unwrap_or((0,));
+        table_counts.insert(beats.target_table.clone(), json!(count.0));
+    }
+
+    if let Some(events) = &manifest.workout_events {
+        let count: (i64,) =
+            sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", events.target_table))
+                .fetch_one(pool)
+                .await
+                .unwrap_or((0,));
+        table_counts.insert(events.target_table.clone(), json!(count.0));
+    }
+
+    if let Some(activities) = &manifest.workout_activities {
+        let count: (i64,) =
+            sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", activities.target_table))
+                .fetch_one(pool)
+                .await
+                .unwrap_or((0,));
+        table_counts.insert(activities.target_table.clone(), json!(count.0));
+    }
+
     summary.insert("tables".to_string(), Value::Object(table_counts));
     summary.insert(
         "database_size_mb".to_string(),
@@ -330,25 +821,31 @@ pub async fn get_workout_intensity(
     session_id: &str,
 ) -> Result<Value> {
     // 1. Fetch workout range
-    let workout: (String, String) =
-        sqlx::query_as("SELECT start_date, end_date FROM workouts WHERE session_id = ?")
-            .bind(session_id)
-            .fetch_one(pool)
-            .await
-            .context("Workout not found")?;
+    let (workout_table, workout_config) = manifest
+        .workout_table()
+        .context("The manifest has no table for <Workout> elements")?;
+    let workout: (String, String) = sqlx::query_as(&format!(
+        "SELECT start_date, end_date FROM {} WHERE {} = ?",
+        workout_table,
+        workout_config.primary_key()
+    ))
+    .bind(session_id)
+    .fetch_one(pool)
+    .await
+    .context("Workout not found")?;
 
     // 2. Fetch HR samples during workout
-    let samples: Vec<(f64,)> = sqlx::query_as("SELECT heart_rate FROM vitals WHERE heart_rate > 0 AND start_date >= ? AND start_date <= ?")
-        .bind(&workout.0)
-        .bind(&workout.1)
-        .fetch_all(pool)
-        .await?;
-
-    let max_hr = manifest
-        .user_profile
-        .as_ref()
-        .and_then(|u| u.max_heart_rate)
-        .unwrap_or(190) as f64;
+    let (hr_table, hr_col) = manifest.role("heart_rate")?;
+    let samples: Vec<(f64,)> = sqlx::query_as(&format!(
+        "SELECT {0} FROM {1} WHERE {0} > 0 AND start_date >= ? AND start_date <= ?",
+        hr_col.field_name, hr_table
+    ))
+    .bind(&workout.0)
+    .bind(&workout.1)
+    .fetch_all(pool)
+    .await?;
+
+    let (max_hr, max_hr_source) = max_heart_rate(pool, manifest, &workout.0).await?;
 
     let mut zones = HashMap::new();
     zones.insert("Z1_Recovery", 0); // < 60%
@@ -373,10 +870,196 @@ pub async fn get_workout_intensity(
         "session_id": session_id,
         "sample_count": samples.len(),
         "max_hr_used": max_hr,
+        "max_hr_source": max_hr_source,
         "zones": zones
     }))
 }
 
+// The single profile row written from the latest <Me>, None before any import
+pub async fn load_profile(pool: &DbPool) -> Result<Option<MeProfile>> {
+    let row = sqlx::query(
+        "SELECT date_of_birth, biological_sex, blood_type, skin_type FROM user_profile WHERE id = 1",
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to load user profile")?;
+
+    Ok(row.map(|row| MeProfile {
+        date_of_birth: row.get("date_of_birth"),
+        biological_sex: row.get("biological_sex"),
+        blood_type: row.get("blood_type"),
+        skin_type: row.get("skin_type"),
+    }))
+}
+
+pub async fn save_profile(pool: &DbPool, profile: &MeProfile) -> Result<()> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO user_profile (id, date_of_birth, biological_sex, blood_type, skin_type) VALUES (1, ?, ?, ?, ?)",
+    )
+    .bind(&profile.date_of_birth)
+    .bind(&profile.biological_sex)
+    .bind(&profile.blood_type)
+    .bind(&profile.skin_type)
+    .execute(pool)
+    .await
+    .context("Failed to save user profile")?;
+    Ok(())
+}
+
+// Whole years between a YYYY-MM-DD birth date and an RFC3339 or YYYY-MM-DD date
+pub fn age_at(date_of_birth: &str, at: &str) -> Option<i32> {
+    let born = NaiveDate::parse_from_str(date_of_birth, "%Y-%m-%d").ok()?;
+    let at = DateTime::parse_from_rfc3339(at)
+        .map(|dt| dt.date_naive())
+        .or_else(|_| NaiveDate::parse_from_str(at, "%Y-%m-%d"))
+        .ok()?;
+    let mut age = at.year() - born.year();
+    if (at.month(), at.day()) < (born.month(), born.day()) {
+        age -= 1;
+    }
+    (age >= 0).then_some(age)
+}
+
+// Tanaka et al. (2001): 208 - 0.7 x age
+pub fn tanaka_max_heart_rate(age: i32) -> f64 {
+    208.0 - 0.7 * age as f64
+}
+
+// Maximum heart rate on a given date and where it came from: the manifest override,
+// the age-based estimate from the imported profile, or the fixed fallback
+async fn max_heart_rate(
+    pool: &DbPool,
+    manifest: &Manifest,
+    at: &str,
+) -> Result<(f64, &'static str)> {
+    if let Some(max_hr) = manifest
+        .user_profile
+        .as_ref()
+        .and_then(|u| u.max_heart_rate)
+    {
+        return Ok((max_hr as f64, "manifest"));
+    }
+    let age = load_profile(pool)
+        .await?
+        .and_then(|p| p.date_of_birth)
+        .and_then(|dob| age_at(&dob, at));
+    Ok(match age {
+        Some(age) => (tanaka_max_heart_rate(age), "age"),
+        None => (FALLBACK_MAX_HEART_RATE, "default"),
+    })
+}
+
+pub async fn get_profile(pool: &DbPool, manifest: &Manifest) -> Result<Value> {
+    let profile = load_profile(pool).await?.unwrap_or_default();
+    let today = Utc::now().date_naive().format("%Y-%m-%d").to_string();
+    let age = profile
+        .date_of_birth
+        .as_deref()
+        .and_then(|dob| age_at(dob, &today));
+    let (max_hr, max_hr_source) = max_heart_rate(pool, manifest, &today).await?;
+
+    Ok(json!({
+        "date_of_birth": profile.date_of_birth,
+        "age": age,
+        "biological_sex": profile.biological_sex,
+        "blood_type": profile.blood_type,
+        "skin_type": profile.skin_type,
+        "max_heart_rate": max_hr,
+        "max_heart_rate_source": max_hr_source,
+        "resting_heart_rate": manifest.user_profile.as_ref().and_then(|u| u.resting_heart_rate)
+    }))
+}
+
+// Watermarks of the latest completed ingestion that saw an <ExportDate>
+pub async fn load_watermarks(pool: &DbPool) -> Result<Option<Watermarks>> {
+    let latest: Option<(i64, String)> = sqlx::query_as(
+        "SELECT id, export_date FROM ingestions WHERE export_date IS NOT NULL ORDER BY id DESC LIMIT 1",
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to load ingestion history")?;
+
+    let Some((id, export_date)) = latest else {
+        return Ok(None);
+    };
+
+    let types: Vec<(String, String, String)> = sqlx::query_as(
+        "SELECT table_name, hk_type, max_start_date FROM ingestion_type_watermarks WHERE ingestion_id = ? AND max_start_date IS NOT NULL",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await
+    .context("Failed to load ingestion watermarks")?;
+
+    Ok(Some(Watermarks {
+        export_date: Some(export_date),
+        types: types
+            .into_iter()
+            .map(|(table_name, hk_type, date)| ((table_name, hk_type), date))
+            .collect(),
+    }))
+}
+
+// What a watermark tells apart within a table: the record type behind each value column of
+// a record table, otherwise the one correlation type or element the table reads
+pub(crate) fn watermark_types(config: &TableConfig) -> Vec<(String, Option<&str>)> {
+    match config.element() {
+        "Record" => config
+            .columns
+            .iter()
+            .filter(|col| matches!(col.extraction_source.as_deref(), None | Some("value")))
+            .filter_map(|col| Some((col.hk_identifier.clone()?, Some(col.field_name.as_str()))))
+            .collect(),
+        "Correlation" => vec![(config.correlation_type.clone().unwrap_or_default(), None)],
+        element => vec![(element.to_string(), None)],
+    }
+}
+
+pub async fn record_ingestion(
+    pool: &DbPool,
+    source_file: &str,
+    incremental: bool,
+    report: &IngestReport,
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    let id = sqlx::query(
+        "INSERT INTO ingestions (source_file, export_date, incremental, records_processed, records_new, records_skipped, completed_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(source_file)
+    .bind(&report.export_date)
+    .bind(incremental)
+    .bind(report.processed as i64)
+    .bind(report.inserted as i64)
+    .bind(report.skipped as i64)
+    .bind(Utc::now().to_rfc3339())
+    .execute(&mut *tx)
+    .await
+    .context("Failed to record ingestion")?
+    .last_insert_rowid();
+
+    // Only what this ingestion stored counts, so one type cannot move another's watermark
+    for ((table_name, hk_type), start_date) in &report.newest_starts {
+        // Quarantined elements may be stored once the manifest or the export is fixed
+        if report.quarantined_types.contains(hk_type) {
+            continue;
+        }
+        sqlx::query(
+            "INSERT OR IGNORE INTO ingestion_type_watermarks (ingestion_id, table_name, hk_type, max_start_date) VALUES (?, ?, ?, ?)",
+        )
+        .bind(id)
+        .bind(table_name)
+        .bind(hk_type)
+        .bind(start_date)
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to record watermark of {}", table_name))?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
 pub async fn export_table_to_csv(pool: &DbPool, table_name: &str) -> Result<String> {
     let sql = format!("SELECT * FROM {}", table_name);
     let rows = sqlx::query(&sql).fetch_all(pool).await?;
@@ -418,8 +1101,7 @@ pub async fn aggregate_table(
     manifest: &Manifest,
     table_name: &str,
     bucket: &str,
-    start: Option<&str>,
-    end: Option<&str>,
+    filter: &RowFilter,
 ) -> Result<Vec<Value>> {
     let table_config = manifest
         .tables
@@ -438,12 +1120,24 @@ pub async fn aggregate_table(
     };
 
     let mut query_parts = Vec::new();
-    if start.is_some() {
+    if filter.start.is_some() {
         query_parts.push("start_date >= ?".to_string());
     }
-    if end.is_some() {
+    if filter.end.is_some() {
         query_parts.push("start_date <= ?".to_string());
     }
+    if filter.source.is_some() {
+        if !table_has_column(manifest, table_name, "source_name") {
+            return Err(anyhow::anyhow!(
+                "Table {} does not record source_name",
+                table_name
+            ));
+        }
+        query_parts.push("source_name = ?".to_string());
+    }
+    if let Some(clause) = user_entered_clause(manifest, table_name, filter)? {
+        query_parts.push(clause);
+    }
 
     let where_clause = if query_parts.is_empty() {
         "".to_string()
@@ -476,27 +1170,67 @@ pub async fn aggregate_table(
     );
 
     let mut q = sqlx::query(&sql);
-    if let Some(s) = start {
+    if let Some(s) = &filter.start {
         q = q.bind(s);
     }
-    if let Some(e) = end {
+    if let Some(e) = &filter.end {
         q = q.bind(e);
     }
+    if let Some(src) = &filter.source {
+        q = q.bind(src);
+    }
 
     let rows = q
         .fetch_all(pool)
         .await
         .with_context(|| format!("Failed to aggregate table {}", table_name))?;
 
+    // Sums over several overlapping sources are recomputed with one winner per time slice
+    let mut resolved_sums: HashMap<&str, HashMap<String, f64>> = HashMap::new();
+    if filter.source.is_none() && table_has_column(manifest, table_name, "source_name") {
+        for col in &table_config.columns {
+            let priority = manifest.source_priority_for(col.hk_identifier.as_deref());
+            if col.aggregate == "sum" && !priority.is_empty() {
+                let sums = resolved_bucket_sums(
+                    pool,
+                    manifest,
+                    table_name,
+                    &col.field_name,
+                    priority,
+                    (bucket, time_fmt),
+                    filter,
+                )
+                .await?;
+                resolved_sums.insert(col.field_name.as_str(), sums);
+            }
+        }
+    }
+
+    let resolved = |col: &ColumnDefinition, time_bucket: Option<&String>| {
+        let sums = resolved_sums.get(col.field_name.as_str())?;
+        Some(match time_bucket.and_then(|b| sums.get(b)) {
+            Some(t) if col.data_type == "INTEGER" => json!(t.round() as i64),
+            Some(t) => json!(t),
+            None => Value::Null,
+        })
+    };
+    let aggregated = |col: &ColumnDefinition| {
+        ["avg", "sum", "min", "max", "count"].contains(&col.aggregate.as_str())
+    };
+
     let mut results = Vec::new();
+    let mut buckets = HashSet::new();
     for row in rows {
         let mut map = Map::new();
-        if let Ok(val) = row.try_get::<String, _>("time_bucket") {
+        let time_bucket = row.try_get::<String, _>("time_bucket").ok();
+        if let Some(val) = &time_bucket {
             map.insert("time_bucket".to_string(), json!(val));
         }
 
         for col in &table_config.columns {
-            if ["avg", "sum", "min", "max", "count"].contains(&col.aggregate.as_str()) {
+            if let Some(val) = resolved(col, time_bucket.as_ref()) {
+                map.insert(col.field_name.clone(), val);
+            } else if aggregated(col) {
                 if let Ok(val) = row.try_get::<f64, _>(col.field_name.as_str()) {
                     map.insert(col.field_name.clone(), json!(val));
                 } else if let Ok(val) = row.try_get::<i64, _>(col.field_name.as_str()) {
@@ -506,25 +1240,186 @@ pub async fn aggregate_table(
                 }
             }
         }
+        buckets.extend(time_bucket);
         results.push(Value::Object(map));
     }
 
+    // A sample running into the next bucket counts there even if nothing starts in it
+    let mut spilled: Vec<&String> = resolved_sums
+        .values()
+        .flat_map(|sums| sums.keys())
+        .filter(|b| !buckets.contains(*b))
+        .collect();
+    if !spilled.is_empty() {
+        spilled.sort();
+        spilled.dedup();
+        for time_bucket in spilled {
+            let mut map = Map::new();
+            map.insert("time_bucket".to_string(), json!(time_bucket));
+            for col in &table_config.columns {
+                if let Some(val) = resolved(col, Some(time_bucket)) {
+                    map.insert(col.field_name.clone(), val);
+                } else if aggregated(col) {
+                    map.insert(col.field_name.clone(), Value::Null);
+                }
+            }
+            results.push(Value::Object(map));
+        }
+        results.sort_by(|a, b| b["time_bucket"].as_str().cmp(&a["time_bucket"].as_str()));
+    }
+
     Ok(results)
 }
 
+// Samples are read in start order and resolved one cluster of overlapping samples at a
+// time, so memory stays bounded by the longest overlap rather than the table. Each slice is
+// clipped to the requested range and split at bucket boundaries.
+async fn resolved_bucket_sums(
+    pool: &DbPool,
+    manifest: &Manifest,
+    table_name: &str,
+    column: &str,
+    priority: &[String],
+    (bucket, time_fmt): (&str, &str),
+    filter: &RowFilter,
+) -> Result<HashMap<String, f64>> {
+    // Samples overlapping the range, including ones that start before it
+    let mut query_parts = vec![format!("{} IS NOT NULL", column)];
+    if filter.start.is_some() {
+        query_parts.push("end_date >= ?".to_string());
+    }
+    if filter.end.is_some() {
+        query_parts.push("start_date <= ?".to_string());
+    }
+    if let Some(clause) = user_entered_clause(manifest, table_name, filter)? {
+        query_parts.push(clause);
+    }
+
+    let sql = format!(
+        "SELECT start_date, end_date, source_name, CAST({} AS REAL) FROM {} WHERE {} ORDER BY start_date",
+        column,
+        table_name,
+        query_parts.join(" AND ")
+    );
+
+    let mut q = sqlx::query_as::<_, (String, String, Option<String>, f64)>(&sql);
+    if let Some(s) = &filter.start {
+        q = q.bind(s);
+    }
+    if let Some(e) = &filter.end {
+        q = q.bind(e);
+    }
+
+    let range = (
+        filter
+            .start
+            .as_deref()
+            .and_then(priority::parse_timestamp)
+            .unwrap_or(i64::MIN),
+        filter
+            .end
+            .as_deref()
+            .and_then(priority::parse_timestamp)
+            .unwrap_or(i64::MAX),
+    );
+    let mut sums = HashMap::new();
+    let mut cluster: Vec<priority::SourcedInterval> = Vec::new();
+    let mut cluster_end = i64::MIN;
+    let mut rows = q.fetch(pool);
+    while let Some((start, end, source, value)) = rows
+        .try_next()
+        .await
+        .with_context(|| format!("Failed to load {}.{} samples", table_name, column))?
+    {
+        let (Some(start), Some(end)) = (
+            priority::parse_timestamp(&start),
+            priority::parse_timestamp(&end),
+        ) else {
+            continue;
+        };
+        // Nothing later can overlap the cluster once a sample starts after all of it
+        if start > cluster_end {
+            add_resolved(&mut sums, &cluster, priority, range, (bucket, time_fmt));
+            cluster.clear();
+        }
+        cluster_end = cluster_end.max(end);
+        cluster.push(priority::SourcedInterval {
+            start,
+            end,
+            source: source.unwrap_or_default(),
+            value,
+        });
+    }
+    add_resolved(&mut sums, &cluster, priority, range, (bucket, time_fmt));
+
+    Ok(sums)
+}
+
+fn add_resolved(
+    sums: &mut HashMap<String, f64>,
+    intervals: &[priority::SourcedInterval],
+    priority: &[String],
+    (range_start, range_end): (i64, i64),
+    (bucket, time_fmt): (&str, &str),
+) {
+    for slice in priority::resolve_overlaps(intervals, priority) {
+        let (mut from, to) = (slice.start.max(range_start), slice.end.min(range_end));
+        if from > to || (from == to && slice.start < slice.end) {
+            continue;
+        }
+        loop {
+            let part = priority::ResolvedSlice {
+                start: from,
+                end: to.min(next_bucket(from, bucket)),
+                winner: slice.winner,
+            };
+            if let Some(ts) = DateTime::from_timestamp(part.start, 0) {
+                *sums.entry(ts.format(time_fmt).to_string()).or_insert(0.0) +=
+                    part.prorated_value(intervals);
+            }
+            if part.end >= to {
+                break;
+            }
+            from = part.end;
+        }
+    }
+}
+
+// Start of the bucket after the one holding `ts`, in UTC like strftime
+fn next_bucket(ts: i64, bucket: &str) -> i64 {
+    match bucket {
+        "hour" => ts - ts.rem_euclid(3600) + 3600,
+        "day" => ts - ts.rem_euclid(86400) + 86400,
+        _ => DateTime::from_timestamp(ts, 0)
+            .and_then(|dt| {
+                let (year, month) = match dt.month() {
+                    12 => (dt.year() + 1, 1),
+                    month => (dt.year(), month + 1),
+                };
+                NaiveDate::from_ymd_opt(year, month, 1)
+            })
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+            .map(|d| d.and_utc().timestamp())
+            .unwrap_or(i64::MAX),
+    }
+}
+
 pub async fn get_biometric_trends(
     pool: &DbPool,
     manifest: &Manifest,
     start: &str,
     end: &str,
 ) -> Result<Value> {
-    let table_config = manifest
-        .tables
-        .get("vitals")
-        .ok_or_else(|| anyhow::anyhow!("Vitals table not found in manifest"))?;
+    // Every measurement stored alongside heart rate
+    let (table_name, _) = manifest.role("heart_rate")?;
+    let table_config = &manifest.tables[table_name];
 
     let mut select_parts = Vec::new();
     for col in &table_config.columns {
+        // Metadata flags such as HKWasUserEntered are not measurements
+        if col.extraction_source.as_deref() == Some("metadata_value") {
+            continue;
+        }
         if col.data_type == "REAL" || col.data_type == "INTEGER" {
             select_parts.push(format!("AVG({0}) as {0}_avg", col.field_name));
             select_parts.push(format!("MIN({0}) as {0}_min", col.field_name));
@@ -537,8 +1432,9 @@ pub async fn get_biometric_trends(
     }
 
     let sql = format!(
-        "SELECT {} FROM vitals WHERE start_date >= ? AND start_date <= ?",
-        select_parts.join(", ")
+        "SELECT {} FROM {} WHERE start_date >= ? AND start_date <= ?",
+        select_parts.join(", "),
+        table_name
     );
 
     let row = sqlx::query(&sql)
@@ -560,37 +1456,19 @@ pub async fn get_biometric_trends(
     Ok(Value::Object(map))
 }
 
-pub async fn get_recovery_analysis(pool: &DbPool) -> Result<Value> {
+pub async fn get_recovery_analysis(pool: &DbPool, manifest: &Manifest) -> Result<Value> {
+    let hrv = manifest.role("hrv")?;
+    let rhr = manifest.role("resting_hr")?;
+
     // 1. Get 7-day HRV Baseline
-    let baseline_hrv: (f64,) = sqlx::query_as(
-        "SELECT AVG(hrv_sdnn) FROM vitals WHERE hrv_sdnn > 0 AND start_date >= date('now', '-7 days')"
-    )
-    .fetch_one(pool)
-    .await
-    .unwrap_or((0.0,));
+    let baseline_hrv = recent_average(pool, hrv, "-7 days").await;
 
     // 2. Get Last 24h HRV
-    let current_hrv: (f64,) = sqlx::query_as(
-        "SELECT AVG(hrv_sdnn) FROM vitals WHERE hrv_sdnn > 0 AND start_date >= date('now', '-1 day')"
-    )
-    .fetch_one(pool)
-    .await
-    .unwrap_or((0.0,));
+    let current_hrv = recent_average(pool, hrv, "-1 day").await;
 
     // 3. Get RHR Baseline vs Current
-    let baseline_rhr: (f64,) = sqlx::query_as(
-        "SELECT AVG(resting_hr) FROM vitals WHERE resting_hr > 0 AND start_date >= date('now', '-7 days')"
-    )
-    .fetch_one(pool)
-    .await
-    .unwrap_or((0.0,));
-
-    let current_rhr: (f64,) = sqlx::query_as(
-        "SELECT AVG(resting_hr) FROM vitals WHERE resting_hr > 0 AND start_date >= date('now', '-1 day')"
-    )
-    .fetch_one(pool)
-    .await
-    .unwrap_or((0.0,));
+    let baseline_rhr = recent_average(pool, rhr, "-7 days").await;
+    let current_rhr = recent_average(pool, rhr, "-1 day").await;
 
     // Simple Recovery Logic
     let mut score: f64 = 0.0;
@@ -625,44 +1503,155 @@ pub async fn get_recovery_analysis(pool: &DbPool) -> Result<Value> {
     }))
 }
 
+// Mean of a role's positive values since a date('now', ...) modifier, 0 without data
+async fn recent_average(
+    pool: &DbPool,
+    (table_name, col): (&str, &ColumnDefinition),
+    since: &str,
+) -> (f64,) {
+    sqlx::query_as(&format!(
+        "SELECT AVG({0}) FROM {1} WHERE {0} > 0 AND start_date >= date('now', '{2}')",
+        col.field_name, table_name, since
+    ))
+    .fetch_one(pool)
+    .await
+    .unwrap_or((0.0,))
+}
+
+pub async fn get_rr_series(pool: &DbPool, manifest: &Manifest, record_uuid: &str) -> Result<Value> {
+    let beats_config = manifest
+        .beat_to_beat
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Beat-to-beat storage is not configured in manifest"))?;
+
+    let rows: Vec<(i64, String, f64, f64, bool)> = sqlx::query_as(&format!(
+        "SELECT beat_index, timestamp, bpm, rr_ms, continuous FROM {} WHERE record_uuid = ? ORDER BY beat_index ASC",
+        beats_config.target_table
+    ))
+    .bind(record_uuid)
+    .fetch_all(pool)
+    .await
+    .with_context(|| format!("Failed to load beats of record {}", record_uuid))?;
+
+    if rows.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No beat-to-beat data for record {}",
+            record_uuid
+        ));
+    }
+
+    let beats: Vec<hrv::Beat> = rows
+        .into_iter()
+        .map(|(index, timestamp, bpm, rr_ms, continuous)| hrv::Beat {
+            index: index as usize,
+            timestamp,
+            bpm,
+            rr_ms,
+            continuous,
+        })
+        .collect();
+
+    let series: Vec<Value> = beats
+        .iter()
+        .map(|b| {
+            json!({
+                "index": b.index,
+                "timestamp": b.timestamp,
+                "bpm": b.bpm,
+                "rr_ms": b.rr_ms,
+                "continuous": b.continuous
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "record_uuid": record_uuid,
+        "beat_count": beats.len(),
+        "rmssd_ms": hrv::rmssd(&beats),
+        "pnn50": hrv::pnn50(&beats),
+        "beats": series
+    }))
+}
+
 pub async fn get_sleep_summary(
     pool: &DbPool,
+    manifest: &Manifest,
     date: &str, // YYYY-MM-DD
 ) -> Result<Value> {
     // 1. Fetch all sleep records for the window (e.g. 6PM previous day to 12PM current day)
     // For simplicity, we'll just use the provided date string as a start_date prefix
-    let sql = "SELECT sleep_stage, start_date, end_date FROM sleep WHERE start_date LIKE ? ORDER BY start_date ASC";
+    let (sleep_table, stage_col) = manifest.role("sleep_stage")?;
+    let source_expr = if table_has_column(manifest, sleep_table, "source_name") {
+        "source_name"
+    } else {
+        "NULL"
+    };
+    let sql = format!(
+        "SELECT {0} AS stage, start_date, end_date, {1} AS source_name FROM {2} WHERE {0} IS NOT NULL AND start_date LIKE ? ORDER BY start_date ASC",
+        stage_col.field_name, source_expr, sleep_table
+    );
 
-    let rows = sqlx::query(sql)
+    let rows = sqlx::query(&sql)
         .bind(format!("{}%", date))
         .fetch_all(pool)
         .await?;
 
-    let mut staging_seconds = HashMap::new();
-    let mut total_seconds = 0.0;
-
+    // 2. Collect every stage's samples together
+    let mut intervals: Vec<priority::SourcedInterval> = Vec::new();
     for row in rows {
-        let stage: i64 = row.get("sleep_stage");
+        let stage: i64 = row.get("stage");
         let start: String = row.get("start_date");
         let end: String = row.get("end_date");
+        let source: Option<String> = row.get("source_name");
 
-        if let (Ok(s_dt), Ok(e_dt)) = (
-            DateTime::parse_from_rfc3339(&start),
-            DateTime::parse_from_rfc3339(&end),
+        if let (Some(s_ts), Some(e_ts)) = (
+            priority::parse_timestamp(&start),
+            priority::parse_timestamp(&end),
         ) {
-            let duration = e_dt.signed_duration_since(s_dt).num_seconds() as f64;
-            let stage_name = match stage {
-                0 => "In Bed",
-                1 => "Asleep",
-                2 => "Awake",
-                3 => "Core",
-                4 => "Deep",
-                5 => "REM",
-                _ => "Unknown",
-            };
+            intervals.push(priority::SourcedInterval {
+                start: s_ts,
+                end: e_ts,
+                source: source.unwrap_or_default(),
+                value: stage as f64,
+            });
+        }
+    }
+
+    let source_priority = manifest.source_priority_for(stage_col.hk_identifier.as_deref());
 
-            *staging_seconds.entry(stage_name.to_string()).or_insert(0.0) += duration;
-            total_seconds += duration;
+    let mut staging_seconds = HashMap::new();
+    let mut total_seconds = 0.0;
+    let mut add = |stage: i64, seconds: i64| {
+        let stage_name = stage_col.category_label(stage).unwrap_or("Unknown");
+        *staging_seconds.entry(stage_name.to_string()).or_insert(0.0) += seconds as f64;
+    };
+
+    if source_priority.is_empty() {
+        for iv in &intervals {
+            add(iv.value as i64, iv.end - iv.start);
+            total_seconds += (iv.end - iv.start) as f64;
+        }
+    } else {
+        // 3. One source wins each stretch of the night whatever the stage, so a phone's
+        // "Asleep" under the watch's "Core" counts once; the stretch goes to the stages the
+        // winner recorded over it
+        for slice in priority::resolve_overlaps(&intervals, source_priority) {
+            if slice.end == slice.start {
+                continue;
+            }
+            let source = &intervals[slice.winner].source;
+            let mut stages: Vec<i64> = intervals
+                .iter()
+                .filter(|iv| &iv.source == source && iv.end > iv.start)
+                .filter(|iv| iv.start <= slice.start && iv.end >= slice.end)
+                .map(|iv| iv.value as i64)
+                .collect();
+            stages.sort_unstable();
+            stages.dedup();
+            for stage in stages {
+                add(stage, slice.end - slice.start);
+            }
+            total_seconds += (slice.end - slice.start) as f64;
         }
     }
 
@@ -673,7 +1662,95 @@ pub async fn get_sleep_summary(
     }))
 }
 
-async fn ensure_indices(pool: &DbPool, manifest: &Manifest) -> Result<()> {
+// Count and covered time per label of a category column, e.g. stand hours stood vs idle
+pub async fn get_category_summary(
+    pool: &DbPool,
+    manifest: &Manifest,
+    table_name: &str,
+    column: &str,
+    filter: &RowFilter,
+) -> Result<Value> {
+    let col = manifest
+        .tables
+        .get(table_name)
+        .and_then(|t| t.columns.iter().find(|c| c.field_name == column))
+        .ok_or_else(|| anyhow::anyhow!("Column {}.{} not found in manifest", table_name, column))?;
+    if col.categories.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Column {}.{} has no category values",
+            table_name,
+            column
+        ));
+    }
+
+    let mut query_parts = vec![format!("{} IS NOT NULL", column)];
+    if filter.start.is_some() {
+        query_parts.push("start_date >= ?".to_string());
+    }
+    if filter.end.is_some() {
+        query_parts.push("start_date <= ?".to_string());
+    }
+    if filter.source.is_some() {
+        if !table_has_column(manifest, table_name, "source_name") {
+            return Err(anyhow::anyhow!(
+                "Table {} does not record source_name",
+                table_name
+            ));
+        }
+        query_parts.push("source_name = ?".to_string());
+    }
+    if let Some(clause) = user_entered_clause(manifest, table_name, filter)? {
+        query_parts.push(clause);
+    }
+
+    let sql = format!(
+        "SELECT {}, start_date, end_date FROM {} WHERE {}",
+        column,
+        table_name,
+        query_parts.join(" AND ")
+    );
+
+    let mut q = sqlx::query_as::<_, (i64, String, String)>(&sql);
+    if let Some(s) = &filter.start {
+        q = q.bind(s);
+    }
+    if let Some(e) = &filter.end {
+        q = q.bind(e);
+    }
+    if let Some(src) = &filter.source {
+        q = q.bind(src);
+    }
+
+    let rows = q
+        .fetch_all(pool)
+        .await
+        .with_context(|| format!("Failed to load {}.{} categories", table_name, column))?;
+
+    let mut breakdown: Map<String, Value> = Map::new();
+    for (code, start, end) in rows {
+        let label = col.category_label(code).unwrap_or("Unknown");
+        let seconds = match (
+            priority::parse_timestamp(&start),
+            priority::parse_timestamp(&end),
+        ) {
+            (Some(s_ts), Some(e_ts)) => e_ts - s_ts,
+            _ => 0,
+        };
+        let entry = breakdown
+            .entry(label.to_string())
+            .or_insert_with(|| json!({ "code": code, "count": 0, "total_seconds": 0 }));
+        entry["count"] = json!(entry["count"].as_i64().unwrap_or(0) + 1);
+        entry["total_seconds"] = json!(entry["total_seconds"].as_i64().unwrap_or(0) + seconds);
+    }
+
+    Ok(json!({
+        "table": table_name,
+        "column": column,
+        "breakdown": breakdown
+    }))
+}
+
+pub(crate) async fn ensure_indices(pool: &DbPool, manifest: &Manifest) -> Result<()> {
     for table_name in manifest.tables.keys() {
         let sql = format!(
             "CREATE INDEX IF NOT EXISTS idx_{}_start_date ON {} (start_date)",
@@ -736,40 +1813,167 @@ async fn ensure_external_schema(pool: &DbPool, manifest: &Manifest) -> Result<()
     Ok(())
 }
 
-async fn ensure_schema(pool: &DbPool, manifest: &Manifest) -> Result<()> {
-    for (table_name, table_config) in &manifest.tables {
-        let pk_col = table_config.columns.iter().find(|c| c.is_primary_key);
+async fn ensure_beat_schema(pool: &DbPool, manifest: &Manifest) -> Result<()> {
+    if let Some(beats) = &manifest.beat_to_beat {
+        // Keyed by parent and position so re-ingesting an export keeps one row per beat
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (record_uuid TEXT, beat_index INTEGER, timestamp TEXT, bpm REAL, rr_ms REAL, continuous INTEGER, PRIMARY KEY (record_uuid, beat_index))",
+            beats.target_table
+        );
+        sqlx::query(&sql).execute(pool).await?;
+    }
+    Ok(())
+}
 
-        let create_sql = if let Some(pk) = pk_col {
-            format!(
-                "CREATE TABLE IF NOT EXISTS {} ({} {} PRIMARY KEY, creation_date TEXT, start_date TEXT, end_date TEXT)",
-                table_name, pk.field_name, pk.data_type
-            )
-        } else {
+async fn ensure_workout_event_schema(pool: &DbPool, manifest: &Manifest) -> Result<()> {
+    if let Some(events) = &manifest.workout_events {
+        // Keyed by workout and position, like the beats of an HRV record
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} (session_id TEXT, event_index INTEGER, event_type TEXT, date TEXT, duration_minutes REAL, metadata TEXT, PRIMARY KEY (session_id, event_index))",
+            events.target_table
+        );
+        sqlx::query(&sql).execute(pool).await?;
+    }
+    Ok(())
+}
+
+async fn ensure_workout_activity_schema(pool: &DbPool, manifest: &Manifest) -> Result<()> {
+    let Some(activities) = &manifest.workout_activities else {
+        return Ok(());
+    };
+    let table_name = &activities.target_table;
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (session_id TEXT, activity_index INTEGER, uuid TEXT, activity_type TEXT, start_date TEXT, end_date TEXT, duration_minutes REAL, PRIMARY KEY (session_id, activity_index))",
+        table_name
+    ))
+    .execute(pool)
+    .await
+    .with_context(|| format!("Failed to create table {}", table_name))?;
+
+    // Each leg reports the same statistics as a whole workout
+    let rows = sqlx::query(&format!("PRAGMA table_info({})", table_name))
+        .fetch_all(pool)
+        .await?;
+    let mut existing_columns: HashSet<String> = rows
+        .iter()
+        .map(|row| row.get::<String, _>("name"))
+        .collect();
+    let Some((_, workouts)) = manifest.workout_table() else {
+        return Ok(());
+    };
+    for col in &workouts.columns {
+        let per_leg = statistics_attribute(col.extraction_source.as_deref()).is_some()
+            || col.extraction_source.as_deref() == Some("metadata_value");
+        if !per_leg || !existing_columns.insert(col.field_name.clone()) {
+            continue;
+        }
+        info!(
+            "Adding new column to {} table: {} ({})",
+            table_name, col.field_name, col.data_type
+        );
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table_name, col.field_name, col.data_type
+        ))
+        .execute(pool)
+        .await
+        .with_context(|| {
             format!(
-                "CREATE TABLE IF NOT EXISTS {} (uuid TEXT PRIMARY KEY, creation_date TEXT, start_date TEXT, end_date TEXT)",
-                table_name
+                "Failed to add column {} to table {}",
+                col.field_name, table_name
             )
+        })?;
+    }
+    Ok(())
+}
+
+async fn ensure_ingestion_schema(pool: &DbPool) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS ingestions (id INTEGER PRIMARY KEY AUTOINCREMENT, source_file TEXT, export_date TEXT, incremental INTEGER, records_processed INTEGER, records_new INTEGER, records_skipped INTEGER, completed_at TEXT)",
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create ingestions table")?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS ingestion_type_watermarks (ingestion_id INTEGER, table_name TEXT, hk_type TEXT, max_start_date TEXT, PRIMARY KEY (ingestion_id, table_name, hk_type))",
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create ingestion_type_watermarks table")?;
+    Ok(())
+}
+
+async fn ensure_profile_schema(pool: &DbPool) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS user_profile (id INTEGER PRIMARY KEY CHECK (id = 1), date_of_birth TEXT, biological_sex TEXT, blood_type TEXT, skin_type TEXT)",
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create user_profile table")?;
+    Ok(())
+}
+
+// Name and definition of every column a manifest table has, in creation order: the key, the
+// dates, the manifest columns (generated ones with `expression`) and the provenance columns
+pub(crate) fn table_columns(config: &TableConfig) -> Vec<(String, String)> {
+    let mut columns = vec![match config.columns.iter().find(|c| c.is_primary_key) {
+        Some(pk) => (
+            pk.field_name.clone(),
+            format!("{} {} PRIMARY KEY", pk.field_name, pk.data_type),
+        ),
+        None => ("uuid".to_string(), "uuid TEXT PRIMARY KEY".to_string()),
+    }];
+    for date in ["creation_date", "start_date", "end_date"] {
+        columns.push((date.to_string(), format!("{} TEXT", date)));
+    }
+    for col in config.columns.iter().filter(|c| !c.is_primary_key) {
+        let definition = match &col.expression {
+            Some(expr) => format!(
+                "{} {} GENERATED ALWAYS AS ({}) VIRTUAL",
+                col.field_name, col.data_type, expr
+            ),
+            None => format!("{} {}", col.field_name, col.data_type),
         };
+        columns.push((col.field_name.clone(), definition));
+    }
+    for prov_col in &config.provenance {
+        columns.push((prov_col.clone(), format!("{} TEXT", prov_col)));
+    }
+    let mut seen = HashSet::new();
+    columns.retain(|(name, _)| seen.insert(name.clone()));
+    columns
+}
+
+async fn ensure_schema(pool: &DbPool, manifest: &Manifest) -> Result<()> {
+    for (table_name, table_config) in &manifest.tables {
+        let definitions: Vec<String> = table_columns(table_config)
+            .into_iter()
+            .map(|(_, definition)| definition)
+            .collect();
+        let create_sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} ({})",
+            table_name,
+            definitions.join(", ")
+        );
 
         sqlx::query(&create_sql)
             .execute(pool)
             .await
             .with_context(|| format!("Failed to create base table {}", table_name))?;
 
-        let query_sql = format!("PRAGMA table_info({})", table_name);
+        let query_sql = format!("PRAGMA table_xinfo({})", table_name);
         let rows = sqlx::query(&query_sql)
             .fetch_all(pool)
             .await
             .with_context(|| format!("Failed to fetch table info for {}", table_name))?;
 
-        let existing_columns: HashSet<String> = rows
+        let mut existing_columns: HashSet<String> = rows
             .iter()
             .map(|row| row.get::<String, _>("name"))
             .collect();
 
         for col_def in &table_config.columns {
-            if !existing_columns.contains(&col_def.field_name) {
+            if existing_columns.insert(col_def.field_name.clone()) {
                 info!(
                     "Adding new column to {} table: {} ({})",
                     table_name, col_def.field_name, col_def.data_type
@@ -795,6 +1999,31 @@ async fn ensure_schema(pool: &DbPool, manifest: &Manifest) -> Result<()> {
                 })?;
             }
         }
+
+        for prov_col in &table_config.provenance {
+            if provenance_attribute(prov_col).is_none() {
+                return Err(anyhow::anyhow!(
+                    "Unknown provenance column '{}' in table {}",
+                    prov_col,
+                    table_name
+                ));
+            }
+            if existing_columns.insert(prov_col.clone()) {
+                info!(
+                    "Adding provenance column to {} table: {}",
+                    table_name, prov_col
+                );
+                sqlx::query(&format!(
+                    "ALTER TABLE {} ADD COLUMN {} TEXT",
+                    table_name, prov_col
+                ))
+                .execute(pool)
+                .await
+                .with_context(|| {
+                    format!("Failed to add column {} to table {}", prov_col, table_name)
+                })?;
+            }
+        }
     }
 
     Ok(())
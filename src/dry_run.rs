@@ -0,0 +1,254 @@
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::BufRead;
+use std::path::Path;
+use tracing::info;
+
+use crate::db::{self, Manifest};
+use crate::parser::{normalize_date, type_attribute, with_export, Routes};
+
+// What a dry run found in an export, and how it lines up with the manifest
+#[derive(Debug, Default, Serialize)]
+pub struct DryRunReport {
+    pub export_date: Option<String>,
+    pub types: Vec<TypeSummary>, // most frequent first
+    pub unmapped_types: Vec<String>,
+    pub unmatched_columns: Vec<UnmatchedColumn>,
+    pub unknown_categories: Vec<UnknownCategory>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct TypeSummary {
+    pub element: String, // Record, Correlation, Workout, WorkoutStatistics, WorkoutEvent, WorkoutActivity or an element a table reads
+    pub hk_type: String,
+    pub count: usize,
+    pub first_date: Option<String>,
+    pub last_date: Option<String>,
+    pub sources: BTreeSet<String>,
+    pub units: BTreeSet<String>,
+    pub categories: BTreeMap<String, usize>, // HKCategoryValue... -> count
+    pub tables: Vec<String>,                 // where the manifest stores it; empty if unmapped
+}
+
+// A manifest column whose type or key never occurred in the export
+#[derive(Debug, Serialize)]
+pub struct UnmatchedColumn {
+    pub table: String,
+    pub column: String,
+    pub hk_identifier: String,
+    pub extraction_source: Option<String>,
+}
+
+// A category value the manifest has no code for; ingestion rejects these records
+#[derive(Debug, Serialize)]
+pub struct UnknownCategory {
+    pub hk_type: String,
+    pub value: String,
+    pub count: usize,
+}
+
+// Elements reported whether or not the manifest maps them
+const HEALTHKIT_ELEMENTS: [&str; 7] = [
+    "Record",
+    "Correlation",
+    "Workout",
+    "WorkoutStatistics",
+    "WorkoutEvent",
+    "WorkoutActivity",
+    "ActivitySummary",
+];
+
+// Streams the export and reports its contents without touching the database
+pub async fn dry_run(file_path: &Path, manifest: &Manifest) -> Result<DryRunReport> {
+    let (path, manifest) = (file_path.to_path_buf(), manifest.clone());
+    tokio::task::spawn_blocking(move || {
+        with_export(&path, 0, |reader, _| analyze(reader, &manifest))
+    })
+    .await?
+}
+
+pub fn analyze<R: BufRead>(file_reader: R, manifest: &Manifest) -> Result<DryRunReport> {
+    let mut reader = Reader::from_reader(file_reader);
+    reader.config_mut().trim_text(true);
+
+    let mut report = DryRunReport::default();
+    let mut types: HashMap<(String, String), TypeSummary> = HashMap::new();
+    // Elements tables read whole (ActivitySummary, Audiogram...), besides the HealthKit ones
+    let table_elements: HashSet<&str> = manifest.tables.values().map(|c| c.element()).collect();
+    let mut metadata_keys: HashSet<String> = HashSet::new();
+    let mut has_routes = false;
+    let mut buf = Vec::new();
+
+    loop {
+        let e = match reader.read_event_into(&mut buf)? {
+            Event::Start(e) | Event::Empty(e) => e,
+            Event::Eof => break,
+            _ => {
+                buf.clear();
+                continue;
+            }
+        };
+        match e.name().as_ref() {
+            b"ExportDate" => {
+                report.export_date = attribute(&e, "value").map(|v| normalize_date(&v));
+            }
+            b"MetadataEntry" => {
+                if let Some(key) = attribute(&e, "key") {
+                    metadata_keys.insert(key);
+                }
+            }
+            b"FileReference" => has_routes = true,
+            name => {
+                let name = String::from_utf8_lossy(name);
+                if HEALTHKIT_ELEMENTS.contains(&name.as_ref())
+                    || table_elements.contains(name.as_ref())
+                {
+                    observe(&mut types, &name, &e)
+                }
+            }
+        }
+        buf.clear();
+    }
+
+    // Mapped means ingestion stores it: the parser's own lookup decides
+    let routes = Routes::new(manifest);
+    let mut summaries: Vec<TypeSummary> = types
+        .into_values()
+        .map(|mut summary| {
+            summary.tables = routes
+                .tables(&summary.element, &summary.hk_type)
+                .into_iter()
+                .map(String::from)
+                .collect();
+            summary.tables.sort();
+            summary
+        })
+        .collect();
+    summaries.sort_by(|a, b| {
+        b.count
+            .cmp(&a.count)
+            .then_with(|| a.hk_type.cmp(&b.hk_type))
+    });
+
+    report.unmapped_types = summaries
+        .iter()
+        .filter(|s| s.tables.is_empty())
+        .map(|s| s.hk_type.clone())
+        .collect();
+
+    let seen = |element: &str, hk_type: &str| {
+        summaries
+            .iter()
+            .any(|s| s.element == element && s.hk_type == hk_type)
+    };
+    for (table_name, config) in &manifest.tables {
+        for col in &config.columns {
+            let Some(hk_id) = &col.hk_identifier else {
+                continue;
+            };
+            let matched = match col.extraction_source.as_deref() {
+                Some("metadata_value") => metadata_keys.contains(hk_id),
+                Some("route_ref") => has_routes,
+                source if db::statistics_attribute(source).is_some() => {
+                    seen("WorkoutStatistics", hk_id)
+                }
+                _ => seen("Record", hk_id),
+            };
+            if !matched {
+                report.unmatched_columns.push(UnmatchedColumn {
+                    table: table_name.clone(),
+                    column: col.field_name.clone(),
+                    hk_identifier: hk_id.clone(),
+                    extraction_source: col.extraction_source.clone(),
+                });
+            }
+            // Category values that ingestion would reject
+            if col.categories.is_empty() {
+                continue;
+            }
+            let known: HashSet<&str> = col.categories.iter().map(|c| c.hk_value.as_str()).collect();
+            for summary in summaries
+                .iter()
+                .filter(|s| s.element == "Record" && &s.hk_type == hk_id)
+            {
+                for (value, count) in &summary.categories {
+                    if !known.contains(value.as_str()) {
+                        report.unknown_categories.push(UnknownCategory {
+                            hk_type: hk_id.clone(),
+                            value: value.clone(),
+                            count: *count,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    report
+        .unmatched_columns
+        .sort_by(|a, b| (&a.table, &a.column).cmp(&(&b.table, &b.column)));
+    report.types = summaries;
+
+    info!(
+        "Dry run found {} types, {} unmapped",
+        report.types.len(),
+        report.unmapped_types.len()
+    );
+    Ok(report)
+}
+
+fn observe(types: &mut HashMap<(String, String), TypeSummary>, element: &str, e: &BytesStart) {
+    let hk_type = attribute(e, type_attribute(element)).unwrap_or_else(|| element.to_string());
+    let unit_attribute = match element {
+        "Record" | "WorkoutStatistics" => "unit",
+        "Workout" | "WorkoutEvent" | "WorkoutActivity" => "durationUnit",
+        _ => "",
+    };
+    let summary = types
+        .entry((element.to_string(), hk_type.clone()))
+        .or_insert_with(|| TypeSummary {
+            element: element.to_string(),
+            hk_type,
+            ..Default::default()
+        });
+    summary.count += 1;
+
+    let date = attribute(e, "startDate")
+        .or_else(|| attribute(e, "date"))
+        .or_else(|| attribute(e, "dateComponents"))
+        .map(|d| normalize_date(&d));
+    if let Some(date) = date {
+        if summary
+            .first_date
+            .as_ref()
+            .is_none_or(|first| &date < first)
+        {
+            summary.first_date = Some(date.clone());
+        }
+        if summary.last_date.as_ref().is_none_or(|last| &date > last) {
+            summary.last_date = Some(date);
+        }
+    }
+    if let Some(source) = attribute(e, "sourceName") {
+        summary.sources.insert(source);
+    }
+    if let Some(unit) = attribute(e, unit_attribute) {
+        summary.units.insert(unit);
+    }
+    if let Some(value) = attribute(e, "value").filter(|v| v.starts_with("HKCategoryValue")) {
+        *summary.categories.entry(value).or_insert(0) += 1;
+    }
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|a| a.unescape_value().ok())
+        .map(|v| v.to_string())
+}
@@ -0,0 +1,104 @@
+use chrono::{DateTime, Duration, NaiveTime, SecondsFormat, Utc};
+
+// One <InstantaneousBeatsPerMinute> of a HeartRateVariabilityMetadataList
+pub struct Beat {
+    pub index: usize,
+    pub timestamp: String, // RFC3339, UTC
+    pub bpm: f64,
+    pub rr_ms: f64,
+    // RR was measured against the previous beat. False for the first beat and after
+    // gaps, where it falls back to 60000 / bpm and must not enter successive differences.
+    pub continuous: bool,
+}
+
+// Beats are stamped with a local time of day only ("7:52:15.81 PM" or "19:52:15.81"
+// depending on the locale); the parent record's start date supplies day and offset.
+pub fn beats_from_series(raw_start: &str, samples: &[(String, String)]) -> Vec<Beat> {
+    let Ok(start) = DateTime::parse_from_str(raw_start, "%Y-%m-%d %H:%M:%S %z") else {
+        return Vec::new();
+    };
+    let offset = *start.offset();
+    let start_local = start.naive_local();
+
+    let mut beats: Vec<Beat> = Vec::new();
+    let mut prev_instant: Option<DateTime<Utc>> = None;
+
+    for (bpm_raw, time_raw) in samples {
+        let Some(bpm) = bpm_raw.parse::<f64>().ok().filter(|b| *b > 0.0) else {
+            continue;
+        };
+        let nominal_rr = 60_000.0 / bpm;
+
+        let instant = parse_time_of_day(time_raw).and_then(|time| {
+            let mut local = start_local.date().and_time(time);
+            // The series may run past midnight
+            if local < start_local - Duration::hours(12) {
+                local += Duration::days(1);
+            }
+            local
+                .and_local_timezone(offset)
+                .single()
+                .map(|dt| dt.with_timezone(&Utc))
+        });
+
+        let measured = match (prev_instant, instant) {
+            (Some(prev), Some(now)) => {
+                let diff = (now - prev).num_microseconds().unwrap_or(0) as f64 / 1000.0;
+                // Anything much longer than the beat itself means beats were dropped
+                (diff > 0.0 && diff <= nominal_rr * 1.5).then_some(diff)
+            }
+            _ => None,
+        };
+
+        beats.push(Beat {
+            index: beats.len(),
+            timestamp: instant
+                .map(|dt| dt.to_rfc3339_opts(SecondsFormat::Millis, true))
+                .unwrap_or_default(),
+            bpm,
+            rr_ms: measured.unwrap_or(nominal_rr),
+            continuous: measured.is_some(),
+        });
+        if instant.is_some() {
+            prev_instant = instant;
+        }
+    }
+
+    beats
+}
+
+fn parse_time_of_day(raw: &str) -> Option<NaiveTime> {
+    let raw = raw.trim();
+    ["%I:%M:%S%.f %p", "%H:%M:%S%.f"]
+        .iter()
+        .find_map(|fmt| NaiveTime::parse_from_str(raw, fmt).ok())
+}
+
+// Differences between successive measured RR intervals, skipping pairs that involve
+// a fallback interval
+fn successive_differences(beats: &[Beat]) -> Vec<f64> {
+    beats
+        .windows(2)
+        .filter(|pair| pair[0].continuous && pair[1].continuous)
+        .map(|pair| pair[1].rr_ms - pair[0].rr_ms)
+        .collect()
+}
+
+pub fn rmssd(beats: &[Beat]) -> Option<f64> {
+    let diffs = successive_differences(beats);
+    if diffs.is_empty() {
+        return None;
+    }
+    let mean_sq = diffs.iter().map(|d| d * d).sum::<f64>() / diffs.len() as f64;
+    Some(mean_sq.sqrt())
+}
+
+// Share (in %) of successive differences larger than 50 ms
+pub fn pnn50(beats: &[Beat]) -> Option<f64> {
+    let diffs = successive_differences(beats);
+    if diffs.is_empty() {
+        return None;
+    }
+    let over = diffs.iter().filter(|d| d.abs() > 50.0).count();
+    Some(over as f64 * 100.0 / diffs.len() as f64)
+}
@@ -1,16 +1,27 @@
+use crate::archive;
 use crate::db::{DbPool, Manifest};
 use anyhow::Result;
 use quick_xml::events::Event;
 use quick_xml::reader::Reader;
+use serde::Serialize;
 use std::fs;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Read};
 use std::path::Path;
 use tracing::{error, info};
 
+// Reported to on_file for every ECG or route file that was not imported before
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedFile {
+    pub kind: &'static str, // "ecg" or "route"
+    pub file_name: String,
+    pub error: Option<String>,
+}
+
 pub async fn run_external_import(
     base_dir: &Path,
     pool: &DbPool,
     manifest: &Manifest,
+    on_file: Option<impl Fn(&ImportedFile) + Send + Sync>,
 ) -> Result<()> {
     let ext = match &manifest.external_sources {
         Some(e) => e,
@@ -20,21 +31,164 @@ pub async fn run_external_import(
     if let Some(ecg_cfg) = &ext.ecg {
         let folder_path = base_dir.join(&ecg_cfg.folder);
         if folder_path.exists() {
-            import_ecgs(&folder_path, ecg_cfg, pool).await?;
+            import_ecgs(&folder_path, ecg_cfg, pool, &on_file).await?;
         }
     }
 
     if let Some(route_cfg) = &ext.routes {
         let folder_path = base_dir.join(&route_cfg.folder);
         if folder_path.exists() {
-            import_routes(&folder_path, route_cfg, pool, manifest).await?;
+            import_routes(&folder_path, route_cfg, pool, manifest, &on_file).await?;
         }
     }
 
     Ok(())
 }
 
-async fn import_ecgs(folder: &Path, cfg: &crate::db::EcgConfig, pool: &DbPool) -> Result<()> {
+pub async fn run_archive_import(
+    archive_path: &Path,
+    pool: &DbPool,
+    manifest: &Manifest,
+    on_file: Option<impl Fn(&ImportedFile) + Send + Sync>,
+) -> Result<()> {
+    let ext = match &manifest.external_sources {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+
+    let mut zip = archive::open_archive(archive_path)?;
+
+    if let Some(ecg_cfg) = &ext.ecg {
+        info!("Scanning for ECGs in {:?}/{}", archive_path, ecg_cfg.folder);
+        for (index, file_name) in archive::find_folder_entries(&zip, &ecg_cfg.folder, "csv") {
+            if is_already_imported(&ecg_cfg.target_table, &file_name, pool).await? {
+                continue;
+            }
+
+            // One unreadable entry fails that file, not the import
+            let content = zip
+                .by_index(index)
+                .map_err(anyhow::Error::from)
+                .and_then(|mut entry| {
+                    let mut content = String::new();
+                    entry.read_to_string(&mut content)?;
+                    Ok(content)
+                });
+            let result = match content {
+                Ok(content) => process_single_ecg(&file_name, &content, ecg_cfg, pool).await,
+                Err(e) => Err(e),
+            };
+            report_file(&on_file, "ecg", file_name, result);
+        }
+    }
+
+    if let Some(route_cfg) = &ext.routes {
+        info!(
+            "Scanning for Routes in {:?}/{}",
+            archive_path, route_cfg.folder
+        );
+        for (index, file_name) in archive::find_folder_entries(&zip, &route_cfg.folder, "gpx") {
+            if is_already_imported(&route_cfg.target_table, &file_name, pool).await? {
+                continue;
+            }
+
+            let result = match zip.by_index(index) {
+                Ok(entry) => {
+                    let reader = BufReader::new(entry);
+                    process_single_route(&file_name, reader, route_cfg, pool, manifest).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            report_file(&on_file, "route", file_name, result);
+        }
+    }
+
+    Ok(())
+}
+
+// Imports one ECG (.csv) or route (.gpx) file stored at `path`; `file_name` is the name it
+// is recorded and deduplicated under
+pub async fn import_side_car(
+    path: &Path,
+    file_name: &str,
+    pool: &DbPool,
+    manifest: &Manifest,
+    on_file: Option<impl Fn(&ImportedFile) + Send + Sync>,
+) -> Result<()> {
+    let ext = manifest.external_sources.as_ref();
+    let extension = Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("csv") => {
+            let cfg = ext
+                .and_then(|e| e.ecg.as_ref())
+                .ok_or_else(|| anyhow::anyhow!("No ECG source configured in the manifest"))?;
+            if is_already_imported(&cfg.target_table, file_name, pool).await? {
+                info!("ECG {} was already imported", file_name);
+                return Ok(());
+            }
+            let content = fs::read_to_string(path)?;
+            let result = process_single_ecg(file_name, &content, cfg, pool).await;
+            report_file(&on_file, "ecg", file_name.to_string(), result);
+        }
+        Some("gpx") => {
+            let cfg = ext
+                .and_then(|e| e.routes.as_ref())
+                .ok_or_else(|| anyhow::anyhow!("No route source configured in the manifest"))?;
+            if is_already_imported(&cfg.target_table, file_name, pool).await? {
+                info!("Route {} was already imported", file_name);
+                return Ok(());
+            }
+            let file = fs::File::open(path)?;
+            let result =
+                process_single_route(file_name, BufReader::new(file), cfg, pool, manifest).await;
+            report_file(&on_file, "route", file_name.to_string(), result);
+        }
+        _ => anyhow::bail!("{} is neither an ECG (.csv) nor a route (.gpx)", file_name),
+    }
+    Ok(())
+}
+
+fn report_file(
+    on_file: &Option<impl Fn(&ImportedFile)>,
+    kind: &'static str,
+    file_name: String,
+    result: Result<()>,
+) {
+    match &result {
+        Ok(_) => info!("Successfully imported {}: {}", kind, file_name),
+        Err(e) => error!("Failed to import {} {}: {:?}", kind, file_name, e),
+    }
+    if let Some(cb) = on_file {
+        cb(&ImportedFile {
+            kind,
+            file_name,
+            error: result.err().map(|e| e.to_string()),
+        });
+    }
+}
+
+async fn is_already_imported(target_table: &str, file_name: &str, pool: &DbPool) -> Result<bool> {
+    let exists: (i64,) = sqlx::query_as(&format!(
+        "SELECT COUNT(*) FROM {} WHERE file_name = ?",
+        target_table
+    ))
+    .bind(file_name)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(exists.0 > 0)
+}
+
+async fn import_ecgs(
+    folder: &Path,
+    cfg: &crate::db::EcgConfig,
+    pool: &DbPool,
+    on_file: &Option<impl Fn(&ImportedFile)>,
+) -> Result<()> {
     info!("Scanning for ECGs in {:?}", folder);
     let entries = fs::read_dir(folder)?;
 
@@ -44,29 +198,26 @@ async fn import_ecgs(folder: &Path, cfg: &crate::db::EcgConfig, pool: &DbPool) -
         if path.extension().and_then(|s| s.to_str()) == Some("csv") {
             let file_name = path.file_name().unwrap().to_string_lossy().to_string();
 
-            let exists: (i64,) = sqlx::query_as(&format!(
-                "SELECT COUNT(*) FROM {} WHERE file_name = ?",
-                cfg.target_table
-            ))
-            .bind(&file_name)
-            .fetch_one(pool)
-            .await?;
-
-            if exists.0 > 0 {
+            if is_already_imported(&cfg.target_table, &file_name, pool).await? {
                 continue;
             }
 
-            match process_single_ecg(&path, cfg, pool).await {
-                Ok(_) => info!("Successfully imported ECG: {}", file_name),
-                Err(e) => error!("Failed to import ECG {}: {:?}", file_name, e),
-            }
+            let result = match fs::read_to_string(&path) {
+                Ok(content) => process_single_ecg(&file_name, &content, cfg, pool).await,
+                Err(e) => Err(e.into()),
+            };
+            report_file(on_file, "ecg", file_name, result);
         }
     }
     Ok(())
 }
 
-async fn process_single_ecg(path: &Path, cfg: &crate::db::EcgConfig, pool: &DbPool) -> Result<()> {
-    let content = fs::read_to_string(path)?;
+async fn process_single_ecg(
+    file_name: &str,
+    content: &str,
+    cfg: &crate::db::EcgConfig,
+    pool: &DbPool,
+) -> Result<()> {
     let lines: Vec<&str> = content.lines().collect();
 
     let mut metadata = std::collections::HashMap::new();
@@ -114,7 +265,6 @@ async fn process_single_ecg(path: &Path, cfg: &crate::db::EcgConfig, pool: &DbPo
     }
 
     let payload = samples.join(",");
-    let file_name = path.file_name().unwrap().to_string_lossy().to_string();
 
     // Calculate derived metrics
     let numeric_samples: Vec<f64> = samples
@@ -144,7 +294,7 @@ async fn process_single_ecg(path: &Path, cfg: &crate::db::EcgConfig, pool: &DbPo
         "calculated_hr".to_string(),
     ];
     let mut values = vec![
-        file_name,
+        file_name.to_string(),
         sample_count.to_string(),
         mean_voltage.to_string(),
         calculated_hr.to_string(),
@@ -179,6 +329,7 @@ async fn import_routes(
     cfg: &crate::db::RouteConfig,
     pool: &DbPool,
     manifest: &Manifest,
+    on_file: &Option<impl Fn(&ImportedFile)>,
 ) -> Result<()> {
     info!("Scanning for Routes in {:?}", folder);
     let entries = fs::read_dir(folder)?;
@@ -189,29 +340,22 @@ async fn import_routes(
         if path.extension().and_then(|s| s.to_str()) == Some("gpx") {
             let file_name = path.file_name().unwrap().to_string_lossy().to_string();
 
-            let exists: (i64,) = sqlx::query_as(&format!(
-                "SELECT COUNT(*) FROM {} WHERE file_name = ?",
-                cfg.target_table
-            ))
-            .bind(&file_name)
-            .fetch_one(pool)
-            .await?;
-
-            if exists.0 > 0 {
+            if is_already_imported(&cfg.target_table, &file_name, pool).await? {
                 continue;
             }
 
-            match process_single_route(&path, cfg, pool, manifest).await {
-                Ok(_) => info!("Successfully imported Route: {}", file_name),
-                Err(e) => error!("Failed to import Route {}: {:?}", file_name, e),
-            }
+            let file = fs::File::open(&path)?;
+            let result =
+                process_single_route(&file_name, BufReader::new(file), cfg, pool, manifest).await;
+            report_file(on_file, "route", file_name, result);
         }
     }
     Ok(())
 }
 
-async fn process_single_route(
-    path: &Path,
+async fn process_single_route<R: BufRead>(
+    file_name: &str,
+    route_reader: R,
     cfg: &crate::db::RouteConfig,
     pool: &DbPool,
     manifest: &Manifest,
@@ -222,10 +366,8 @@ async fn process_single_route(
         .and_then(|s| s.batch_size)
         .unwrap_or(5000);
 
-    let file = fs::File::open(path)?;
-    let mut reader = Reader::from_reader(BufReader::new(file));
+    let mut reader = Reader::from_reader(route_reader);
     let mut buf = Vec::new();
-    let file_name = path.file_name().unwrap().to_string_lossy().to_string();
 
     let mut point_buffer = Vec::with_capacity(batch_size);
     let mut current_point: Option<std::collections::HashMap<String, String>> = None;
@@ -272,14 +414,14 @@ async fn process_single_route(
         }
 
         if point_buffer.len() >= batch_size {
-            flush_route_points(&file_name, &point_buffer, cfg, pool).await?;
+            flush_route_points(file_name, &point_buffer, cfg, pool).await?;
             point_buffer.clear();
         }
         buf.clear();
     }
 
     if !point_buffer.is_empty() {
-        flush_route_points(&file_name, &point_buffer, cfg, pool).await?;
+        flush_route_points(file_name, &point_buffer, cfg, pool).await?;
     }
 
     Ok(())
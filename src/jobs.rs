@@ -0,0 +1,460 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{sqlite::SqliteRow, Row, Sqlite, Transaction};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::db::DbPool;
+use crate::parser::IngestOptions;
+use crate::quarantine;
+
+// Progress of an ingestion as of its last committed batch. `offset` is the byte position
+// in export.xml right after the last element whose rows were committed.
+#[derive(Debug, Default, Clone)]
+pub struct Checkpoint {
+    pub offset: u64,
+    pub export_date: Option<String>,
+    pub processed: usize,
+    pub inserted: usize,
+    pub skipped: usize,
+    pub quarantined: usize,
+    pub quarantined_types: BTreeSet<String>,
+    pub tables: HashMap<String, usize>,
+    pub newest_starts: HashMap<(String, String), String>,
+}
+
+// An ingestion job as persisted in the jobs table
+#[derive(Debug, Clone)]
+pub struct IngestJob {
+    pub id: String,
+    pub input_path: String,
+    pub options: IngestOptions,
+    pub checkpoint: Checkpoint,
+    pub cancel: Arc<CancelToken>,
+}
+
+// Shared between a running job and the API; the job polls it after every committed batch
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    cancelled: AtomicBool,
+    rollback: AtomicBool,
+}
+
+impl CancelToken {
+    pub fn cancel(&self, rollback: bool) {
+        self.rollback.fetch_or(rollback, Ordering::SeqCst);
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn rollback_requested(&self) -> bool {
+        self.rollback.load(Ordering::SeqCst)
+    }
+}
+
+// A row of the jobs table as exposed by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRecord {
+    pub id: String,
+    pub job_type: String,
+    pub input_path: Option<String>,
+    pub incremental: bool,
+    pub strict: bool,
+    pub status: String,
+    pub records_processed: i64,
+    pub records_new: i64,
+    pub records_skipped: i64,
+    pub records_quarantined: i64,
+    pub table_counts: Option<Value>,
+    pub error: Option<String>,
+    pub rolled_back: bool,
+    pub request: Option<Value>,
+    pub request_id: Option<String>,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct JobFilter {
+    pub status: Option<String>,
+    pub job_type: Option<String>,
+    pub since: Option<String>, // started_at >=
+    pub until: Option<String>, // started_at <=
+}
+
+const JOB_COLUMNS: [(&str, &str); 21] = [
+    ("id", "TEXT PRIMARY KEY"),
+    ("job_type", "TEXT"),
+    ("input_path", "TEXT"),
+    ("incremental", "INTEGER"),
+    ("strict", "INTEGER DEFAULT 0"),
+    ("status", "TEXT"),
+    ("checkpoint_offset", "INTEGER DEFAULT 0"),
+    ("export_date", "TEXT"),
+    ("records_processed", "INTEGER DEFAULT 0"),
+    ("records_new", "INTEGER DEFAULT 0"),
+    ("records_skipped", "INTEGER DEFAULT 0"),
+    ("records_quarantined", "INTEGER DEFAULT 0"),
+    ("quarantined_types", "TEXT"),
+    ("table_counts", "TEXT"),
+    ("newest_starts", "TEXT"), // [[[table, type], start_date], ...]
+    ("error", "TEXT"),
+    ("rolled_back", "INTEGER DEFAULT 0"),
+    ("request", "TEXT"),
+    ("request_id", "TEXT"),
+    ("started_at", "TEXT"),
+    ("finished_at", "TEXT"),
+];
+
+pub(crate) async fn ensure_jobs_schema(pool: &DbPool) -> Result<()> {
+    let cols: Vec<String> = JOB_COLUMNS
+        .iter()
+        .map(|(name, def)| format!("{} {}", name, def))
+        .collect();
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS jobs ({})",
+        cols.join(", ")
+    ))
+    .execute(pool)
+    .await
+    .context("Failed to create jobs table")?;
+
+    // Databases created before a column existed get it added
+    let existing: HashSet<String> = sqlx::query("PRAGMA table_info(jobs)")
+        .fetch_all(pool)
+        .await
+        .context("Failed to fetch table info for jobs")?
+        .iter()
+        .map(|row| row.get::<String, _>("name"))
+        .collect();
+    for (name, def) in JOB_COLUMNS {
+        if !existing.contains(name) {
+            info!("Adding new column to jobs table: {}", name);
+            sqlx::query(&format!("ALTER TABLE jobs ADD COLUMN {} {}", name, def))
+                .execute(pool)
+                .await
+                .with_context(|| format!("Failed to add column {} to table jobs", name))?;
+        }
+    }
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_jobs_started_at ON jobs (started_at)")
+        .execute(pool)
+        .await?;
+
+    // Rows inserted by jobs that have not finished yet: the exact rowids (a JSON array) each
+    // batch added to each table. Ranges would not do, since concurrent jobs and tables
+    // keyed by an INTEGER PRIMARY KEY interleave their rowids.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS job_row_ids (job_id TEXT, table_name TEXT, row_ids TEXT)",
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create job_row_ids table")?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_job_row_ids_job_id ON job_row_ids (job_id)")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn create_job(
+    pool: &DbPool,
+    id: &str,
+    job_type: &str,
+    input_path: &str,
+    options: IngestOptions,
+    request: &Value,
+    request_id: Option<&str>,
+) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO jobs (id, job_type, input_path, incremental, strict, status, request, request_id, started_at) VALUES (?, ?, ?, ?, ?, 'processing', ?, ?, ?)",
+    )
+    .bind(id)
+    .bind(job_type)
+    .bind(input_path)
+    .bind(options.incremental)
+    .bind(options.strict)
+    .bind(request.to_string())
+    .bind(request_id)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await
+    .with_context(|| format!("Failed to create job {}", id))?;
+    Ok(())
+}
+
+// Written in the same transaction as the batch it describes, so a crash can never leave
+// the checkpoint ahead of (or behind) the committed rows
+pub async fn save_checkpoint(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: &str,
+    checkpoint: &Checkpoint,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE jobs SET checkpoint_offset = ?, export_date = ?, records_processed = ?, records_new = ?, records_skipped = ?, records_quarantined = ?, quarantined_types = ?, table_counts = ?, newest_starts = ? WHERE id = ?",
+    )
+    .bind(checkpoint.offset as i64)
+    .bind(&checkpoint.export_date)
+    .bind(checkpoint.processed as i64)
+    .bind(checkpoint.inserted as i64)
+    .bind(checkpoint.skipped as i64)
+    .bind(checkpoint.quarantined as i64)
+    .bind(serde_json::to_string(&checkpoint.quarantined_types)?)
+    .bind(serde_json::to_string(&checkpoint.tables)?)
+    .bind(serde_json::to_string(
+        &checkpoint.newest_starts.iter().collect::<Vec<_>>(),
+    )?)
+    .bind(id)
+    .execute(&mut **tx)
+    .await
+    .with_context(|| format!("Failed to checkpoint job {}", id))?;
+    Ok(())
+}
+
+pub async fn journal_row_ids(
+    tx: &mut Transaction<'_, Sqlite>,
+    id: &str,
+    table_name: &str,
+    row_ids: &[i64],
+) -> Result<()> {
+    sqlx::query("INSERT INTO job_row_ids (job_id, table_name, row_ids) VALUES (?, ?, ?)")
+        .bind(id)
+        .bind(table_name)
+        .bind(serde_json::to_string(row_ids)?)
+        .execute(&mut **tx)
+        .await
+        .with_context(|| format!("Failed to journal rows for job {}", id))?;
+    Ok(())
+}
+
+// Rowids per DELETE statement, well below SQLite's bound parameter limit
+const ROLLBACK_CHUNK: usize = 500;
+
+// Deletes every row the job inserted, returns how many were removed. Rows that already
+// existed before the job ran were ignored on insert and are left alone. The job's
+// quarantined records go with them.
+pub async fn rollback_job(pool: &DbPool, id: &str) -> Result<usize> {
+    let mut tx = pool.begin().await?;
+    let batches: Vec<(String, String)> =
+        sqlx::query_as("SELECT table_name, row_ids FROM job_row_ids WHERE job_id = ?")
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+    let mut removed = 0;
+    for (table_name, row_ids) in batches {
+        let row_ids: Vec<i64> = serde_json::from_str(&row_ids)
+            .with_context(|| format!("Corrupt row journal of job {}", id))?;
+        for chunk in row_ids.chunks(ROLLBACK_CHUNK) {
+            let sql = format!(
+                "DELETE FROM {} WHERE rowid IN ({})",
+                table_name,
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let mut q = sqlx::query(&sql);
+            for row_id in chunk {
+                q = q.bind(*row_id);
+            }
+            removed += q
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("Failed to roll back job {} in {}", id, table_name))?
+                .rows_affected() as usize;
+        }
+    }
+    sqlx::query("DELETE FROM job_row_ids WHERE job_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    quarantine::discard(&mut tx, id).await?;
+    sqlx::query("UPDATE jobs SET rolled_back = 1 WHERE id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    info!("Rolled back {} rows inserted by job {}", removed, id);
+    Ok(removed)
+}
+
+pub async fn finish_job(pool: &DbPool, id: &str, status: &str, error: Option<&str>) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE jobs SET status = ?, error = ?, finished_at = ? WHERE id = ?")
+        .bind(status)
+        .bind(error)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to finish job {}", id))?;
+    // A finished job can no longer be rolled back
+    sqlx::query("DELETE FROM job_row_ids WHERE job_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// ECG and route imports keep no checkpoint, so one the server went down in the middle of
+// cannot be resumed. It is marked failed instead of staying "processing" forever.
+pub async fn fail_interrupted_imports(pool: &DbPool) -> Result<Vec<String>> {
+    let ids: Vec<String> = sqlx::query_scalar(
+        "SELECT id FROM jobs WHERE job_type != 'ingest' AND status = 'processing'",
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to load interrupted imports")?;
+    for id in &ids {
+        finish_job(pool, id, "failed", Some("Interrupted by a server restart")).await?;
+    }
+    Ok(ids)
+}
+
+// Ingestion jobs that were still running when the server went down
+pub async fn interrupted_jobs(pool: &DbPool) -> Result<Vec<IngestJob>> {
+    let rows = sqlx::query(
+        "SELECT id, input_path, incremental, strict, checkpoint_offset, export_date, records_processed, records_new, records_skipped, records_quarantined, quarantined_types, table_counts, newest_starts FROM jobs WHERE job_type = 'ingest' AND status = 'processing' ORDER BY started_at ASC",
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to load interrupted jobs")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| IngestJob {
+            id: row.get("id"),
+            input_path: row.get("input_path"),
+            options: IngestOptions {
+                incremental: row.get("incremental"),
+                strict: row.get::<Option<bool>, _>("strict").unwrap_or(false),
+            },
+            checkpoint: Checkpoint {
+                offset: row.get::<i64, _>("checkpoint_offset") as u64,
+                export_date: row.get("export_date"),
+                processed: row.get::<i64, _>("records_processed") as usize,
+                inserted: row.get::<i64, _>("records_new") as usize,
+                skipped: row.get::<i64, _>("records_skipped") as usize,
+                quarantined: row.get::<i64, _>("records_quarantined") as usize,
+                quarantined_types: row
+                    .get::<Option<String>, _>("quarantined_types")
+                    .and_then(|t| serde_json::from_str(&t).ok())
+                    .unwrap_or_default(),
+                tables: row
+                    .get::<Option<String>, _>("table_counts")
+                    .and_then(|t| serde_json::from_str(&t).ok())
+                    .unwrap_or_default(),
+                newest_starts: row
+                    .get::<Option<String>, _>("newest_starts")
+                    .and_then(|t| serde_json::from_str::<Vec<_>>(&t).ok())
+                    .map(|starts| starts.into_iter().collect())
+                    .unwrap_or_default(),
+            },
+            cancel: Arc::default(),
+        })
+        .collect())
+}
+
+pub async fn get_job(pool: &DbPool, id: &str) -> Result<Option<JobRecord>> {
+    let row = sqlx::query("SELECT * FROM jobs WHERE id = ?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+        .with_context(|| format!("Failed to load job {}", id))?;
+    Ok(row.as_ref().map(job_record))
+}
+
+// Newest first, with the total number of matching jobs for pagination
+pub async fn list_jobs(
+    pool: &DbPool,
+    filter: &JobFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<JobRecord>, i64)> {
+    let mut query_parts = Vec::new();
+    if filter.status.is_some() {
+        query_parts.push("status = ?");
+    }
+    if filter.job_type.is_some() {
+        query_parts.push("job_type = ?");
+    }
+    if filter.since.is_some() {
+        query_parts.push("started_at >= ?");
+    }
+    if filter.until.is_some() {
+        query_parts.push("started_at <= ?");
+    }
+    let where_clause = if query_parts.is_empty() {
+        "".to_string()
+    } else {
+        format!("WHERE {}", query_parts.join(" AND "))
+    };
+    let binds: Vec<&String> = [
+        &filter.status,
+        &filter.job_type,
+        &filter.since,
+        &filter.until,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let count_sql = format!("SELECT COUNT(*) FROM jobs {}", where_clause);
+    let mut count_q = sqlx::query_as::<_, (i64,)>(&count_sql);
+    for val in &binds {
+        count_q = count_q.bind(*val);
+    }
+    let (total,) = count_q
+        .fetch_one(pool)
+        .await
+        .context("Failed to count jobs")?;
+
+    let sql = format!(
+        "SELECT * FROM jobs {} ORDER BY started_at DESC LIMIT ? OFFSET ?",
+        where_clause
+    );
+    let mut q = sqlx::query(&sql);
+    for val in &binds {
+        q = q.bind(*val);
+    }
+    let rows = q
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+        .context("Failed to list jobs")?;
+
+    Ok((rows.iter().map(job_record).collect(), total))
+}
+
+fn job_record(row: &SqliteRow) -> JobRecord {
+    let json_column = |name: &str| {
+        row.get::<Option<String>, _>(name)
+            .and_then(|v| serde_json::from_str(&v).ok())
+    };
+    JobRecord {
+        id: row.get("id"),
+        job_type: row.get("job_type"),
+        input_path: row.get("input_path"),
+        incremental: row.get::<Option<bool>, _>("incremental").unwrap_or(false),
+        strict: row.get::<Option<bool>, _>("strict").unwrap_or(false),
+        status: row.get("status"),
+        records_processed: row.get("records_processed"),
+        records_new: row.get("records_new"),
+        records_skipped: row.get("records_skipped"),
+        records_quarantined: row.get("records_quarantined"),
+        table_counts: json_column("table_counts"),
+        error: row.get("error"),
+        rolled_back: row.get::<Option<bool>, _>("rolled_back").unwrap_or(false),
+        request: json_column("request"),
+        request_id: row.get("request_id"),
+        started_at: row.get("started_at"),
+        finished_at: row.get("finished_at"),
+    }
+}
@@ -1,3 +1,13 @@
+pub mod api;
+pub mod archive;
 pub mod db;
+pub mod dry_run;
+pub mod hrv;
 pub mod importer;
+pub mod jobs;
+pub mod migrations;
 pub mod parser;
+pub mod priority;
+pub mod quarantine;
+pub mod units;
+pub mod uploads;
@@ -0,0 +1,680 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqliteConnection};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+use crate::db::{self, DbPool, Manifest, TableConfig};
+use crate::parser;
+
+const DEFAULT_BACKUP_DIR: &str = "backups";
+
+// The part of a manifest table the database schema follows, stored with every applied version
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableSchema {
+    pub element: String,
+    pub columns: Vec<ColumnSchema>,
+    pub provenance: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnSchema {
+    pub field_name: String,
+    pub data_type: String,
+    pub is_primary_key: bool,
+    pub expression: Option<String>,
+    pub source: Option<String>, // hk_identifier or hk_attribute, what the column holds
+    pub extraction_source: Option<String>,
+}
+
+pub type SchemaSnapshot = BTreeMap<String, TableSchema>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaVersion {
+    pub version: i64,
+    pub manifest_hash: String,
+    pub applied_at: String,
+    pub steps: i64,
+    pub backup_path: Option<String>,
+}
+
+// What it takes to bring the database from the last applied manifest to the current one
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationPlan {
+    pub from_version: Option<i64>, // None before any version was recorded
+    pub from_hash: Option<String>,
+    pub manifest_hash: String,
+    pub steps: Vec<MigrationStep>, // in the order they run, all in one transaction
+    pub warnings: Vec<String>,
+}
+
+impl MigrationPlan {
+    pub fn is_current(&self) -> bool {
+        self.from_hash.as_deref() == Some(self.manifest_hash.as_str())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStep {
+    pub table: String,
+    pub action: &'static str, // "move" copies a metric into its new table, "rebuild" recreates one
+    pub changes: Vec<String>,
+    pub sql: Vec<String>,
+    // Columns whose rows get their uuid recomputed once the SQL ran, see `rekey`
+    pub rekey: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub steps: usize,
+    pub backup_path: Option<String>,
+}
+
+pub(crate) async fn ensure_migration_schema(pool: &DbPool) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_versions (version INTEGER PRIMARY KEY AUTOINCREMENT, manifest_hash TEXT, schema TEXT, applied_at TEXT, steps INTEGER, backup_path TEXT)",
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create schema_versions table")?;
+    Ok(())
+}
+
+pub fn snapshot(manifest: &Manifest) -> SchemaSnapshot {
+    manifest
+        .tables
+        .iter()
+        .map(|(table_name, config)| {
+            let columns = config
+                .columns
+                .iter()
+                .map(|col| ColumnSchema {
+                    field_name: col.field_name.clone(),
+                    data_type: col.data_type.clone(),
+                    is_primary_key: col.is_primary_key,
+                    expression: col.expression.clone(),
+                    source: col.hk_identifier.clone().or(col.hk_attribute.clone()),
+                    extraction_source: col.extraction_source.clone(),
+                })
+                .collect();
+            let schema = TableSchema {
+                element: config.element().to_string(),
+                columns,
+                provenance: config.provenance.clone(),
+            };
+            (table_name.clone(), schema)
+        })
+        .collect()
+}
+
+// The columns a row's uuid is computed over, for tables whose rows are keyed by one
+fn hashed_columns(table: &TableSchema) -> Vec<&ColumnSchema> {
+    if table.element != "Record" || table.columns.iter().any(|c| c.is_primary_key) {
+        return Vec::new();
+    }
+    table
+        .columns
+        .iter()
+        .filter(|c| {
+            c.source.is_some()
+                && c.expression.is_none()
+                && matches!(
+                    c.extraction_source.as_deref(),
+                    None | Some("value") | Some("correlation_member")
+                )
+        })
+        .collect()
+}
+
+fn fingerprint(snapshot: &SchemaSnapshot) -> String {
+    let json = serde_json::to_string(snapshot).unwrap_or_default();
+    format!("{:x}", Sha256::digest(json.as_bytes()))
+}
+
+// Newest first
+pub async fn list_versions(pool: &DbPool) -> Result<Vec<SchemaVersion>> {
+    let rows = sqlx::query(
+        "SELECT version, manifest_hash, applied_at, steps, backup_path FROM schema_versions ORDER BY version DESC",
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to list schema versions")?;
+    Ok(rows
+        .iter()
+        .map(|row| SchemaVersion {
+            version: row.get("version"),
+            manifest_hash: row.get("manifest_hash"),
+            applied_at: row.get("applied_at"),
+            steps: row.get("steps"),
+            backup_path: row.get("backup_path"),
+        })
+        .collect())
+}
+
+async fn latest(pool: &DbPool) -> Result<Option<(i64, String, SchemaSnapshot)>> {
+    let row: Option<(i64, String, String)> = sqlx::query_as(
+        "SELECT version, manifest_hash, schema FROM schema_versions ORDER BY version DESC LIMIT 1",
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to load the applied schema version")?;
+    row.map(|(version, hash, schema)| {
+        let snapshot = serde_json::from_str(&schema)
+            .with_context(|| format!("Schema version {} is unreadable", version))?;
+        Ok((version, hash, snapshot))
+    })
+    .transpose()
+}
+
+async fn record_version<'e, E>(
+    executor: E,
+    snapshot: &SchemaSnapshot,
+    steps: usize,
+    backup_path: Option<&str>,
+) -> Result<i64>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let (version,): (i64,) = sqlx::query_as(
+        "INSERT INTO schema_versions (manifest_hash, schema, applied_at, steps, backup_path) VALUES (?, ?, ?, ?, ?) RETURNING version",
+    )
+    .bind(fingerprint(snapshot))
+    .bind(serde_json::to_string(snapshot)?)
+    .bind(Utc::now().to_rfc3339())
+    .bind(steps as i64)
+    .bind(backup_path)
+    .fetch_one(executor)
+    .await
+    .context("Failed to record schema version")?;
+    Ok(version)
+}
+
+// Runs at startup, after the additive schema updates. The first run adopts the manifest as
+// version 1; later manifests are recorded as they are when adding tables and columns was all
+// they needed, and otherwise left pending for `apply`.
+pub(crate) async fn check(pool: &DbPool, manifest: &Manifest) -> Result<()> {
+    let plan = plan(pool, manifest).await?;
+    if plan.is_current() {
+        return Ok(());
+    }
+    for warning in &plan.warnings {
+        warn!("{}", warning);
+    }
+    match plan.from_version {
+        Some(version) if !plan.steps.is_empty() => warn!(
+            "The manifest needs {} migration steps from schema version {}; preview them with GET /api/migrations and apply them with POST /api/migrations/apply",
+            plan.steps.len(),
+            version
+        ),
+        _ => {
+            let version = record_version(pool, &snapshot(manifest), 0, None).await?;
+            info!("Recorded schema version {}", version);
+        }
+    }
+    Ok(())
+}
+
+// Diffs the last applied manifest against the current one. Nothing is changed, so this is
+// also the dry run of `apply`.
+pub async fn plan(pool: &DbPool, manifest: &Manifest) -> Result<MigrationPlan> {
+    let target = snapshot(manifest);
+    let mut plan = MigrationPlan {
+        manifest_hash: fingerprint(&target),
+        ..Default::default()
+    };
+    let Some((version, hash, applied)) = latest(pool).await? else {
+        return Ok(plan);
+    };
+    plan.from_version = Some(version);
+    plan.from_hash = Some(hash);
+
+    for table_name in applied.keys().filter(|t| !target.contains_key(*t)) {
+        plan.warnings.push(format!(
+            "Table {} is no longer in the manifest and is left as it is",
+            table_name
+        ));
+    }
+
+    // Columns that disappeared from a table, and the ones that took their place there
+    let mut dropped: BTreeMap<&str, Vec<&ColumnSchema>> = BTreeMap::new();
+    let mut added: Vec<(&str, &ColumnSchema)> = Vec::new();
+    for (table_name, new) in &target {
+        let old = applied.get(table_name);
+        let had = |name: &str| old.is_some_and(|o| o.columns.iter().any(|c| c.field_name == name));
+        added.extend(
+            new.columns
+                .iter()
+                .filter(|c| !had(&c.field_name))
+                .map(|c| (table_name.as_str(), c)),
+        );
+        if let Some(old) = old {
+            dropped.insert(
+                table_name,
+                old.columns
+                    .iter()
+                    .filter(|c| !new.columns.iter().any(|n| n.field_name == c.field_name))
+                    .collect(),
+            );
+        }
+    }
+    let mut claimed: HashSet<(&str, &str)> = HashSet::new();
+    let same_metric = |a: &ColumnSchema, b: &ColumnSchema| {
+        a.source.is_some()
+            && a.source == b.source
+            && a.extraction_source == b.extraction_source
+            && a.expression.is_none()
+            && b.expression.is_none()
+    };
+
+    // A metric moved to another record table: copy its rows, then drop it where it was
+    let mut moved: BTreeMap<&str, Vec<(&str, &str, &str)>> = BTreeMap::new(); // table -> (column, to table, to column)
+    for (table_name, columns) in &dropped {
+        if target[*table_name].element != "Record" {
+            continue;
+        }
+        for col in columns {
+            let renamed = added
+                .iter()
+                .any(|(t, a)| t == table_name && same_metric(col, a));
+            let destination = added.iter().find(|(t, a)| {
+                t != table_name
+                    && target[*t].element == "Record"
+                    && !claimed.contains(&(*t, a.field_name.as_str()))
+                    && same_metric(col, a)
+            });
+            if let (false, Some((to_table, to_col))) = (renamed, destination) {
+                claimed.insert((to_table, to_col.field_name.as_str()));
+                moved.entry(table_name).or_default().push((
+                    &col.field_name,
+                    to_table,
+                    &to_col.field_name,
+                ));
+            }
+        }
+    }
+    for (table_name, moves) in &moved {
+        for (column, to_table, to_column) in moves {
+            let to_provenance = &target[*to_table].provenance;
+            let shared: Vec<&str> = ["uuid", "creation_date", "start_date", "end_date"]
+                .into_iter()
+                .chain(
+                    applied[*table_name]
+                        .provenance
+                        .iter()
+                        .filter(|p| to_provenance.contains(p))
+                        .map(String::as_str),
+                )
+                .collect();
+            // The uuid covers the table and column, so the moved rows get new ones
+            let rekey: Vec<String> = hashed_columns(&target[*to_table])
+                .iter()
+                .filter(|c| c.field_name == *to_column)
+                .map(|c| c.field_name.clone())
+                .collect();
+            let mut changes = vec![format!(
+                "move {}.{} to {}.{}",
+                table_name, column, to_table, to_column
+            )];
+            if !rekey.is_empty() {
+                changes.push(format!("recompute the uuid of {} rows", to_column));
+            }
+            plan.steps.push(MigrationStep {
+                table: to_table.to_string(),
+                action: "move",
+                changes,
+                sql: vec![
+                    format!(
+                        "INSERT OR IGNORE INTO {} ({}, {}) SELECT {}, {} FROM {} WHERE {} IS NOT NULL",
+                        to_table,
+                        shared.join(", "),
+                        to_column,
+                        shared.join(", "),
+                        column,
+                        table_name,
+                        column
+                    ),
+                    format!("DELETE FROM {} WHERE {} IS NOT NULL", table_name, column),
+                ],
+                rekey,
+            });
+        }
+    }
+
+    // Anything ALTER TABLE cannot do is a rebuild: create the new layout, copy the rows over
+    // (keeping their rowids, which job journals refer to), swap the tables
+    for (table_name, new) in &target {
+        let Some(old) = applied.get(table_name) else {
+            continue;
+        };
+        let mut changes = Vec::new();
+        let mut renames: Vec<(&str, &str)> = Vec::new();
+        for col in &dropped[table_name.as_str()] {
+            let moved_to = moved
+                .get(table_name.as_str())
+                .and_then(|m| m.iter().find(|(c, _, _)| *c == col.field_name));
+            let renamed_to = added.iter().find(|(t, a)| {
+                t == table_name
+                    && !claimed.contains(&(*t, a.field_name.as_str()))
+                    && same_metric(col, a)
+            });
+            if let Some((_, to_table, to_column)) = moved_to {
+                changes.push(format!(
+                    "drop {} (moved to {}.{})",
+                    col.field_name, to_table, to_column
+                ));
+            } else if let Some((t, to)) = renamed_to {
+                claimed.insert((t, to.field_name.as_str()));
+                renames.push((&col.field_name, &to.field_name));
+                changes.push(format!("rename {} to {}", col.field_name, to.field_name));
+            } else {
+                changes.push(format!("drop {}", col.field_name));
+            }
+        }
+        for col in &new.columns {
+            let Some(before) = old.columns.iter().find(|c| c.field_name == col.field_name) else {
+                continue;
+            };
+            if before.data_type != col.data_type {
+                changes.push(format!(
+                    "change {} from {} to {}",
+                    col.field_name, before.data_type, col.data_type
+                ));
+            }
+            if before.expression != col.expression {
+                changes.push(format!("recompute {}", col.field_name));
+            }
+            if before.is_primary_key != col.is_primary_key {
+                changes.push(format!("change the primary key to {}", col.field_name));
+            }
+        }
+        let mut provenance_dropped = false;
+        for prov_col in old
+            .provenance
+            .iter()
+            .filter(|p| !new.provenance.contains(p))
+        {
+            changes.push(format!("drop provenance column {}", prov_col));
+            provenance_dropped = true;
+        }
+        if changes.is_empty() {
+            continue;
+        }
+        // Renamed columns change the uuid of their rows. A correlation's covers all of its
+        // members, and every row's covers the provenance columns.
+        let is_member =
+            |c: &ColumnSchema| c.extraction_source.as_deref() == Some("correlation_member");
+        let members_changed = dropped[table_name.as_str()].iter().any(|c| is_member(c));
+        let rekey: Vec<String> = hashed_columns(new)
+            .into_iter()
+            .filter(|c| {
+                provenance_dropped
+                    || (members_changed && is_member(c))
+                    || renames.iter().any(|(_, to)| *to == c.field_name)
+            })
+            .map(|c| c.field_name.clone())
+            .collect();
+        if !rekey.is_empty() {
+            changes.push(format!("recompute the uuid of {} rows", rekey.join(", ")));
+        }
+
+        let existing: HashSet<String> = sqlx::query(&format!("PRAGMA table_xinfo({})", table_name))
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get::<String, _>("name"))
+            .collect();
+        let config = &manifest.tables[table_name];
+        let generated: HashSet<&str> = config
+            .columns
+            .iter()
+            .filter(|c| c.expression.is_some())
+            .map(|c| c.field_name.as_str())
+            .collect();
+        let columns = db::table_columns(config);
+        let (mut targets, mut sources) = (vec!["rowid".to_string()], vec!["rowid".to_string()]);
+        for (name, _) in &columns {
+            if generated.contains(name.as_str()) {
+                continue;
+            }
+            let renamed_from = renames
+                .iter()
+                .find(|(_, to)| to == name)
+                .map(|(from, _)| *from)
+                .filter(|from| existing.contains(*from));
+            // Startup already added the new column, and ingestions since then write to it
+            let source = match (renamed_from, existing.contains(name)) {
+                (Some(from), true) => Some(format!("COALESCE({}, {})", name, from)),
+                (Some(from), false) => Some(from.to_string()),
+                (None, true) => Some(name.clone()),
+                (None, false) => None,
+            };
+            if let Some(source) = source {
+                targets.push(name.clone());
+                sources.push(source);
+            }
+        }
+        let definitions: Vec<&str> = columns.iter().map(|(_, d)| d.as_str()).collect();
+        let staging = format!("{}__migrating", table_name);
+        plan.steps.push(MigrationStep {
+            table: table_name.clone(),
+            action: "rebuild",
+            changes,
+            sql: vec![
+                format!("CREATE TABLE {} ({})", staging, definitions.join(", ")),
+                format!(
+                    "INSERT INTO {} ({}) SELECT {} FROM {}",
+                    staging,
+                    targets.join(", "),
+                    sources.join(", "),
+                    table_name
+                ),
+                format!("DROP TABLE {}", table_name),
+                format!("ALTER TABLE {} RENAME TO {}", staging, table_name),
+            ],
+            rekey,
+        });
+    }
+    Ok(plan)
+}
+
+// Recomputes the uuid of the rows holding `columns` the way ingestion computes it now, so
+// re-importing the export finds them instead of storing them again. A row whose new uuid is
+// already taken is the same sample stored twice and is dropped; returns how many were.
+async fn rekey(
+    conn: &mut SqliteConnection,
+    table_name: &str,
+    config: &TableConfig,
+    columns: &[String],
+) -> Result<u64> {
+    // The hash saw the exported unit, which a converted record keeps in source_unit
+    let keeps_source_unit = config.provenance.iter().any(|p| p == "source_unit");
+    let provenance: Vec<&str> = config
+        .provenance
+        .iter()
+        .map(|p| match p.as_str() {
+            "unit" if keeps_source_unit => "COALESCE(source_unit, unit)",
+            "source_unit" => "NULL",
+            p => p,
+        })
+        .collect();
+    let members: Vec<&str> = config
+        .columns
+        .iter()
+        .filter(|c| c.extraction_source.as_deref() == Some("correlation_member"))
+        .map(|c| c.field_name.as_str())
+        .collect();
+
+    // Record rows hold one metric column; correlation rows are keyed over all members
+    let mut selections: Vec<(Vec<&str>, bool)> = columns
+        .iter()
+        .filter(|c| !members.contains(&c.as_str()))
+        .map(|c| (vec![c.as_str()], false))
+        .collect();
+    if columns.iter().any(|c| members.contains(&c.as_str())) {
+        selections.push((members.clone(), true));
+    }
+
+    let mut merged = 0;
+    for (values, correlation) in selections {
+        let present: Vec<String> = values
+            .iter()
+            .map(|c| format!("{} IS NOT NULL", c))
+            .collect();
+        let sql = format!(
+            "SELECT rowid, start_date, end_date, {} FROM {} WHERE {}",
+            values
+                .iter()
+                .chain(&provenance)
+                .copied()
+                .collect::<Vec<_>>()
+                .join(", "),
+            table_name,
+            present.join(" OR ")
+        );
+        let rows = sqlx::query(&sql).fetch_all(&mut *conn).await?;
+        for row in rows {
+            let rowid: i64 = row.get(0);
+            let start_date: String = row.get::<Option<String>, _>(1).unwrap_or_default();
+            let end_date: String = row.get::<Option<String>, _>(2).unwrap_or_default();
+            let stored: Vec<Option<String>> = (3..3 + values.len())
+                .map(|i| stored_text(&row, i))
+                .collect();
+            let prov_values: Vec<String> = (3 + values.len()..3 + values.len() + provenance.len())
+                .map(|i| stored_text(&row, i).unwrap_or_default())
+                .collect();
+            let prov_values = prov_values.iter().map(String::as_str);
+            let uuid = if correlation {
+                let present = values
+                    .iter()
+                    .zip(&stored)
+                    .filter_map(|(c, v)| Some((*c, v.as_deref()?)));
+                parser::correlation_uuid(table_name, &start_date, &end_date, present, prov_values)
+            } else {
+                let value = stored[0].as_deref().unwrap_or_default();
+                parser::record_uuid(
+                    table_name,
+                    values[0],
+                    &start_date,
+                    &end_date,
+                    value,
+                    prov_values,
+                )
+            };
+            let updated = sqlx::query(&format!(
+                "UPDATE OR IGNORE {} SET uuid = ? WHERE rowid = ?",
+                table_name
+            ))
+            .bind(&uuid)
+            .bind(rowid)
+            .execute(&mut *conn)
+            .await?;
+            if updated.rows_affected() == 0 {
+                sqlx::query(&format!("DELETE FROM {} WHERE rowid = ?", table_name))
+                    .bind(rowid)
+                    .execute(&mut *conn)
+                    .await?;
+                merged += 1;
+            }
+        }
+    }
+    Ok(merged)
+}
+
+// A value as ingestion wrote it. Numbers come back in their shortest form, the one the
+// export and the unit conversions write.
+fn stored_text(row: &SqliteRow, index: usize) -> Option<String> {
+    if let Ok(value) = row.try_get::<Option<i64>, _>(index) {
+        return value.map(|v| v.to_string());
+    }
+    if let Ok(value) = row.try_get::<Option<f64>, _>(index) {
+        return value.map(|v| v.to_string());
+    }
+    row.try_get::<Option<String>, _>(index).ok().flatten()
+}
+
+pub fn backup_dir(manifest: &Manifest) -> PathBuf {
+    PathBuf::from(
+        manifest
+            .settings
+            .as_ref()
+            .and_then(|s| s.backup_dir.clone())
+            .unwrap_or_else(|| DEFAULT_BACKUP_DIR.to_string()),
+    )
+}
+
+// Copies the database to the backup directory, then runs every step of the plan and records
+// the new version in one transaction: a failing step leaves the database as it was.
+pub async fn apply(pool: &DbPool, manifest: &Manifest) -> Result<AppliedMigration> {
+    let plan = plan(pool, manifest).await?;
+    let target = snapshot(manifest);
+    if plan.steps.is_empty() {
+        let version = match (plan.is_current(), plan.from_version) {
+            (true, Some(version)) => version,
+            _ => record_version(pool, &target, 0, None).await?,
+        };
+        return Ok(AppliedMigration {
+            version,
+            steps: 0,
+            backup_path: None,
+        });
+    }
+
+    let dir = backup_dir(manifest);
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create backup directory {:?}", dir))?;
+    let backup = dir.join(format!(
+        "schema-v{}-{}.db",
+        plan.from_version.unwrap_or_default(),
+        Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    let backup = backup.to_string_lossy().to_string();
+    sqlx::query("VACUUM INTO ?")
+        .bind(&backup)
+        .execute(pool)
+        .await
+        .with_context(|| format!("Failed to back up the database to {}", backup))?;
+    info!("Backed up the database to {}", backup);
+
+    let mut tx = pool.begin().await?;
+    for step in &plan.steps {
+        for sql in &step.sql {
+            sqlx::query(sql)
+                .execute(&mut *tx)
+                .await
+                .with_context(|| format!("Migration of {} failed at: {}", step.table, sql))?;
+        }
+        if !step.rekey.is_empty() {
+            let merged = rekey(
+                &mut tx,
+                &step.table,
+                &manifest.tables[&step.table],
+                &step.rekey,
+            )
+            .await
+            .with_context(|| format!("Migration of {} failed to recompute uuids", step.table))?;
+            if merged > 0 {
+                info!("Merged {} duplicate rows of {}", merged, step.table);
+            }
+        }
+        info!("Migrated {}: {}", step.table, step.changes.join(", "));
+    }
+    let version = record_version(&mut *tx, &target, plan.steps.len(), Some(&backup)).await?;
+    tx.commit().await?;
+    db::ensure_indices(pool, manifest).await?;
+
+    info!(
+        "Applied schema version {} in {} steps",
+        version,
+        plan.steps.len()
+    );
+    Ok(AppliedMigration {
+        version,
+        steps: plan.steps.len(),
+        backup_path: Some(backup),
+    })
+}
@@ -1,89 +1,730 @@
-use crate::db::{DbPool, Manifest};
+use crate::archive;
+use crate::db::{self, DbPool, Manifest, TableConfig};
+use crate::hrv;
+use crate::importer::{self, ImportedFile};
+use crate::jobs::{self, Checkpoint};
+use crate::quarantine::{self, Rejection};
+use crate::units;
 use chrono::{DateTime, Utc};
+use quick_xml::events::attributes::Attribute;
 use quick_xml::events::{BytesStart, Event};
+use quick_xml::name::QName;
 use quick_xml::reader::Reader;
 use sha2::Digest;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
-use tracing::{error, info};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 
 pub struct DataPoint {
     pub table_name: String,
     pub columns: HashMap<String, String>, // column_name -> value
 }
 
+// Where a <Record> of a given HK type lands
+struct RecordTarget {
+    table_name: String,
+    field_name: String,
+    provenance: Vec<String>,
+    unit: Option<String>,
+    data_type: String,
+    metadata: Vec<(String, String)>, // MetadataEntry key -> column
+    rmssd_column: Option<String>,
+    pnn50_column: Option<String>,
+    categories: HashMap<String, i64>, // HKCategoryValue... -> stored code
+}
+
+// Children of a non-empty <Record>
+struct RecordChildren {
+    metadata: Vec<(String, String)>,
+    beats: Vec<(String, String)>, // InstantaneousBeatsPerMinute (bpm, time)
+}
+
+// A <Correlation> together with the quantity samples it wraps
+struct CorrelationSample {
+    creation_date: String,
+    start_date: String,
+    end_date: String,
+    provenance: HashMap<&'static str, String>,
+    metadata: Vec<(String, String)>,
+    members: Vec<CorrelationMember>,
+}
+
+struct CorrelationMember {
+    hk_type: String,
+    value: String,
+    unit: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IngestOptions {
+    // Skip rows whose key is already stored instead of inserting them again
+    pub incremental: bool,
+    // Fail on the first element that cannot be stored faithfully instead of quarantining it
+    pub strict: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct IngestReport {
+    pub processed: usize,                    // rows handed to the database
+    pub inserted: usize,                     // rows that were not there yet
+    pub skipped: usize,                      // rows incremental mode found already stored
+    pub quarantined: usize,                  // elements rejected into the quarantine table
+    pub quarantined_types: BTreeSet<String>, // their types, which get no watermark
+    pub export_date: Option<String>,
+    pub newest_starts: HashMap<(String, String), String>, // (table, type) -> newest start_date stored
+    pub tables: HashMap<String, usize>,                   // rows handed to the database per table
+    pub cancelled: bool,                                  // stopped early at the job's request
+    pub parse_error: Option<String>, // malformed XML that ended the parse before EOF
+    pub side_cars: Vec<ImportedFile>, // ECG and route files imported from export.zip
+}
+
+impl IngestReport {
+    fn checkpoint(&self, offset: u64) -> Checkpoint {
+        Checkpoint {
+            offset,
+            export_date: self.export_date.clone(),
+            processed: self.processed,
+            inserted: self.inserted,
+            skipped: self.skipped,
+            quarantined: self.quarantined,
+            quarantined_types: self.quarantined_types.clone(),
+            tables: self.tables.clone(),
+            newest_starts: self.newest_starts.clone(),
+        }
+    }
+}
+
+// Where an ingestion stands after a committed batch
+#[derive(Debug, Default, Clone)]
+pub struct IngestProgress {
+    pub bytes_read: u64, // position in export.xml, including a resumed offset
+    pub total_bytes: Option<u64>, // size of export.xml (uncompressed inside a zip)
+    pub records_processed: usize,
+    pub tables: HashMap<String, usize>,
+    pub quarantined: usize,
+    pub queued_batches: usize, // parsed but not yet written; PIPELINE_DEPTH when the database lags
+}
+
+impl IngestProgress {
+    pub fn fraction(&self) -> Option<f64> {
+        self.total_bytes
+            .filter(|total| *total > 0)
+            .map(|total| (self.bytes_read as f64 / total as f64).min(1.0))
+    }
+}
+
 pub async fn parse_and_ingest(
     file_path: &Path,
     pool: &DbPool,
     manifest: &Manifest,
     on_progress: Option<impl Fn(usize) + Send + Sync>,
 ) -> anyhow::Result<usize> {
+    let report = parse_and_ingest_with_options(
+        file_path,
+        pool,
+        manifest,
+        IngestOptions::default(),
+        on_progress,
+    )
+    .await?;
+    Ok(report.processed)
+}
+
+pub async fn parse_and_ingest_with_options(
+    file_path: &Path,
+    pool: &DbPool,
+    manifest: &Manifest,
+    options: IngestOptions,
+    on_progress: Option<impl Fn(usize) + Send + Sync>,
+) -> anyhow::Result<IngestReport> {
+    let on_progress = on_progress.map(|cb| move |p: &IngestProgress| cb(p.records_processed));
+    ingest_file(file_path, pool, manifest, options, None, on_progress).await
+}
+
+// Runs a persisted job, checkpointing every batch; picks up at the job's last checkpoint
+// when it was interrupted and stops after the batch during which it was cancelled
+pub async fn run_ingest_job(
+    job: &jobs::IngestJob,
+    pool: &DbPool,
+    manifest: &Manifest,
+    on_progress: Option<impl Fn(&IngestProgress) + Send + Sync>,
+) -> anyhow::Result<IngestReport> {
+    if job.checkpoint.offset > 0 {
+        info!(
+            "Resuming job {} at byte {} ({} records already processed)",
+            job.id, job.checkpoint.offset, job.checkpoint.processed
+        );
+    }
+    ingest_file(
+        Path::new(&job.input_path),
+        pool,
+        manifest,
+        job.options,
+        Some(job),
+        on_progress,
+    )
+    .await
+}
+
+async fn ingest_file(
+    file_path: &Path,
+    pool: &DbPool,
+    manifest: &Manifest,
+    options: IngestOptions,
+    job: Option<&jobs::IngestJob>,
+    on_progress: Option<impl Fn(&IngestProgress) + Send + Sync>,
+) -> anyhow::Result<IngestReport> {
+    let mut report = ingest_pipeline(file_path, pool, manifest, options, job, on_progress).await?;
+
+    // Half an export must not move the watermarks
+    if report.cancelled {
+        info!("Job cancelled after {} records", report.processed);
+        return Ok(report);
+    }
+    if report.parse_error.is_none() {
+        db::record_ingestion(
+            pool,
+            &file_path.to_string_lossy(),
+            options.incremental,
+            &report,
+        )
+        .await?;
+    }
+
+    // export.zip also carries the ECG and route side-car files
+    if archive::is_zip_archive(file_path) {
+        let side_cars = std::sync::Mutex::new(Vec::new());
+        importer::run_archive_import(
+            file_path,
+            pool,
+            manifest,
+            Some(|file: &ImportedFile| side_cars.lock().unwrap().push(file.clone())),
+        )
+        .await?;
+        report.side_cars = side_cars.into_inner().unwrap();
+    }
+    Ok(report)
+}
+
+// Where each element of the export is stored, as parse_xml reads the manifest. The dry run
+// uses the same lookup, so what it reports as mapped is what ingestion stores.
+pub(crate) struct Routes<'a> {
+    records: HashMap<String, RecordTarget>,
+    correlations: HashMap<String, String>, // correlation type -> table
+    // Tables fed one row per element, by element name; <Workout> is handled on its own
+    elements: HashMap<Vec<u8>, Vec<&'a str>>,
+    workout_table: Option<(&'a str, &'a TableConfig)>,
+    beat_table: Option<String>,
+    // Workout events and activities are only read inside a stored <Workout>
+    event_table: Option<String>,
+    activity_table: Option<String>,
+}
+
+impl<'a> Routes<'a> {
+    pub(crate) fn new(manifest: &'a Manifest) -> Self {
+        let mut records: HashMap<String, RecordTarget> = HashMap::new();
+        let mut correlations: HashMap<String, String> = HashMap::new();
+        let mut elements: HashMap<Vec<u8>, Vec<&str>> = HashMap::new();
+        for (table_name, config) in &manifest.tables {
+            if let Some(corr_type) = &config.correlation_type {
+                correlations.insert(corr_type.clone(), table_name.clone());
+            }
+            match config.element() {
+                "Record" | "Correlation" => {}
+                element => {
+                    if element != "Workout" {
+                        elements
+                            .entry(element.as_bytes().to_vec())
+                            .or_default()
+                            .push(table_name);
+                    }
+                    continue;
+                }
+            }
+            let metadata_columns: Vec<(String, String)> = config
+                .columns
+                .iter()
+                .filter(|col| col.extraction_source.as_deref() == Some("metadata_value"))
+                .filter_map(|col| Some((col.hk_identifier.clone()?, col.field_name.clone())))
+                .collect();
+            for col in &config.columns {
+                if let Some(hk_id) = &col.hk_identifier {
+                    if col.extraction_source.is_none()
+                        || col.extraction_source.as_deref() == Some("value")
+                    {
+                        records.insert(
+                            hk_id.clone(),
+                            RecordTarget {
+                                table_name: table_name.clone(),
+                                field_name: col.field_name.clone(),
+                                provenance: config.provenance.clone(),
+                                unit: col.unit.clone(),
+                                data_type: col.data_type.clone(),
+                                metadata: metadata_columns.clone(),
+                                rmssd_column: None,
+                                pnn50_column: None,
+                                categories: col
+                                    .categories
+                                    .iter()
+                                    .map(|c| (c.hk_value.clone(), c.code))
+                                    .collect(),
+                            },
+                        );
+                    }
+                }
+            }
+        }
+        // Statistics derived from a record's beat series land next to its value
+        for config in manifest.tables.values() {
+            for col in &config.columns {
+                let Some(target) = col
+                    .hk_identifier
+                    .as_ref()
+                    .and_then(|hk_id| records.get_mut(hk_id))
+                else {
+                    continue;
+                };
+                match col.extraction_source.as_deref() {
+                    Some("beat_rmssd") => target.rmssd_column = Some(col.field_name.clone()),
+                    Some("beat_pnn50") => target.pnn50_column = Some(col.field_name.clone()),
+                    _ => {}
+                }
+            }
+        }
+
+        Routes {
+            records,
+            correlations,
+            elements,
+            workout_table: manifest.workout_table(),
+            beat_table: manifest
+                .beat_to_beat
+                .as_ref()
+                .map(|b| b.target_table.clone()),
+            event_table: manifest
+                .workout_events
+                .as_ref()
+                .map(|w| w.target_table.clone()),
+            activity_table: manifest
+                .workout_activities
+                .as_ref()
+                .map(|w| w.target_table.clone()),
+        }
+    }
+
+    // Tables an element of the given type (see type_attribute) is stored in; empty when
+    // ingestion would drop it
+    pub(crate) fn tables(&self, element: &str, hk_type: &str) -> Vec<&str> {
+        let workout_table = self.workout_table.map(|(name, _)| name);
+        let table = match element {
+            "Record" => self.records.get(hk_type).map(|t| t.table_name.as_str()),
+            "Correlation" => self.correlations.get(hk_type).map(String::as_str),
+            "Workout" => workout_table,
+            "WorkoutStatistics" => self
+                .workout_table
+                .filter(|(_, config)| {
+                    config.columns.iter().any(|col| {
+                        col.hk_identifier.as_deref() == Some(hk_type)
+                            && db::statistics_attribute(col.extraction_source.as_deref()).is_some()
+                    })
+                })
+                .map(|(name, _)| name),
+            "WorkoutEvent" => workout_table.and(self.event_table.as_deref()),
+            "WorkoutActivity" => workout_table.and(self.activity_table.as_deref()),
+            element => {
+                return self
+                    .elements
+                    .get(element.as_bytes())
+                    .cloned()
+                    .unwrap_or_default()
+            }
+        };
+        table.into_iter().collect()
+    }
+}
+
+// The attribute naming an element's type: its HK identifier, or the activity for workouts
+pub(crate) fn type_attribute(element: &str) -> &'static str {
+    match element {
+        "Workout" | "WorkoutActivity" => "workoutActivityType",
+        _ => "type",
+    }
+}
+
+// Batches the parse thread may run ahead of the writer before it blocks
+pub const PIPELINE_DEPTH: usize = 4;
+
+// Handed from the parse thread to the writer, in document order
+enum Parsed {
+    Profile(db::MeProfile),
+    Batch(Box<ParsedBatch>),
+}
+
+struct ParsedBatch {
+    rows: HashMap<String, Vec<DataPoint>>,
+    offset: u64, // byte position right after the batch's last element
+    total_bytes: Option<u64>,
+    counters: IngestReport, // the parser's counters as of this batch; `inserted` is the writer's
+    rejections: Vec<Rejection>,
+}
+
+// How parse_xml treats the file as a whole
+#[derive(Clone, Copy)]
+struct ParseMode {
+    strict: bool,      // fail instead of quarantining, see IngestOptions
+    flush_final: bool, // always send a last batch, so a job's checkpoint reaches the end
+}
+
+// Parsing runs on a blocking thread and feeds the async writer through a bounded channel,
+// so a slow database holds the parser back instead of buffering the whole file. Every batch
+// is committed (and checkpointed) in document order.
+async fn ingest_pipeline(
+    file_path: &Path,
+    pool: &DbPool,
+    manifest: &Manifest,
+    options: IngestOptions,
+    job: Option<&jobs::IngestJob>,
+    on_progress: Option<impl Fn(&IngestProgress) + Send + Sync>,
+) -> anyhow::Result<IngestReport> {
+    let job_id = job.map(|j| j.id.as_str());
+    let start = job.map(|j| j.checkpoint.clone()).unwrap_or_default();
+
+    let (tx, mut rx) = mpsc::channel(PIPELINE_DEPTH);
+    let parser = {
+        let (path, manifest, start) = (file_path.to_path_buf(), manifest.clone(), start.clone());
+        let mode = ParseMode {
+            strict: options.strict,
+            flush_final: job_id.is_some(),
+        };
+        tokio::task::spawn_blocking(move || read_export(&path, &manifest, start, mode, &tx))
+    };
+
+    let source_file = file_path.to_string_lossy();
+    // The parser keeps every counter but these, which depend on what the database holds
+    let mut report = IngestReport {
+        inserted: start.inserted,
+        skipped: start.skipped,
+        newest_starts: start.newest_starts.clone(),
+        ..Default::default()
+    };
+    while let Some(parsed) = rx.recv().await {
+        let batch = match parsed {
+            Parsed::Profile(profile) => {
+                db::save_profile(pool, &profile).await?;
+                continue;
+            }
+            Parsed::Batch(batch) => *batch,
+        };
+        let has_rows = batch.rows.values().any(|rows| !rows.is_empty());
+        let has_rejections = !batch.rejections.is_empty();
+        let mut rows = batch.rows;
+        note_newest_starts(manifest, &rows, &mut report.newest_starts);
+        if options.incremental {
+            report.skipped += skip_stored_rows(pool, manifest, &mut rows).await?;
+        }
+        report = IngestReport {
+            inserted: report.inserted,
+            skipped: report.skipped,
+            newest_starts: report.newest_starts,
+            ..batch.counters
+        };
+        let checkpoint = report.checkpoint(batch.offset);
+        report.inserted += flush_buffers(
+            rows,
+            &batch.rejections,
+            pool,
+            &source_file,
+            job_id.map(|id| (id, checkpoint)),
+        )
+        .await?;
+        if has_rows || has_rejections {
+            info!("Processed {} records...", report.processed);
+            if let Some(ref cb) = on_progress {
+                cb(&IngestProgress {
+                    bytes_read: batch.offset,
+                    total_bytes: batch.total_bytes,
+                    records_processed: report.processed,
+                    tables: report.tables.clone(),
+                    quarantined: report.quarantined,
+                    queued_batches: rx.len(),
+                });
+            }
+        }
+        // Everything up to here is committed, so this is a clean place to stop
+        if job.is_some_and(|j| j.cancel.is_cancelled()) {
+            report.cancelled = true;
+            break;
+        }
+    }
+    // Closing the channel makes a parser that is still running give up
+    drop(rx);
+    let parsed = parser.await??;
+    if report.cancelled {
+        return Ok(report);
+    }
+    let report = IngestReport {
+        inserted: report.inserted,
+        skipped: report.skipped,
+        newest_starts: report.newest_starts,
+        ..parsed
+    };
+
+    if report.quarantined > 0 {
+        warn!(
+            "Quarantined {} elements that could not be stored faithfully",
+            report.quarantined
+        );
+    }
+    if report.skipped > 0 {
+        info!("Skipped {} rows that were already stored", report.skipped);
+    }
+    info!(
+        "Finished processing. Total records: {} ({} new)",
+        report.processed, report.inserted
+    );
+    Ok(report)
+}
+
+// Opens export.xml (or the entry inside export.zip) at the checkpoint and parses it
+fn read_export(
+    file_path: &Path,
+    manifest: &Manifest,
+    start: Checkpoint,
+    mode: ParseMode,
+    tx: &mpsc::Sender<Parsed>,
+) -> anyhow::Result<IngestReport> {
+    with_export(file_path, start.offset, |reader, total_bytes| {
+        parse_xml(reader, manifest, start, Some(total_bytes), mode, tx)
+    })
+}
+
+// Hands `read` the export's XML positioned at `offset`, along with its total size. A zip
+// is streamed straight out of the archive without unpacking it to disk.
+pub(crate) fn with_export<T>(
+    file_path: &Path,
+    offset: u64,
+    read: impl FnOnce(&mut dyn BufRead, u64) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    if archive::is_zip_archive(file_path) {
+        let mut zip = archive::open_archive(file_path)?;
+        let index = archive::find_export_xml(&zip)?;
+        let mut entry = zip.by_index(index)?;
+        let total_bytes = entry.size();
+        info!(
+            "Starting streaming parse of {} in {:?}",
+            entry.name(),
+            file_path
+        );
+        // Compressed entries cannot seek; reading past the checkpoint still skips the parsing
+        io::copy(&mut (&mut entry).take(offset), &mut io::sink())?;
+        let mut reader = BufReader::new(entry);
+        read(&mut reader, total_bytes)
+    } else {
+        let mut file = File::open(file_path)?;
+        let total_bytes = file.metadata()?.len();
+        info!("Starting streaming parse of {:?}", file_path);
+        file.seek(SeekFrom::Start(offset))?;
+        read(&mut BufReader::new(file), total_bytes)
+    }
+}
+
+// Returns the parser's final counters. Stops early, without an error, once the writer has
+// gone away; malformed XML ends the parse with `parse_error` set, or fails it in strict mode.
+fn parse_xml<R: BufRead>(
+    file_reader: R,
+    manifest: &Manifest,
+    start: Checkpoint,
+    total_bytes: Option<u64>,
+    mode: ParseMode,
+    tx: &mpsc::Sender<Parsed>,
+) -> anyhow::Result<IngestReport> {
     let batch_size = manifest
         .settings
         .as_ref()
         .and_then(|s| s.batch_size)
         .unwrap_or(5000);
 
-    let file = File::open(file_path)?;
-    let file_reader = BufReader::new(file);
     let mut reader = Reader::from_reader(file_reader);
     reader.config_mut().trim_text(true);
+    // A resumed reader starts between elements and will meet </HealthData> unopened
+    reader.config_mut().allow_unmatched_ends = start.offset > 0;
 
     let mut table_buffers: HashMap<String, Vec<DataPoint>> = HashMap::new();
-    let mut total_count = 0;
+    let routes = Routes::new(manifest);
+    let tables = manifest
+        .tables
+        .keys()
+        .chain(routes.beat_table.iter())
+        .chain(routes.event_table.iter())
+        .chain(routes.activity_table.iter());
+    for table_name in tables {
+        table_buffers.insert(table_name.clone(), Vec::with_capacity(batch_size));
+    }
+    let mut state = ParseState {
+        table_buffers,
+        rejections: Vec::new(),
+        report: IngestReport {
+            processed: start.processed,
+            quarantined: start.quarantined,
+            quarantined_types: start.quarantined_types.clone(),
+            export_date: start.export_date.clone(),
+            tables: start.tables.clone(),
+            ..Default::default()
+        },
+        batch_size,
+        total_bytes,
+    };
 
-    // Pre-process manifest for quick lookup
-    let mut record_map: HashMap<String, (String, String)> = HashMap::new();
-    for (table_name, config) in &manifest.tables {
-        for col in &config.columns {
-            if let Some(hk_id) = &col.hk_identifier {
-                if col.extraction_source.is_none()
-                    || col.extraction_source.as_deref() == Some("value")
-                {
-                    record_map.insert(hk_id.clone(), (table_name.clone(), col.field_name.clone()));
-                }
+    match read_events(&mut reader, manifest, &routes, &start, mode, tx, &mut state) {
+        Ok(true) => {}
+        Ok(false) => return Ok(state.report),
+        // The reader cannot resynchronise after a syntax error, so the rest of the file is lost
+        Err(e) => match e.downcast::<ParseFailure>() {
+            Ok(failure) if !mode.strict => {
+                error!("{}", failure.0);
+                state.report.parse_error = Some(failure.0);
             }
-            table_buffers
-                .entry(table_name.clone())
-                .or_insert_with(|| Vec::with_capacity(batch_size));
-        }
+            Ok(failure) => return Err(failure.into()),
+            Err(e) => return Err(e),
+        },
+    }
+
+    // Final batch; jobs always send one so their checkpoint covers the whole file
+    let ParseState {
+        mut table_buffers,
+        mut rejections,
+        mut report,
+        ..
+    } = state;
+    if count_batch(&table_buffers, &mut report) > 0 || !rejections.is_empty() || mode.flush_final {
+        let offset = start.offset + reader.buffer_position();
+        send_batch(
+            &mut table_buffers,
+            &mut rejections,
+            &report,
+            offset,
+            total_bytes,
+            tx,
+        );
+    }
+    Ok(report)
+}
+
+// What parse_xml has read but not yet handed to the writer
+struct ParseState {
+    table_buffers: HashMap<String, Vec<DataPoint>>,
+    rejections: Vec<Rejection>,
+    report: IngestReport,
+    batch_size: usize,
+    total_bytes: Option<u64>,
+}
+
+// Malformed XML anywhere in the export, nested elements included
+#[derive(Debug)]
+struct ParseFailure(String);
+
+impl std::fmt::Display for ParseFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
+}
+
+impl std::error::Error for ParseFailure {}
+
+fn malformed(offset: u64, e: impl std::fmt::Display) -> anyhow::Error {
+    ParseFailure(format!("Malformed XML at byte {}: {}", offset, e)).into()
+}
 
-    info!("Starting streaming parse of {:?}", file_path);
+// Reads the export's elements into `state`, sending full batches as it goes. Returns false
+// once the writer has gone away; malformed XML comes back as a ParseFailure.
+fn read_events<R: BufRead>(
+    reader: &mut Reader<R>,
+    manifest: &Manifest,
+    routes: &Routes,
+    start: &Checkpoint,
+    mode: ParseMode,
+    tx: &mpsc::Sender<Parsed>,
+    state: &mut ParseState,
+) -> anyhow::Result<bool> {
+    let ParseState {
+        table_buffers,
+        rejections,
+        report,
+        batch_size,
+        total_bytes,
+    } = state;
+    let (batch_size, total_bytes) = (*batch_size, *total_bytes);
+    let Routes {
+        records: record_map,
+        correlations: correlation_map,
+        elements: element_tables,
+        workout_table,
+        beat_table,
+        event_table,
+        activity_table,
+    } = routes;
 
     let mut buf = Vec::new();
     loop {
+        let position = start.offset + reader.buffer_position();
         match reader.read_event_into(&mut buf) {
             Ok(Event::Empty(e)) => {
                 let name = e.name();
                 if name.as_ref() == b"Record" {
-                    if let Some(dp) = extract_record_data(&e, &record_map) {
-                        if let Some(buffer) = table_buffers.get_mut(&dp.table_name) {
-                            buffer.push(dp);
+                    match extract_record_data(&e, record_map, &[], &[]) {
+                        Ok(Some(dp)) => {
+                            if let Some(buffer) = table_buffers.get_mut(&dp.table_name) {
+                                buffer.push(dp);
+                            }
                         }
+                        Ok(None) => {}
+                        Err(reason) => reject(
+                            rejections,
+                            report,
+                            mode.strict,
+                            rejection(position, "Record", &e, reason),
+                        )?,
                     }
-                } else if name.as_ref() == b"ActivitySummary" {
-                    let mut summary_data = HashMap::new();
-                    for attr in e.attributes() {
-                        let attr = attr?;
-                        let key = String::from_utf8_lossy(attr.key.as_ref());
-                        let val = String::from_utf8_lossy(attr.value.as_ref()).to_string();
-
-                        if let Some(config) = manifest.tables.get("activity_summaries") {
-                            for col in &config.columns {
-                                if col.hk_attribute.as_ref() == Some(&key.to_string()) {
-                                    summary_data.insert(col.field_name.clone(), val.clone());
-                                }
+                } else if name.as_ref() == b"ExportDate" {
+                    report.export_date = attribute(&e, "value").map(|v| normalize_date(&v));
+                } else if name.as_ref() == b"Me" {
+                    match read_me_profile(&e) {
+                        Ok(profile) => {
+                            if tx.blocking_send(Parsed::Profile(profile)).is_err() {
+                                return Ok(false);
                             }
                         }
+                        // The profile is left as it was rather than failing the ingestion
+                        Err(reason) => reject(
+                            rejections,
+                            report,
+                            mode.strict,
+                            rejection(position, "Me", &e, reason),
+                        )?,
                     }
-                    if let Some(buffer) = table_buffers.get_mut("activity_summaries") {
-                        buffer.push(DataPoint {
-                            table_name: "activity_summaries".to_string(),
-                            columns: summary_data,
-                        });
+                } else if let Some(tables) = element_tables.get(name.as_ref()) {
+                    match element_rows(&e, tables, manifest, &[]) {
+                        Ok(rows) => {
+                            for dp in rows {
+                                if let Some(buffer) = table_buffers.get_mut(&dp.table_name) {
+                                    buffer.push(dp);
+                                }
+                            }
+                        }
+                        Err(reason) => reject(
+                            rejections,
+                            report,
+                            mode.strict,
+                            rejection(
+                                position,
+                                &String::from_utf8_lossy(name.as_ref()),
+                                &e,
+                                reason,
+                            ),
+                        )?,
                     }
                 }
             }
@@ -91,90 +732,195 @@ pub async fn parse_and_ingest(
                 let name = e.name();
                 if name.as_ref() == b"Record" {
                     // Non-empty Record (has children like MetadataEntry)
-                    if let Some(dp) = extract_record_data(&e, &record_map) {
-                        if let Some(buffer) = table_buffers.get_mut(&dp.table_name) {
-                            buffer.push(dp);
+                    let children =
+                        read_record_children(reader).map_err(|e| malformed(position, e))?;
+                    let beats = if children.beats.is_empty() {
+                        Vec::new()
+                    } else {
+                        let raw_start = e
+                            .try_get_attribute("startDate")
+                            .map_err(|e| malformed(position, e))?
+                            .map(|a| String::from_utf8_lossy(&a.value).to_string())
+                            .unwrap_or_default();
+                        hrv::beats_from_series(&raw_start, &children.beats)
+                    };
+                    match extract_record_data(&e, record_map, &children.metadata, &beats) {
+                        Ok(Some(dp)) => {
+                            if let (Some(table_name), Some(uuid)) =
+                                (&beat_table, dp.columns.get("uuid"))
+                            {
+                                let rows = beat_rows(table_name, uuid, &beats);
+                                if let Some(buffer) = table_buffers.get_mut(table_name) {
+                                    buffer.extend(rows);
+                                }
+                            }
+                            if let Some(buffer) = table_buffers.get_mut(&dp.table_name) {
+                                buffer.push(dp);
+                            }
                         }
+                        Ok(None) => {}
+                        Err(reason) => reject(
+                            rejections,
+                            report,
+                            mode.strict,
+                            rejection(position, "Record", &e, reason),
+                        )?,
                     }
-                    // Skip children for now as they are not mapped in manifest for standard records
-                    reader.read_to_end_into(e.to_end().name(), &mut Vec::new())?;
-                } else if name.as_ref() == b"Workout" {
-                    let mut workout_data = HashMap::new();
-                    let mut start_date_raw = String::new();
-                    let mut end_date_raw = String::new();
-                    let mut creation_date_raw = String::new();
-
-                    for attr in e.attributes() {
-                        let attr = attr?;
-                        let key = String::from_utf8_lossy(attr.key.as_ref());
-                        let val = String::from_utf8_lossy(attr.value.as_ref()).to_string();
-
-                        match key.as_ref() {
-                            "startDate" => start_date_raw = val.clone(),
-                            "endDate" => end_date_raw = val.clone(),
-                            "creationDate" => creation_date_raw = val.clone(),
-                            _ => {}
-                        }
-
-                        for (table_name, config) in &manifest.tables {
-                            if table_name == "workouts" {
-                                for col in &config.columns {
-                                    if col.extraction_source.as_deref() == Some("attribute") {
-                                        if let Some(hk_attr) = &col.hk_attribute {
-                                            if hk_attr == &key {
-                                                workout_data
-                                                    .insert(col.field_name.clone(), val.clone());
-                                            }
-                                        }
-                                    }
+                } else if name.as_ref() == b"Correlation" {
+                    let corr_type = e
+                        .try_get_attribute("type")
+                        .map_err(|e| malformed(position, e))?
+                        .map(|a| String::from_utf8_lossy(&a.value).to_string())
+                        .unwrap_or_default();
+                    // Unmapped correlations fall through: their children are read as plain records
+                    if let Some(table_name) = correlation_map.get(&corr_type) {
+                        let sample =
+                            read_correlation(reader, &e).map_err(|e| malformed(position, e))?;
+                        match build_correlation_row(
+                            sample,
+                            table_name,
+                            &manifest.tables[table_name],
+                        ) {
+                            Ok(Some(dp)) => {
+                                if let Some(buffer) = table_buffers.get_mut(&dp.table_name) {
+                                    buffer.push(dp);
                                 }
                             }
+                            Ok(None) => {}
+                            Err(reason) => reject(
+                                rejections,
+                                report,
+                                mode.strict,
+                                rejection(position, "Correlation", &e, reason),
+                            )?,
                         }
                     }
+                } else if let Some((workout_table, workout_config)) =
+                    workout_table.filter(|_| name.as_ref() == b"Workout")
+                {
+                    let row = element_row(&e, workout_table, workout_config, &[]);
+                    // Statistics and metadata read from the children
+                    let mut workout_data = HashMap::new();
 
-                    workout_data.insert("start_date".to_string(), normalize_date(&start_date_raw));
-                    workout_data.insert("end_date".to_string(), normalize_date(&end_date_raw));
-                    workout_data.insert(
-                        "creation_date".to_string(),
-                        normalize_date(&creation_date_raw),
-                    );
+                    // Events hang off the workout's primary key
+                    let session_id = row
+                        .as_ref()
+                        .ok()
+                        .and_then(|r| r.get(workout_config.primary_key()).cloned());
+                    let mut event_rows = Vec::new();
+                    let mut event_index = 0;
+                    // Statistics and metadata inside a <WorkoutActivity> belong to it, not
+                    // to the workout
+                    let mut activity: Option<PendingActivity> = None;
+                    let mut activities = Vec::new();
+                    let mut activity_index = 0;
 
                     let mut child_buf = Vec::new();
                     loop {
-                        match reader.read_event_into(&mut child_buf) {
+                        let child_position = start.offset + reader.buffer_position();
+                        let event = reader.read_event_into(&mut child_buf);
+                        let has_children = matches!(event, Ok(Event::Start(_)));
+                        let closes_activity = match &event {
+                            Ok(Event::Empty(ce)) => ce.name().as_ref() == b"WorkoutActivity",
+                            Ok(Event::End(ce)) => ce.name().as_ref() == b"WorkoutActivity",
+                            _ => false,
+                        };
+                        match event {
+                            Ok(Event::Empty(ce)) | Ok(Event::Start(ce))
+                                if ce.name().as_ref() == b"WorkoutActivity" =>
+                            {
+                                activity = Some(PendingActivity {
+                                    offset: child_position,
+                                    element: ce.into_owned(),
+                                    columns: HashMap::new(),
+                                });
+                            }
+                            Ok(Event::Empty(ce)) | Ok(Event::Start(ce))
+                                if ce.name().as_ref() == b"WorkoutEvent" =>
+                            {
+                                let metadata = if has_children {
+                                    read_record_children(reader)
+                                        .map_err(|e| malformed(child_position, e))?
+                                        .metadata
+                                } else {
+                                    Vec::new()
+                                };
+                                if let (Some(table_name), Some(session_id)) =
+                                    (&event_table, &session_id)
+                                {
+                                    match workout_event_row(
+                                        table_name,
+                                        session_id,
+                                        event_index,
+                                        &ce,
+                                        &metadata,
+                                    ) {
+                                        Ok(dp) => event_rows.push(dp),
+                                        Err(reason) => reject(
+                                            rejections,
+                                            report,
+                                            mode.strict,
+                                            rejection(child_position, "WorkoutEvent", &ce, reason),
+                                        )?,
+                                    }
+                                }
+                                event_index += 1;
+                            }
                             Ok(Event::Empty(ce)) => {
                                 let cname = ce.name();
                                 if cname.as_ref() == b"WorkoutStatistics" {
-                                    let mut stat_type = String::new();
-                                    let mut stat_sum = String::new();
-                                    for attr in ce.attributes() {
-                                        let attr = attr?;
-                                        match attr.key.as_ref() {
-                                            b"type" => {
-                                                stat_type =
-                                                    String::from_utf8_lossy(&attr.value).to_string()
-                                            }
-                                            b"sum" => {
-                                                stat_sum =
-                                                    String::from_utf8_lossy(&attr.value).to_string()
+                                    let stat_type = attribute(&ce, "type");
+                                    let stat_unit = attribute(&ce, "unit");
+                                    // Each column reads its own aggregate (sum, average,
+                                    // minimum or maximum); one the element lacks is skipped
+                                    let values = workout_config
+                                        .columns
+                                        .iter()
+                                        .filter(|col| col.hk_identifier == stat_type)
+                                        .filter_map(|col| {
+                                            let aggregate = db::statistics_attribute(
+                                                col.extraction_source.as_deref(),
+                                            )?;
+                                            let raw = attribute(&ce, aggregate)?;
+                                            let value = match &col.unit {
+                                                Some(canonical) => convert_to_canonical(
+                                                    &raw,
+                                                    stat_unit.as_ref(),
+                                                    canonical,
+                                                ),
+                                                None => Ok(raw),
                                             }
-                                            _ => {}
-                                        }
-                                    }
-                                    for col in &manifest.tables["workouts"].columns {
-                                        if col.extraction_source.as_deref()
-                                            == Some("statistics_sum")
-                                            && col.hk_identifier.as_ref() == Some(&stat_type)
-                                        {
-                                            workout_data
-                                                .insert(col.field_name.clone(), stat_sum.clone());
+                                            .and_then(|v| check_numeric(v, &col.data_type));
+                                            Some(value.map(|v| (col.field_name.clone(), v)))
+                                        })
+                                        .collect::<Result<Vec<_>, _>>();
+                                    // The workout is kept without the statistic
+                                    match values {
+                                        Ok(values) => {
+                                            let target = match &mut activity {
+                                                Some(a) => &mut a.columns,
+                                                None => &mut workout_data,
+                                            };
+                                            target.extend(values);
                                         }
+                                        Err(reason) => reject(
+                                            rejections,
+                                            report,
+                                            mode.strict,
+                                            rejection(
+                                                child_position,
+                                                "WorkoutStatistics",
+                                                &ce,
+                                                reason,
+                                            ),
+                                        )?,
                                     }
                                 } else if cname.as_ref() == b"MetadataEntry" {
                                     let mut mkey = String::new();
                                     let mut mval = String::new();
                                     for attr in ce.attributes() {
-                                        let attr = attr?;
+                                        let attr =
+                                            attr.map_err(|e| malformed(child_position, e))?;
                                         match attr.key.as_ref() {
                                             b"key" => {
                                                 mkey =
@@ -187,18 +933,22 @@ pub async fn parse_and_ingest(
                                             _ => {}
                                         }
                                     }
-                                    for col in &manifest.tables["workouts"].columns {
+                                    for col in &workout_config.columns {
                                         if col.extraction_source.as_deref()
                                             == Some("metadata_value")
                                             && col.hk_identifier.as_ref() == Some(&mkey)
                                         {
-                                            workout_data
-                                                .insert(col.field_name.clone(), mval.clone());
+                                            let target = match &mut activity {
+                                                Some(a) => &mut a.columns,
+                                                None => &mut workout_data,
+                                            };
+                                            target.insert(col.field_name.clone(), mval.clone());
                                         }
                                     }
                                 } else if cname.as_ref() == b"FileReference" {
                                     for attr in ce.attributes() {
-                                        let attr = attr?;
+                                        let attr =
+                                            attr.map_err(|e| malformed(child_position, e))?;
                                         if attr.key.as_ref() == b"path" {
                                             let path_val =
                                                 String::from_utf8_lossy(&attr.value).to_string();
@@ -207,7 +957,7 @@ pub async fn parse_and_ingest(
                                                 .unwrap_or_default()
                                                 .to_string_lossy()
                                                 .to_string();
-                                            for col in &manifest.tables["workouts"].columns {
+                                            for col in &workout_config.columns {
                                                 if col.extraction_source.as_deref()
                                                     == Some("route_ref")
                                                 {
@@ -224,29 +974,122 @@ pub async fn parse_and_ingest(
                             Ok(Event::Start(ce)) if ce.name().as_ref() == b"WorkoutRoute" => {}
                             Ok(Event::End(ce)) if ce.name().as_ref() == b"Workout" => break,
                             Ok(Event::Eof) => break,
+                            Err(e) => {
+                                return Err(malformed(start.offset + reader.error_position(), e))
+                            }
                             _ => {}
                         }
+                        if closes_activity {
+                            if let Some(pending) = activity.take() {
+                                match workout_activity_row(activity_index, &pending) {
+                                    Ok(columns) => activities.push(columns),
+                                    Err(reason) => reject(
+                                        rejections,
+                                        report,
+                                        mode.strict,
+                                        rejection(
+                                            pending.offset,
+                                            "WorkoutActivity",
+                                            &pending.element,
+                                            reason,
+                                        ),
+                                    )?,
+                                }
+                                activity_index += 1;
+                            }
+                        }
                         child_buf.clear();
                     }
 
-                    if let Some(buffer) = table_buffers.get_mut("workouts") {
-                        buffer.push(DataPoint {
-                            table_name: "workouts".to_string(),
-                            columns: workout_data,
-                        });
+                    // Statistics the workout does not report itself are derived from its
+                    // activities: totals summed, extremes taken over all legs. An average
+                    // cannot be recovered from the legs' averages and stays empty.
+                    for col in &workout_config.columns {
+                        if workout_data.contains_key(&col.field_name) {
+                            continue;
+                        }
+                        let values = activities
+                            .iter()
+                            .filter_map(|a| a.get(&col.field_name)?.parse::<f64>().ok());
+                        let derived = match col.extraction_source.as_deref() {
+                            Some("statistics_sum") => values.reduce(|a, b| a + b),
+                            Some("statistics_min") => values.reduce(f64::min),
+                            Some("statistics_max") => values.reduce(f64::max),
+                            _ => None,
+                        };
+                        if let Some(value) = derived {
+                            workout_data.insert(col.field_name.clone(), units::format_value(value));
+                        }
+                    }
+
+                    match row {
+                        Ok(mut row) => {
+                            row.extend(workout_data);
+                            if let (Some(table_name), Some(session_id)) =
+                                (&activity_table, &session_id)
+                            {
+                                if let Some(buffer) = table_buffers.get_mut(table_name) {
+                                    buffer.extend(activities.into_iter().map(|mut columns| {
+                                        columns
+                                            .insert("session_id".to_string(), session_id.clone());
+                                        DataPoint {
+                                            table_name: table_name.clone(),
+                                            columns,
+                                        }
+                                    }));
+                                }
+                            }
+                            if let Some(buffer) = table_buffers.get_mut(workout_table) {
+                                buffer.push(DataPoint {
+                                    table_name: workout_table.to_string(),
+                                    columns: row,
+                                });
+                            }
+                            if let Some(buffer) =
+                                event_table.as_ref().and_then(|t| table_buffers.get_mut(t))
+                            {
+                                buffer.extend(event_rows);
+                            }
+                        }
+                        Err(reason) => reject(
+                            rejections,
+                            report,
+                            mode.strict,
+                            rejection(position, "Workout", &e, reason),
+                        )?,
+                    }
+                } else if let Some(tables) = element_tables.get(name.as_ref()) {
+                    let children =
+                        read_record_children(reader).map_err(|e| malformed(position, e))?;
+                    match element_rows(&e, tables, manifest, &children.metadata) {
+                        Ok(rows) => {
+                            for dp in rows {
+                                if let Some(buffer) = table_buffers.get_mut(&dp.table_name) {
+                                    buffer.push(dp);
+                                }
+                            }
+                        }
+                        Err(reason) => reject(
+                            rejections,
+                            report,
+                            mode.strict,
+                            rejection(
+                                position,
+                                &String::from_utf8_lossy(name.as_ref()),
+                                &e,
+                                reason,
+                            ),
+                        )?,
                     }
                 }
             }
             Ok(Event::Eof) => break,
-            Err(e) => {
-                error!("Error at position {}: {:?}", reader.buffer_position(), e);
-                break;
-            }
+            Err(e) => return Err(malformed(start.offset + reader.error_position(), e)),
             _ => (),
         }
 
         // Check buffer sizes
-        let mut needs_flush = false;
+        let mut needs_flush = rejections.len() >= batch_size;
         for buffer in table_buffers.values() {
             if buffer.len() >= batch_size {
                 needs_flush = true;
@@ -255,43 +1098,170 @@ pub async fn parse_and_ingest(
         }
 
         if needs_flush {
-            let mut batch_count = 0;
-            for buffer in table_buffers.values() {
-                batch_count += buffer.len();
-            }
-            total_count += batch_count;
-            flush_buffers(&mut table_buffers, pool).await?;
-            info!("Processed {} records...", total_count);
-            if let Some(ref cb) = on_progress {
-                cb(total_count);
+            count_batch(table_buffers, report);
+            let offset = start.offset + reader.buffer_position();
+            if !send_batch(table_buffers, rejections, report, offset, total_bytes, tx) {
+                return Ok(false);
             }
         }
         buf.clear();
     }
+    Ok(true)
+}
+
+// Hands the buffered rows to the writer, blocking while it is PIPELINE_DEPTH batches behind.
+// Returns false once the writer has gone away.
+fn send_batch(
+    table_buffers: &mut HashMap<String, Vec<DataPoint>>,
+    rejections: &mut Vec<Rejection>,
+    report: &IngestReport,
+    offset: u64,
+    total_bytes: Option<u64>,
+    tx: &mpsc::Sender<Parsed>,
+) -> bool {
+    let rows = table_buffers
+        .iter_mut()
+        .filter(|(_, buffer)| !buffer.is_empty())
+        .map(|(table_name, buffer)| {
+            let capacity = buffer.capacity();
+            (
+                table_name.clone(),
+                std::mem::replace(buffer, Vec::with_capacity(capacity)),
+            )
+        })
+        .collect();
+    tx.blocking_send(Parsed::Batch(Box::new(ParsedBatch {
+        rows,
+        offset,
+        total_bytes,
+        counters: report.clone(),
+        rejections: std::mem::take(rejections),
+    })))
+    .is_ok()
+}
 
-    // Final flush
-    let mut final_count = 0;
-    for buffer in table_buffers.values() {
-        final_count += buffer.len();
+// Adds the buffered rows to the report's counters, returns how many there are
+fn count_batch(
+    table_buffers: &HashMap<String, Vec<DataPoint>>,
+    report: &mut IngestReport,
+) -> usize {
+    let mut batch_count = 0;
+    for (table_name, buffer) in table_buffers {
+        if !buffer.is_empty() {
+            *report.tables.entry(table_name.clone()).or_insert(0) += buffer.len();
+            batch_count += buffer.len();
+        }
     }
-    if final_count > 0 {
-        total_count += final_count;
-        flush_buffers(&mut table_buffers, pool).await?;
+    report.processed += batch_count;
+    batch_count
+}
+
+// Quarantines a rejected element, or fails the ingestion in strict mode
+fn reject(
+    rejections: &mut Vec<Rejection>,
+    report: &mut IngestReport,
+    strict: bool,
+    rejection: Rejection,
+) -> anyhow::Result<()> {
+    if strict {
+        anyhow::bail!(
+            "Rejected {} at byte {}: {}",
+            rejection.element,
+            rejection.offset,
+            rejection.reason
+        );
     }
+    debug!(
+        "Quarantined {} at byte {}: {}",
+        rejection.element, rejection.offset, rejection.reason
+    );
+    report.quarantined += 1;
+    // Keyed like db::watermark_types: a workout's children count for the workout
+    let hk_type = match rejection.element.as_str() {
+        "Record" | "Correlation" => rejection.hk_type.clone().unwrap_or_default(),
+        "WorkoutStatistics" | "WorkoutEvent" | "WorkoutActivity" => "Workout".to_string(),
+        element => element.to_string(),
+    };
+    report.quarantined_types.insert(hk_type);
+    rejections.push(rejection);
+    Ok(())
+}
+
+fn rejection(offset: u64, element: &str, e: &BytesStart, reason: String) -> Rejection {
+    Rejection {
+        offset,
+        element: element.to_string(),
+        hk_type: attribute(e, type_attribute(element)),
+        reason,
+        raw: format!("<{}>", String::from_utf8_lossy(e)),
+    }
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .map(|a| String::from_utf8_lossy(&a.value).to_string())
+}
 
-    info!("Finished processing. Total records: {}", total_count);
-    Ok(total_count)
+// Content-based ID of a record, for deduplication. The same sample from two sources is two
+// rows; tables without provenance keep their old IDs.
+pub(crate) fn record_uuid<'a>(
+    table_name: &'a str,
+    field_name: &'a str,
+    start_date: &'a str,
+    end_date: &'a str,
+    value: &'a str,
+    provenance: impl IntoIterator<Item = &'a str>,
+) -> String {
+    content_uuid(
+        [table_name, field_name, start_date, end_date, value]
+            .into_iter()
+            .chain(provenance),
+    )
 }
 
+// Content-based ID of a correlation over its members, given as (column, value)
+pub(crate) fn correlation_uuid<'a>(
+    table_name: &'a str,
+    start_date: &'a str,
+    end_date: &'a str,
+    members: impl IntoIterator<Item = (&'a str, &'a str)>,
+    provenance: impl IntoIterator<Item = &'a str>,
+) -> String {
+    content_uuid(
+        [table_name, start_date, end_date]
+            .into_iter()
+            .chain(
+                members
+                    .into_iter()
+                    .flat_map(|(field, value)| [field, value]),
+            )
+            .chain(provenance),
+    )
+}
+
+fn content_uuid<'a>(parts: impl IntoIterator<Item = &'a str>) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for part in parts {
+        sha2::Digest::update(&mut hasher, part.as_bytes());
+    }
+    format!("{:x}", sha2::Digest::finalize(hasher))
+}
+
+// Ok(None) for unmapped types, Err(reason) for records that cannot be stored faithfully
 fn extract_record_data(
     e: &BytesStart,
-    record_map: &HashMap<String, (String, String)>,
-) -> Option<DataPoint> {
+    record_map: &HashMap<String, RecordTarget>,
+    metadata: &[(String, String)],
+    beats: &[hrv::Beat],
+) -> Result<Option<DataPoint>, String> {
     let mut hk_type = String::new();
     let mut value = String::new();
     let mut creation_date = String::new();
     let mut start_date = String::new();
     let mut end_date = String::new();
+    let mut provenance_values: HashMap<&'static str, String> = HashMap::new();
 
     for attr in e.attributes().flatten() {
         let key = attr.key.as_ref();
@@ -299,80 +1269,745 @@ fn extract_record_data(
         match key {
             b"type" => hk_type = val.to_string(),
             b"value" => value = val.to_string(),
-            b"creationDate" => creation_date = normalize_date(&val),
-            b"startDate" => start_date = normalize_date(&val),
-            b"endDate" => end_date = normalize_date(&val),
-            _ => {}
+            b"creationDate" => creation_date = val.to_string(),
+            b"startDate" => start_date = val.to_string(),
+            b"endDate" => end_date = val.to_string(),
+            _ => {
+                if let Some((col, prov_val)) = provenance_value(&attr) {
+                    provenance_values.insert(col, prov_val);
+                }
+            }
         }
     }
 
-    if let Some((table_name, col_name)) = record_map.get(&hk_type) {
-        // Content-based ID for deduplication
-        let mut hasher = sha2::Sha256::new();
-        sha2::Digest::update(&mut hasher, table_name.as_bytes());
-        sha2::Digest::update(&mut hasher, col_name.as_bytes());
-        sha2::Digest::update(&mut hasher, start_date.as_bytes());
-        sha2::Digest::update(&mut hasher, end_date.as_bytes());
-        sha2::Digest::update(&mut hasher, value.as_bytes());
-        let hash_id = format!("{:x}", sha2::Digest::finalize(hasher));
+    if let Some(target) = record_map.get(&hk_type) {
+        let creation_date = checked_date("creationDate", &creation_date)?;
+        let start_date = checked_date("startDate", &start_date)?;
+        let end_date = checked_date("endDate", &end_date)?;
+        if let Some(canonical) = &target.unit {
+            value = convert_to_canonical(&value, provenance_values.get("unit"), canonical)?;
+        }
+        if !target.categories.is_empty() {
+            value = category_code(&value, &target.categories)?;
+        }
+        let value = check_numeric(value, &target.data_type)?;
+
+        let hash_id = record_uuid(
+            &target.table_name,
+            &target.field_name,
+            &start_date,
+            &end_date,
+            &value,
+            target.provenance.iter().map(|prov_col| {
+                provenance_values
+                    .get(prov_col.as_str())
+                    .map(|v| v.as_str())
+                    .unwrap_or("")
+            }),
+        );
+
+        // Set after hashing so the IDs do not depend on whether the table keeps source_unit
+        if let Some(unit) = provenance_values.get("unit").cloned() {
+            if let Some(canonical) = &target.unit {
+                provenance_values.insert("unit", canonical.clone());
+            }
+            provenance_values.insert("source_unit", unit);
+        }
 
         let mut columns = HashMap::new();
         columns.insert("uuid".to_string(), hash_id);
         columns.insert("creation_date".to_string(), creation_date);
         columns.insert("start_date".to_string(), start_date);
         columns.insert("end_date".to_string(), end_date);
-        columns.insert(col_name.clone(), value);
+        columns.insert(target.field_name.clone(), value);
+        for prov_col in &target.provenance {
+            if let Some(prov_val) = provenance_values.remove(prov_col.as_str()) {
+                columns.insert(prov_col.clone(), prov_val);
+            }
+        }
+        // Metadata stays out of the hash so rows keep their IDs when a column is added
+        for (key, field_name) in &target.metadata {
+            if let Some((_, val)) = metadata.iter().find(|(k, _)| k == key) {
+                columns.insert(field_name.clone(), val.clone());
+            }
+        }
+        if let Some(col) = &target.rmssd_column {
+            if let Some(v) = hrv::rmssd(beats) {
+                columns.insert(col.clone(), units::format_value(v));
+            }
+        }
+        if let Some(col) = &target.pnn50_column {
+            if let Some(v) = hrv::pnn50(beats) {
+                columns.insert(col.clone(), units::format_value(v));
+            }
+        }
 
-        Some(DataPoint {
-            table_name: table_name.clone(),
+        Ok(Some(DataPoint {
+            table_name: target.table_name.clone(),
             columns,
-        })
+        }))
     } else {
-        None
+        Ok(None)
     }
 }
 
-fn normalize_date(input: &str) -> String {
-    match DateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S %z") {
-        Ok(dt) => dt.with_timezone(&Utc).to_rfc3339(),
-        Err(_) => input.to_string(),
+// Consumes a <Correlation> up to its end tag, keeping the child <Record> samples
+fn read_correlation<R: BufRead>(
+    reader: &mut Reader<R>,
+    e: &BytesStart,
+) -> anyhow::Result<CorrelationSample> {
+    let mut sample = CorrelationSample {
+        creation_date: String::new(),
+        start_date: String::new(),
+        end_date: String::new(),
+        provenance: HashMap::new(),
+        metadata: Vec::new(),
+        members: Vec::new(),
+    };
+
+    // Dates stay raw until build_correlation_row, which rejects the unparseable ones
+    for attr in e.attributes() {
+        let attr = attr?;
+        let val = String::from_utf8_lossy(&attr.value);
+        match attr.key.as_ref() {
+            b"creationDate" => sample.creation_date = val.to_string(),
+            b"startDate" => sample.start_date = val.to_string(),
+            b"endDate" => sample.end_date = val.to_string(),
+            _ => {
+                if let Some((col, prov_val)) = provenance_value(&attr) {
+                    sample.provenance.insert(col, prov_val);
+                }
+            }
+        }
+    }
+
+    let mut child_buf = Vec::new();
+    loop {
+        let event = reader.read_event_into(&mut child_buf)?;
+        match &event {
+            Event::Empty(ce) | Event::Start(ce) if ce.name().as_ref() == b"Record" => {
+                let mut member = CorrelationMember {
+                    hk_type: String::new(),
+                    value: String::new(),
+                    unit: None,
+                };
+                for attr in ce.attributes() {
+                    let attr = attr?;
+                    let val = String::from_utf8_lossy(&attr.value).to_string();
+                    match attr.key.as_ref() {
+                        b"type" => member.hk_type = val,
+                        b"value" => member.value = val,
+                        b"unit" => member.unit = Some(val),
+                        _ => {}
+                    }
+                }
+                sample.members.push(member);
+                // Metadata of the individual samples is not kept, only the correlation's own
+                if let Event::Start(_) = event {
+                    reader.read_to_end_into(QName(b"Record"), &mut Vec::new())?;
+                }
+            }
+            Event::Empty(ce) if ce.name().as_ref() == b"MetadataEntry" => {
+                sample.metadata.push(metadata_entry(ce)?);
+            }
+            Event::End(ce) if ce.name().as_ref() == b"Correlation" => break,
+            Event::Eof => break,
+            _ => {}
+        }
+        child_buf.clear();
     }
+
+    Ok(sample)
 }
 
-async fn flush_buffers(
-    table_buffers: &mut HashMap<String, Vec<DataPoint>>,
-    pool: &DbPool,
-) -> anyhow::Result<()> {
-    let mut tx = pool.begin().await?;
+// Ok(None) when none of the children map to a column of the table
+fn build_correlation_row(
+    sample: CorrelationSample,
+    table_name: &str,
+    config: &TableConfig,
+) -> Result<Option<DataPoint>, String> {
+    let creation_date = checked_date("creationDate", &sample.creation_date)?;
+    let start_date = checked_date("startDate", &sample.start_date)?;
+    let end_date = checked_date("endDate", &sample.end_date)?;
+    let mut columns = HashMap::new();
+    let mut members = Vec::new();
+    for col in &config.columns {
+        if col.extraction_source.as_deref() != Some("correlation_member") {
+            continue;
+        }
+        let Some(member) = sample
+            .members
+            .iter()
+            .find(|m| col.hk_identifier.as_ref() == Some(&m.hk_type))
+        else {
+            continue;
+        };
+        let value = match &col.unit {
+            Some(canonical) => convert_to_canonical(&member.value, member.unit.as_ref(), canonical),
+            None => Ok(member.value.clone()),
+        }
+        .and_then(|v| check_numeric(v, &col.data_type))
+        .map_err(|reason| format!("{}: {}", member.hk_type, reason))?;
+        members.push((col.field_name.as_str(), value.clone()));
+        columns.insert(col.field_name.clone(), value);
+    }
+
+    if columns.is_empty() {
+        return Ok(None);
+    }
+
+    for col in &config.columns {
+        if col.extraction_source.as_deref() != Some("metadata_value") {
+            continue;
+        }
+        if let Some((_, val)) = sample
+            .metadata
+            .iter()
+            .find(|(k, _)| col.hk_identifier.as_ref() == Some(k))
+        {
+            columns.insert(col.field_name.clone(), val.clone());
+        }
+    }
+
+    let mut provenance = sample.provenance;
+    let provenance: Vec<String> = config
+        .provenance
+        .iter()
+        .map(|prov_col| provenance.remove(prov_col.as_str()).unwrap_or_default())
+        .collect();
+    let uuid = correlation_uuid(
+        table_name,
+        &start_date,
+        &end_date,
+        members
+            .iter()
+            .map(|(field, value)| (*field, value.as_str())),
+        provenance.iter().map(String::as_str),
+    );
+    for (prov_col, prov_val) in config.provenance.iter().zip(provenance) {
+        if !prov_val.is_empty() {
+            columns.insert(prov_col.clone(), prov_val);
+        }
+    }
 
-    for (table_name, records) in table_buffers.iter_mut() {
-        for record in records.iter() {
-            let mut col_names = Vec::new();
-            let mut placeholders = Vec::new();
-            let mut values = Vec::new();
+    columns.insert("uuid".to_string(), uuid);
+    columns.insert("creation_date".to_string(), creation_date);
+    columns.insert("start_date".to_string(), start_date);
+    columns.insert("end_date".to_string(), end_date);
 
-            for (col, val) in &record.columns {
-                col_names.push(col.clone());
-                placeholders.push("?");
-                values.push(val.clone());
+    Ok(Some(DataPoint {
+        table_name: table_name.to_string(),
+        columns,
+    }))
+}
+
+// Reads the children of a non-empty <Record> up to its end tag, keeping MetadataEntry
+// pairs and the beats of a HeartRateVariabilityMetadataList
+fn read_record_children<R: BufRead>(reader: &mut Reader<R>) -> anyhow::Result<RecordChildren> {
+    let mut children = RecordChildren {
+        metadata: Vec::new(),
+        beats: Vec::new(),
+    };
+    let mut child_buf = Vec::new();
+    let mut depth = 0;
+    loop {
+        match reader.read_event_into(&mut child_buf)? {
+            Event::Empty(ce) if depth == 0 && ce.name().as_ref() == b"MetadataEntry" => {
+                children.metadata.push(metadata_entry(&ce)?);
+            }
+            Event::Empty(ce) if ce.name().as_ref() == b"InstantaneousBeatsPerMinute" => {
+                let mut bpm = String::new();
+                let mut time = String::new();
+                for attr in ce.attributes() {
+                    let attr = attr?;
+                    match attr.key.as_ref() {
+                        b"bpm" => bpm = String::from_utf8_lossy(&attr.value).to_string(),
+                        b"time" => time = String::from_utf8_lossy(&attr.value).to_string(),
+                        _ => {}
+                    }
+                }
+                children.beats.push((bpm, time));
             }
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => break,
+            Event::End(_) => depth -= 1,
+            Event::Eof => break,
+            _ => {}
+        }
+        child_buf.clear();
+    }
+    Ok(children)
+}
 
+fn beat_rows(table_name: &str, record_uuid: &str, beats: &[hrv::Beat]) -> Vec<DataPoint> {
+    beats
+        .iter()
+        .map(|beat| {
+            let mut columns = HashMap::new();
+            columns.insert("record_uuid".to_string(), record_uuid.to_string());
+            columns.insert("beat_index".to_string(), beat.index.to_string());
+            columns.insert("timestamp".to_string(), beat.timestamp.clone());
+            columns.insert("bpm".to_string(), units::format_value(beat.bpm));
+            columns.insert("rr_ms".to_string(), units::format_value(beat.rr_ms));
+            columns.insert(
+                "continuous".to_string(),
+                (beat.continuous as i32).to_string(),
+            );
+            DataPoint {
+                table_name: table_name.to_string(),
+                columns,
+            }
+        })
+        .collect()
+}
+
+// A <WorkoutEvent> as a row of the events table, the type without its HKWorkoutEventType
+// prefix and the duration in minutes. Err(reason) when the date or duration is unusable.
+fn workout_event_row(
+    table_name: &str,
+    session_id: &str,
+    index: usize,
+    e: &BytesStart,
+    metadata: &[(String, String)],
+) -> Result<DataPoint, String> {
+    let raw_type = attribute(e, "type").unwrap_or_default();
+    let event_type = raw_type
+        .strip_prefix("HKWorkoutEventType")
+        .unwrap_or(&raw_type);
+    let date = checked_date("date", &attribute(e, "date").unwrap_or_default())?;
+
+    let mut columns = HashMap::new();
+    columns.insert("session_id".to_string(), session_id.to_string());
+    columns.insert("event_index".to_string(), index.to_string());
+    columns.insert("event_type".to_string(), event_type.to_string());
+    columns.insert("date".to_string(), date);
+    if let Some(duration) = attribute(e, "duration") {
+        let minutes = convert_to_canonical(&duration, attribute(e, "durationUnit").as_ref(), "min")
+            .and_then(|v| check_numeric(v, "REAL"))?;
+        columns.insert("duration_minutes".to_string(), minutes);
+    }
+    if !metadata.is_empty() {
+        let metadata: serde_json::Map<String, serde_json::Value> = metadata
+            .iter()
+            .map(|(k, v)| (k.clone(), serde_json::Value::String(v.clone())))
+            .collect();
+        columns.insert(
+            "metadata".to_string(),
+            serde_json::Value::Object(metadata).to_string(),
+        );
+    }
+    Ok(DataPoint {
+        table_name: table_name.to_string(),
+        columns,
+    })
+}
+
+// One row per table fed from this element, see `element_row`
+fn element_rows(
+    e: &BytesStart,
+    tables: &[&str],
+    manifest: &Manifest,
+    metadata: &[(String, String)],
+) -> Result<Vec<DataPoint>, String> {
+    tables
+        .iter()
+        .map(|table_name| {
+            let columns = element_row(e, table_name, &manifest.tables[*table_name], metadata)?;
+            Ok(DataPoint {
+                table_name: table_name.to_string(),
+                columns,
+            })
+        })
+        .collect()
+}
+
+// The row an element (<Workout>, <ActivitySummary>, <Audiogram>...) gives a table: columns with
+// an `hk_attribute` take that attribute as is, provenance columns and the element's dates are
+// read as for records and metadata_value columns from `metadata`. Tables without a primary key
+// column get a content hash of the attributes as uuid. Err(reason) for an unparseable date.
+fn element_row(
+    e: &BytesStart,
+    table_name: &str,
+    config: &TableConfig,
+    metadata: &[(String, String)],
+) -> Result<HashMap<String, String>, String> {
+    let mut columns = HashMap::new();
+    for attr in e.attributes().flatten() {
+        let key = String::from_utf8_lossy(attr.key.as_ref());
+        let val = String::from_utf8_lossy(&attr.value).to_string();
+        for col in &config.columns {
+            if col.hk_attribute.as_deref() == Some(key.as_ref())
+                && matches!(col.extraction_source.as_deref(), None | Some("attribute"))
+            {
+                columns.insert(col.field_name.clone(), val.clone());
+            }
+        }
+        for prov_col in &config.provenance {
+            if db::provenance_attribute(prov_col) == Some(key.as_ref()) {
+                let unescaped = attr
+                    .unescape_value()
+                    .map(|v| v.to_string())
+                    .unwrap_or(val.clone());
+                columns.insert(prov_col.clone(), normalize_provenance(&key, &unescaped));
+            }
+        }
+    }
+    for (column, name) in [
+        ("start_date", "startDate"),
+        ("end_date", "endDate"),
+        ("creation_date", "creationDate"),
+    ] {
+        if let Some(raw) = attribute(e, name) {
+            columns.insert(column.to_string(), checked_date(name, &raw)?);
+        }
+    }
+
+    if config.primary_key() == "uuid" && !columns.contains_key("uuid") {
+        let mut sorted: Vec<(&String, &String)> = columns.iter().collect();
+        sorted.sort_unstable();
+        let mut hasher = sha2::Sha256::new();
+        sha2::Digest::update(&mut hasher, table_name.as_bytes());
+        for (column, value) in sorted {
+            sha2::Digest::update(&mut hasher, column.as_bytes());
+            sha2::Digest::update(&mut hasher, value.as_bytes());
+        }
+        let hash_id = format!("{:x}", sha2::Digest::finalize(hasher));
+        columns.insert("uuid".to_string(), hash_id);
+    }
+    // Metadata stays out of the hash, as for records
+    for col in &config.columns {
+        if col.extraction_source.as_deref() != Some("metadata_value") {
+            continue;
+        }
+        if let Some((_, val)) = metadata
+            .iter()
+            .find(|(k, _)| col.hk_identifier.as_ref() == Some(k))
+        {
+            columns.insert(col.field_name.clone(), val.clone());
+        }
+    }
+    Ok(columns)
+}
+
+// A <WorkoutActivity> whose children are still being read
+struct PendingActivity {
+    offset: u64,
+    element: BytesStart<'static>,
+    columns: HashMap<String, String>, // statistics and metadata, named like the workout's
+}
+
+// The columns of a multisport leg, without the session_id it is filed under. The type is only
+// present when the export names it. Err(reason) when a date or the duration is unusable.
+fn workout_activity_row(
+    index: usize,
+    activity: &PendingActivity,
+) -> Result<HashMap<String, String>, String> {
+    let e = &activity.element;
+    let mut columns = activity.columns.clone();
+    columns.insert("activity_index".to_string(), index.to_string());
+    for (column, name) in [("uuid", "uuid"), ("activity_type", "workoutActivityType")] {
+        if let Some(value) = attribute(e, name) {
+            columns.insert(column.to_string(), value);
+        }
+    }
+    for (column, name) in [("start_date", "startDate"), ("end_date", "endDate")] {
+        let date = checked_date(name, &attribute(e, name).unwrap_or_default())?;
+        columns.insert(column.to_string(), date);
+    }
+    if let Some(duration) = attribute(e, "duration") {
+        let minutes = convert_to_canonical(&duration, attribute(e, "durationUnit").as_ref(), "min")
+            .and_then(|v| check_numeric(v, "REAL"))?;
+        columns.insert("duration_minutes".to_string(), minutes);
+    }
+    Ok(columns)
+}
+
+fn read_me_profile(e: &BytesStart) -> Result<db::MeProfile, String> {
+    let mut profile = db::MeProfile::default();
+    for attr in e.attributes() {
+        let attr = attr.map_err(|e| format!("malformed attribute: {}", e))?;
+        let val = attr.unescape_value().map_err(|e| {
+            format!(
+                "malformed {} value: {}",
+                String::from_utf8_lossy(attr.key.as_ref()),
+                e
+            )
+        })?;
+        match attr.key.as_ref() {
+            b"HKCharacteristicTypeIdentifierDateOfBirth" => {
+                profile.date_of_birth = Some(val.to_string()).filter(|v| !v.is_empty())
+            }
+            b"HKCharacteristicTypeIdentifierBiologicalSex" => {
+                profile.biological_sex = characteristic(&val, "HKBiologicalSex")
+            }
+            b"HKCharacteristicTypeIdentifierBloodType" => {
+                profile.blood_type = characteristic(&val, "HKBloodType")
+            }
+            b"HKCharacteristicTypeIdentifierFitzpatrickSkinType" => {
+                profile.skin_type = characteristic(&val, "HKFitzpatrickSkinType")
+            }
+            _ => {}
+        }
+    }
+    Ok(profile)
+}
+
+// "HKBloodTypeAPositive" -> "APositive"; unset characteristics are stored as NULL
+fn characteristic(raw: &str, prefix: &str) -> Option<String> {
+    let value = raw.strip_prefix(prefix).unwrap_or(raw);
+    (!value.is_empty() && value != "NotSet").then(|| value.to_string())
+}
+
+fn metadata_entry(e: &BytesStart) -> anyhow::Result<(String, String)> {
+    let mut key = String::new();
+    let mut value = String::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        match attr.key.as_ref() {
+            b"key" => key = attr.unescape_value()?.to_string(),
+            b"value" => value = attr.unescape_value()?.to_string(),
+            _ => {}
+        }
+    }
+    Ok((key, value))
+}
+
+// Provenance column and normalized value for a Record/Correlation attribute, if it is one
+fn provenance_value(attr: &Attribute) -> Option<(&'static str, String)> {
+    let (col, attr_name) = db::PROVENANCE_ATTRIBUTES
+        .iter()
+        .find(|(_, attr_name)| attr_name.as_bytes() == attr.key.as_ref())?;
+    let raw = attr
+        .unescape_value()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| String::from_utf8_lossy(&attr.value).to_string());
+    Some((col, normalize_provenance(attr_name, &raw)))
+}
+
+// Codes are accepted as-is so exports that already carry the raw enum value still load
+fn category_code(value: &str, categories: &HashMap<String, i64>) -> Result<String, String> {
+    if let Some(code) = categories.get(value) {
+        return Ok(code.to_string());
+    }
+    match value.parse::<i64>() {
+        Ok(code) if categories.values().any(|c| *c == code) => Ok(code.to_string()),
+        _ => Err(format!("unknown category value '{}'", value)),
+    }
+}
+
+// Values already in the canonical unit (or carrying no unit) are stored verbatim
+fn convert_to_canonical(
+    value: &str,
+    unit: Option<&String>,
+    canonical: &str,
+) -> Result<String, String> {
+    let unit = match unit {
+        Some(u) if u != canonical => u,
+        _ => return Ok(value.to_string()),
+    };
+    let numeric = value
+        .parse::<f64>()
+        .map_err(|_| format!("non-numeric value '{}' in unit {}", value, unit))?;
+    units::convert(numeric, unit, canonical)
+        .map(units::format_value)
+        .ok_or_else(|| format!("cannot convert unit '{}' to '{}'", unit, canonical))
+}
+
+// HKDevice descriptions start with an object address ("<<HKDevice: 0x283e5e260>, name:...")
+// that changes between exports; strip it so the same device hashes identically.
+fn normalize_provenance(attr_name: &str, raw: &str) -> String {
+    if attr_name != "device" {
+        return raw.to_string();
+    }
+    let trimmed = match raw.strip_prefix("<<HKDevice:") {
+        Some(rest) => rest
+            .split_once(">, ")
+            .map(|(_, fields)| fields)
+            .unwrap_or(rest),
+        None => raw,
+    };
+    trimmed.trim_end_matches('>').trim().to_string()
+}
+
+// REAL and INTEGER columns only take numbers; SQLite would otherwise keep the text as is
+fn check_numeric(value: String, data_type: &str) -> Result<String, String> {
+    let numeric = ["REAL", "INTEGER"]
+        .iter()
+        .any(|t| data_type.trim().eq_ignore_ascii_case(t));
+    if numeric && !value.parse::<f64>().is_ok_and(f64::is_finite) {
+        return Err(format!(
+            "non-numeric value '{}' for a {} column",
+            value,
+            data_type.trim()
+        ));
+    }
+    Ok(value)
+}
+
+// Missing dates are left empty, present ones must parse
+fn checked_date(attribute: &str, input: &str) -> Result<String, String> {
+    if input.is_empty() {
+        return Ok(String::new());
+    }
+    parse_date(input).ok_or_else(|| format!("unparseable {} '{}'", attribute, input))
+}
+
+fn parse_date(input: &str) -> Option<String> {
+    DateTime::parse_from_str(input, "%Y-%m-%d %H:%M:%S %z")
+        .ok()
+        .map(|dt| dt.with_timezone(&Utc).to_rfc3339())
+}
+
+// Lenient: anything that does not parse is passed through unchanged
+pub(crate) fn normalize_date(input: &str) -> String {
+    parse_date(input).unwrap_or_else(|| input.to_string())
+}
+
+// SQLite binds at most 32766 parameters per statement
+const MAX_INSERT_PARAMS: usize = 32766;
+const MAX_INSERT_ROWS: usize = 500;
+
+// Returns the number of rows actually inserted (duplicates are ignored). The job's
+// checkpoint, if any, is committed together with the rows; its `inserted` count is
+// taken before this batch.
+// Newest start_date per (table, type) among the rows, types told apart as db::watermark_types
+// does. Rows that were already stored count too: the database holds them either way.
+fn note_newest_starts(
+    manifest: &Manifest,
+    rows: &HashMap<String, Vec<DataPoint>>,
+    newest: &mut HashMap<(String, String), String>,
+) {
+    for (table_name, records) in rows {
+        let Some(config) = manifest.tables.get(table_name) else {
+            continue;
+        };
+        let types = db::watermark_types(config);
+        for record in records {
+            let Some(start) = record.columns.get("start_date") else {
+                continue;
+            };
+            for (hk_type, column) in &types {
+                if column.is_some_and(|c| !record.columns.contains_key(c)) {
+                    continue;
+                }
+                let entry = newest
+                    .entry((table_name.clone(), hk_type.clone()))
+                    .or_default();
+                if start > entry {
+                    entry.clone_from(start);
+                }
+            }
+        }
+    }
+}
+
+// Incremental mode: takes out the rows whose key their table already holds. Only the key
+// proves a row was imported; a sample synced late can be older than anything stored.
+// Workout events, activities and beats are keyed by their parent and always go through.
+async fn skip_stored_rows(
+    pool: &DbPool,
+    manifest: &Manifest,
+    rows: &mut HashMap<String, Vec<DataPoint>>,
+) -> anyhow::Result<usize> {
+    let mut skipped = 0;
+    for (table_name, records) in rows.iter_mut() {
+        let Some(config) = manifest.tables.get(table_name) else {
+            continue;
+        };
+        let key = config.primary_key();
+        let mut stored = HashSet::new();
+        let keys: Vec<&str> = records
+            .iter()
+            .filter_map(|r| r.columns.get(key).map(String::as_str))
+            .collect();
+        for chunk in keys.chunks(MAX_INSERT_ROWS) {
             let query = format!(
-                "INSERT OR IGNORE INTO {} ({}) VALUES ({})",
+                "SELECT CAST({} AS TEXT) FROM {} WHERE {} IN ({})",
+                key,
                 table_name,
-                col_names.join(", "),
-                placeholders.join(", ")
+                key,
+                vec!["?"; chunk.len()].join(", ")
             );
+            let mut q = sqlx::query_scalar::<_, String>(&query);
+            for k in chunk {
+                q = q.bind(*k);
+            }
+            stored.extend(q.fetch_all(pool).await?);
+        }
+        let before = records.len();
+        records.retain(|r| r.columns.get(key).is_none_or(|k| !stored.contains(k)));
+        skipped += before - records.len();
+    }
+    Ok(skipped)
+}
+
+async fn flush_buffers(
+    table_buffers: HashMap<String, Vec<DataPoint>>,
+    rejections: &[Rejection],
+    pool: &DbPool,
+    source_file: &str,
+    checkpoint: Option<(&str, Checkpoint)>,
+) -> anyhow::Result<usize> {
+    let mut tx = pool.begin().await?;
+    let mut inserted = 0;
+
+    let job_id = checkpoint.as_ref().map(|(id, _)| *id);
+    quarantine::store(&mut tx, job_id, source_file, rejections).await?;
+
+    for (table_name, records) in &table_buffers {
+        // Rowids the job added to this table
+        let mut row_ids = Vec::new();
+        // Rows with the same columns share one multi-row statement
+        let mut groups: HashMap<Vec<&str>, Vec<&DataPoint>> = HashMap::new();
+        for record in records {
+            let mut columns: Vec<&str> = record.columns.keys().map(String::as_str).collect();
+            columns.sort_unstable();
+            groups.entry(columns).or_default().push(record);
+        }
 
-            let mut q = sqlx::query(&query);
-            for val in values {
-                q = q.bind(val);
+        for (columns, rows) in groups {
+            let per_statement = (MAX_INSERT_PARAMS / columns.len()).clamp(1, MAX_INSERT_ROWS);
+            let row_placeholders = format!("({})", vec!["?"; columns.len()].join(", "));
+            for chunk in rows.chunks(per_statement) {
+                // Identical SQL for every full chunk, so the prepared statement is reused
+                let mut query = format!(
+                    "INSERT OR IGNORE INTO {} ({}) VALUES {}",
+                    table_name,
+                    columns.join(", "),
+                    vec![row_placeholders.as_str(); chunk.len()].join(", ")
+                );
+                if checkpoint.is_some() {
+                    query.push_str(" RETURNING rowid");
+                }
+
+                let mut q = sqlx::query(&query);
+                for row in chunk {
+                    for column in &columns {
+                        q = q.bind(row.columns[*column].as_str());
+                    }
+                }
+                match &checkpoint {
+                    // Remember which rows the job added, so a cancelled job can take them back
+                    Some(_) => {
+                        for row in q.fetch_all(&mut *tx).await? {
+                            row_ids.push(sqlx::Row::get::<i64, _>(&row, 0));
+                            inserted += 1;
+                        }
+                    }
+                    None => inserted += q.execute(&mut *tx).await?.rows_affected() as usize,
+                }
             }
-            q.execute(&mut *tx).await?;
         }
-        records.clear();
+        if let Some((job_id, _)) = checkpoint.as_ref().filter(|_| !row_ids.is_empty()) {
+            jobs::journal_row_ids(&mut tx, job_id, table_name, &row_ids).await?;
+        }
+    }
+
+    if let Some((job_id, mut checkpoint)) = checkpoint {
+        checkpoint.inserted += inserted;
+        jobs::save_checkpoint(&mut tx, job_id, &checkpoint).await?;
     }
 
     tx.commit().await?;
-    Ok(())
+    Ok(inserted)
 }
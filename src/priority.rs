@@ -0,0 +1,113 @@
+use chrono::DateTime;
+
+// A sample covering [start, end) in unix seconds, attributed to one source
+pub struct SourcedInterval {
+    pub start: i64,
+    pub end: i64,
+    pub source: String,
+    pub value: f64,
+}
+
+// A stretch of time owned by a single interval after overlaps have been resolved
+pub struct ResolvedSlice {
+    pub start: i64,
+    pub end: i64,
+    pub winner: usize, // index into the input intervals
+}
+
+impl ResolvedSlice {
+    // Share of the winning interval's value that falls inside this slice
+    pub fn prorated_value(&self, intervals: &[SourcedInterval]) -> f64 {
+        let iv = &intervals[self.winner];
+        let duration = iv.end - iv.start;
+        if duration <= 0 {
+            iv.value
+        } else {
+            iv.value * (self.end - self.start) as f64 / duration as f64
+        }
+    }
+}
+
+pub fn parse_timestamp(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|dt| dt.timestamp())
+}
+
+// Lower is better. Patterns match source names case-insensitively as substrings, so
+// "Watch" covers "Jane's Apple Watch". Unlisted sources rank after every listed one.
+pub fn source_rank(priority: &[String], source: &str) -> usize {
+    let source = source.to_lowercase();
+    priority
+        .iter()
+        .position(|p| source.contains(&p.to_lowercase()))
+        .unwrap_or(priority.len())
+}
+
+// Splits the timeline at every interval boundary and keeps exactly one interval per
+// slice: the best ranked source, then the earliest starting sample. Instantaneous
+// samples (start == end) survive unless a better ranked interval covers them.
+pub fn resolve_overlaps(intervals: &[SourcedInterval], priority: &[String]) -> Vec<ResolvedSlice> {
+    let ranks: Vec<usize> = intervals
+        .iter()
+        .map(|iv| source_rank(priority, &iv.source))
+        .collect();
+
+    let mut order: Vec<usize> = (0..intervals.len()).collect();
+    order.sort_by_key(|&i| (intervals[i].start, intervals[i].end));
+
+    let mut boundaries: Vec<i64> = intervals.iter().flat_map(|iv| [iv.start, iv.end]).collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut slices = Vec::new();
+    let mut active: Vec<usize> = Vec::new();
+    let mut next = 0;
+
+    for (idx, &point) in boundaries.iter().enumerate() {
+        while next < order.len() && intervals[order[next]].start <= point {
+            active.push(order[next]);
+            next += 1;
+        }
+        active.retain(|&i| intervals[i].end >= point);
+
+        let best_covering = |from: i64, to: i64| {
+            active
+                .iter()
+                .copied()
+                .filter(|&i| intervals[i].start <= from && intervals[i].end >= to)
+                .filter(|&i| intervals[i].end > intervals[i].start)
+                .min_by_key(|&i| (ranks[i], intervals[i].start, i))
+        };
+
+        // Instantaneous samples at this point
+        for &i in &active {
+            let iv = &intervals[i];
+            if iv.start == point && iv.end == point {
+                let shadowed = best_covering(point, point)
+                    .map(|w| ranks[w] < ranks[i])
+                    .unwrap_or(false);
+                if !shadowed {
+                    slices.push(ResolvedSlice {
+                        start: point,
+                        end: point,
+                        winner: i,
+                    });
+                }
+            }
+        }
+
+        // The stretch up to the next boundary
+        if let Some(&slice_end) = boundaries.get(idx + 1) {
+            if let Some(winner) = best_covering(point, slice_end) {
+                slices.push(ResolvedSlice {
+                    start: point,
+                    end: slice_end,
+                    winner,
+                });
+            }
+        }
+    }
+
+    slices
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::{Row, Sqlite, Transaction};
+
+use crate::db::DbPool;
+
+// An element the parser could not store faithfully, kept instead of being dropped
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    pub offset: u64, // byte position in export.xml at (or in the whitespace just before) the element
+    pub element: String,
+    pub hk_type: Option<String>,
+    pub reason: String,
+    pub raw: String, // the element's start tag
+}
+
+// A row of the quarantine table as exposed by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedRecord {
+    pub id: i64,
+    pub job_id: Option<String>,
+    pub source_file: String,
+    pub byte_offset: i64,
+    pub element: String,
+    pub hk_type: Option<String>,
+    pub reason: String,
+    pub raw: String,
+    pub created_at: String,
+}
+
+// How many records of one element and type were quarantined
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantineCount {
+    pub element: String,
+    pub hk_type: Option<String>,
+    pub count: i64,
+}
+
+pub(crate) async fn ensure_quarantine_schema(pool: &DbPool) -> Result<()> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS quarantine (id INTEGER PRIMARY KEY AUTOINCREMENT, job_id TEXT, source_file TEXT, byte_offset INTEGER, element TEXT, hk_type TEXT, reason TEXT, raw TEXT, created_at TEXT)",
+    )
+    .execute(pool)
+    .await
+    .context("Failed to create quarantine table")?;
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_quarantine_job_id ON quarantine (job_id)")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Written in the batch's transaction, so a resumed job never quarantines an element twice
+pub(crate) async fn store(
+    tx: &mut Transaction<'_, Sqlite>,
+    job_id: Option<&str>,
+    source_file: &str,
+    rejections: &[Rejection],
+) -> Result<()> {
+    let created_at = Utc::now().to_rfc3339();
+    for chunk in rejections.chunks(500) {
+        let sql = format!(
+            "INSERT INTO quarantine (job_id, source_file, byte_offset, element, hk_type, reason, raw, created_at) VALUES {}",
+            vec!["(?, ?, ?, ?, ?, ?, ?, ?)"; chunk.len()].join(", ")
+        );
+        let mut q = sqlx::query(&sql);
+        for r in chunk {
+            q = q
+                .bind(job_id)
+                .bind(source_file)
+                .bind(r.offset as i64)
+                .bind(&r.element)
+                .bind(&r.hk_type)
+                .bind(&r.reason)
+                .bind(&r.raw)
+                .bind(&created_at);
+        }
+        q.execute(&mut **tx)
+            .await
+            .context("Failed to quarantine rejected records")?;
+    }
+    Ok(())
+}
+
+// Oldest first (document order), with the total for pagination
+pub async fn list_quarantine(
+    pool: &DbPool,
+    job_id: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<QuarantinedRecord>, i64)> {
+    let (total,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM quarantine WHERE job_id = ?")
+        .bind(job_id)
+        .fetch_one(pool)
+        .await
+        .context("Failed to count quarantined records")?;
+
+    let rows = sqlx::query(
+        "SELECT * FROM quarantine WHERE job_id = ? ORDER BY byte_offset ASC, id ASC LIMIT ? OFFSET ?",
+    )
+    .bind(job_id)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+    .context("Failed to list quarantined records")?;
+
+    let records = rows
+        .iter()
+        .map(|row| QuarantinedRecord {
+            id: row.get("id"),
+            job_id: row.get("job_id"),
+            source_file: row.get("source_file"),
+            byte_offset: row.get("byte_offset"),
+            element: row.get("element"),
+            hk_type: row.get("hk_type"),
+            reason: row.get("reason"),
+            raw: row.get("raw"),
+            created_at: row.get("created_at"),
+        })
+        .collect();
+    Ok((records, total))
+}
+
+// Most frequent first
+pub async fn quarantine_counts(pool: &DbPool, job_id: &str) -> Result<Vec<QuarantineCount>> {
+    let rows: Vec<(String, Option<String>, i64)> = sqlx::query_as(
+        "SELECT element, hk_type, COUNT(*) AS count FROM quarantine WHERE job_id = ? GROUP BY element, hk_type ORDER BY count DESC, element, hk_type",
+    )
+    .bind(job_id)
+    .fetch_all(pool)
+    .await
+    .context("Failed to count quarantined records")?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(element, hk_type, count)| QuarantineCount {
+            element,
+            hk_type,
+            count,
+        })
+        .collect())
+}
+
+pub(crate) async fn discard(tx: &mut Transaction<'_, Sqlite>, job_id: &str) -> Result<()> {
+    sqlx::query("DELETE FROM quarantine WHERE job_id = ?")
+        .bind(job_id)
+        .execute(&mut **tx)
+        .await
+        .with_context(|| format!("Failed to discard quarantine of job {}", job_id))?;
+    Ok(())
+}
@@ -0,0 +1,199 @@
+use crate::db::Manifest;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    Mass,
+    Length,
+    Energy,
+    Temperature,
+    BloodGlucose,
+    Volume,
+    Speed,
+    Time,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    Metric,
+    Imperial,
+}
+
+#[derive(Clone, Copy)]
+struct UnitDef {
+    symbol: &'static str,
+    dimension: Dimension,
+    // base = value * factor + offset
+    factor: f64,
+    offset: f64,
+    metric: &'static str,
+    imperial: &'static str,
+}
+
+const fn unit(
+    symbol: &'static str,
+    dimension: Dimension,
+    factor: f64,
+    metric: &'static str,
+    imperial: &'static str,
+) -> UnitDef {
+    UnitDef {
+        symbol,
+        dimension,
+        factor,
+        offset: 0.0,
+        metric,
+        imperial,
+    }
+}
+
+// Symbols as they appear in the export's `unit` attribute. Base units: kg, m, kcal,
+// degC, mg/dL, mL, m/s, s. `metric`/`imperial` name the display counterpart.
+const UNITS: &[UnitDef] = &[
+    unit("kg", Dimension::Mass, 1.0, "kg", "lb"),
+    unit("g", Dimension::Mass, 1e-3, "g", "oz"),
+    unit("mg", Dimension::Mass, 1e-6, "mg", "mg"),
+    unit("mcg", Dimension::Mass, 1e-9, "mcg", "mcg"),
+    unit("lb", Dimension::Mass, 0.453_592_37, "kg", "lb"),
+    unit("oz", Dimension::Mass, 0.028_349_523_125, "g", "oz"),
+    unit("st", Dimension::Mass, 6.350_293_18, "kg", "st"),
+    unit("m", Dimension::Length, 1.0, "m", "ft"),
+    unit("cm", Dimension::Length, 0.01, "cm", "in"),
+    unit("mm", Dimension::Length, 0.001, "mm", "in"),
+    unit("km", Dimension::Length, 1000.0, "km", "mi"),
+    unit("in", Dimension::Length, 0.0254, "cm", "in"),
+    unit("ft", Dimension::Length, 0.3048, "m", "ft"),
+    unit("yd", Dimension::Length, 0.9144, "m", "yd"),
+    unit("mi", Dimension::Length, 1609.344, "km", "mi"),
+    unit("kcal", Dimension::Energy, 1.0, "kcal", "kcal"),
+    unit("Cal", Dimension::Energy, 1.0, "kcal", "kcal"),
+    unit("cal", Dimension::Energy, 1e-3, "cal", "cal"),
+    unit("kJ", Dimension::Energy, 1.0 / 4.184, "kJ", "kcal"),
+    unit("J", Dimension::Energy, 1.0 / 4184.0, "J", "kcal"),
+    unit("degC", Dimension::Temperature, 1.0, "degC", "degF"),
+    UnitDef {
+        symbol: "degF",
+        dimension: Dimension::Temperature,
+        factor: 5.0 / 9.0,
+        offset: -32.0 * 5.0 / 9.0,
+        metric: "degC",
+        imperial: "degF",
+    },
+    UnitDef {
+        symbol: "K",
+        dimension: Dimension::Temperature,
+        factor: 1.0,
+        offset: -273.15,
+        metric: "degC",
+        imperial: "degF",
+    },
+    unit("mg/dL", Dimension::BloodGlucose, 1.0, "mg/dL", "mg/dL"),
+    unit("mL", Dimension::Volume, 1.0, "mL", "fl_oz_us"),
+    unit("L", Dimension::Volume, 1000.0, "L", "fl_oz_us"),
+    unit(
+        "fl_oz_us",
+        Dimension::Volume,
+        29.573_529_562_5,
+        "mL",
+        "fl_oz_us",
+    ),
+    unit("cup_us", Dimension::Volume, 236.588_236_5, "mL", "cup_us"),
+    unit("m/s", Dimension::Speed, 1.0, "m/s", "mi/hr"),
+    unit("km/hr", Dimension::Speed, 1.0 / 3.6, "km/hr", "mi/hr"),
+    unit("mi/hr", Dimension::Speed, 0.447_04, "km/hr", "mi/hr"),
+    unit("ms", Dimension::Time, 1e-3, "ms", "ms"),
+    unit("s", Dimension::Time, 1.0, "s", "s"),
+    unit("min", Dimension::Time, 60.0, "min", "min"),
+    unit("hr", Dimension::Time, 3600.0, "hr", "hr"),
+    unit("d", Dimension::Time, 86400.0, "d", "d"),
+];
+
+fn lookup(symbol: &str) -> Option<UnitDef> {
+    if let Some(def) = UNITS.iter().find(|u| u.symbol == symbol) {
+        return Some(*def);
+    }
+    // Molar glucose units carry the molar mass: "mmol<180.1558800000541>/L"
+    let molar_mass = symbol
+        .strip_prefix("mmol<")
+        .and_then(|rest| rest.strip_suffix(">/L"))
+        .and_then(|m| m.parse::<f64>().ok())?;
+    Some(UnitDef {
+        symbol: "mmol/L",
+        dimension: Dimension::BloodGlucose,
+        factor: molar_mass / 10.0,
+        offset: 0.0,
+        metric: "mmol/L",
+        imperial: "mg/dL",
+    })
+}
+
+// True if `from` can be expressed in `to`: identical symbols always are, even when the
+// unit is not in the table (count/min, %, dBASPL...).
+pub fn is_convertible(from: &str, to: &str) -> bool {
+    from == to
+        || matches!((lookup(from), lookup(to)), (Some(f), Some(t)) if f.dimension == t.dimension)
+}
+
+pub fn convert(value: f64, from: &str, to: &str) -> Option<f64> {
+    if from == to {
+        return Some(value);
+    }
+    let (f, t) = (lookup(from)?, lookup(to)?);
+    if f.dimension != t.dimension {
+        return None;
+    }
+    let base = value * f.factor + f.offset;
+    Some((base - t.offset) / t.factor)
+}
+
+// Display counterpart of a canonical unit in the requested system; unknown units stay as-is.
+pub fn display_unit(canonical: &str, system: UnitSystem) -> String {
+    match (lookup(canonical), system) {
+        (Some(def), UnitSystem::Metric) => def.metric.to_string(),
+        (Some(def), UnitSystem::Imperial) => def.imperial.to_string(),
+        (None, _) => canonical.to_string(),
+    }
+}
+
+// Stable text form for converted values: rounded to absorb float noise, no trailing zeros
+pub fn format_value(value: f64) -> String {
+    let rounded = (value * 1e6).round() / 1e6;
+    format!("{}", rounded)
+}
+
+// Rewrites unit-bearing columns of query results into the requested unit system.
+// `count` aggregates are skipped since they are not measurements.
+pub fn convert_rows_for_display(
+    manifest: &Manifest,
+    table_name: &str,
+    rows: &mut [Value],
+    system: UnitSystem,
+    aggregated: bool,
+) {
+    let Some(table_config) = manifest.tables.get(table_name) else {
+        return;
+    };
+
+    for col in &table_config.columns {
+        let Some(canonical) = &col.unit else {
+            continue;
+        };
+        if aggregated && col.aggregate == "count" {
+            continue;
+        }
+        let target = display_unit(canonical, system);
+        if &target == canonical {
+            continue;
+        }
+
+        for row in rows.iter_mut() {
+            if let Some(v) = row.get(&col.field_name).and_then(|v| v.as_f64()) {
+                if let Some(converted) = convert(v, canonical, &target) {
+                    row[&col.field_name] = json!(converted);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::{bail, Context, Result};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::db::Manifest;
+
+const DEFAULT_UPLOAD_DIR: &str = "uploads";
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+// What an uploaded file is, decided by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadKind {
+    Export, // export.xml or export.zip, fed to the parser
+    Ecg,    // electrocardiogram CSV
+    Route,  // workout route GPX
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredUpload {
+    pub id: String,
+    pub file_name: String, // the client's name, stripped of any directories
+    pub kind: UploadKind,
+    pub size: u64,
+    pub sha256: String,
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+pub fn upload_dir(manifest: &Manifest) -> PathBuf {
+    PathBuf::from(
+        manifest
+            .settings
+            .as_ref()
+            .and_then(|s| s.upload_dir.clone())
+            .unwrap_or_else(|| DEFAULT_UPLOAD_DIR.to_string()),
+    )
+}
+
+// Directories holding unpacked exports, see `import_dirs` in the manifest
+pub fn import_dirs(manifest: &Manifest) -> Vec<PathBuf> {
+    manifest
+        .settings
+        .as_ref()
+        .and_then(|s| s.import_dirs.as_ref())
+        .map(|dirs| dirs.iter().map(PathBuf::from).collect())
+        .unwrap_or_default()
+}
+
+// Resolves a server-side path given to /ingest or /ingest/dry-run. Only files inside the
+// upload directory or one of the manifest's `import_dirs` may be read; the path is
+// canonicalized first so `..` and symlinks cannot step outside them.
+pub fn allowed_input_path(manifest: &Manifest, file_path: &str) -> Result<PathBuf> {
+    let path = std::fs::canonicalize(file_path)
+        .with_context(|| format!("File not found: {}", file_path))?;
+    let allowed = std::iter::once(upload_dir(manifest))
+        .chain(import_dirs(manifest))
+        .filter_map(|dir| std::fs::canonicalize(dir).ok())
+        .any(|dir| path.starts_with(dir));
+    if !allowed {
+        bail!(
+            "{} is outside the upload directory and the configured import_dirs",
+            file_path
+        );
+    }
+    Ok(path)
+}
+
+// Deletes a file once it has been imported, if it is one stored by the upload endpoint.
+// Anything else given by path is left alone.
+pub async fn discard_upload(manifest: &Manifest, path: &Path) {
+    let uploaded = match (
+        fs::canonicalize(upload_dir(manifest)).await,
+        fs::canonicalize(path).await,
+    ) {
+        (Ok(dir), Ok(path)) => path.starts_with(dir) && path.is_file(),
+        _ => false,
+    };
+    if !uploaded {
+        return;
+    }
+    match fs::remove_file(path).await {
+        Ok(()) => info!("Removed imported upload {:?}", path),
+        Err(e) => warn!("Failed to remove imported upload {:?}: {}", path, e),
+    }
+}
+
+pub fn max_upload_bytes(manifest: &Manifest) -> u64 {
+    manifest
+        .settings
+        .as_ref()
+        .and_then(|s| s.max_upload_bytes)
+        .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES)
+}
+
+// Validates a client supplied file name. Only the last path component is kept, so a
+// name can never point outside the upload directory.
+pub fn classify(file_name: &str) -> Result<(String, UploadKind)> {
+    let name = Path::new(file_name)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| !n.starts_with('.'))
+        .with_context(|| format!("Invalid file name {:?}", file_name))?;
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    let kind = match extension.as_deref() {
+        Some("xml") | Some("zip") => UploadKind::Export,
+        Some("csv") => UploadKind::Ecg,
+        Some("gpx") => UploadKind::Route,
+        _ => bail!(
+            "Unsupported file type {:?}, expected xml, zip, csv or gpx",
+            name
+        ),
+    };
+    Ok((name.to_string(), kind))
+}
+
+// Spools a body stream into the upload directory chunk by chunk, hashing as it goes.
+// The file only appears under its final name once it is complete, within `max_bytes`
+// and matches `expected_sha256` (hex) when one is given.
+pub async fn store_upload<S, B, E>(
+    dir: &Path,
+    file_name: &str,
+    mut body: S,
+    max_bytes: u64,
+    expected_sha256: Option<&str>,
+) -> Result<StoredUpload>
+where
+    S: Stream<Item = std::result::Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let (file_name, kind) = classify(file_name)?;
+    fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create upload directory {:?}", dir))?;
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let extension = Path::new(&file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let path = dir.join(format!("{}.{}", id, extension));
+    let partial = dir.join(format!("{}.{}.part", id, extension));
+
+    let spooled = spool(&partial, &mut body, max_bytes).await;
+    let (size, sha256) = match spooled {
+        Ok(done) => done,
+        Err(e) => {
+            let _ = fs::remove_file(&partial).await;
+            return Err(e);
+        }
+    };
+    if let Some(expected) = expected_sha256 {
+        if !expected.eq_ignore_ascii_case(&sha256) {
+            let _ = fs::remove_file(&partial).await;
+            bail!(
+                "Checksum mismatch for {}: expected {}, received {}",
+                file_name,
+                expected,
+                sha256
+            );
+        }
+    }
+    fs::rename(&partial, &path).await?;
+
+    info!(
+        "Stored upload {} ({}, {} bytes) as {:?}",
+        file_name, sha256, size, path
+    );
+    Ok(StoredUpload {
+        id,
+        file_name,
+        kind,
+        size,
+        sha256,
+        path,
+    })
+}
+
+async fn spool<S, B, E>(path: &Path, body: &mut S, max_bytes: u64) -> Result<(u64, String)>
+where
+    S: Stream<Item = std::result::Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut file = fs::File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Upload interrupted")?;
+        let chunk = chunk.as_ref();
+        size += chunk.len() as u64;
+        if size > max_bytes {
+            bail!("Upload exceeds the limit of {} bytes", max_bytes);
+        }
+        hasher.update(chunk);
+        file.write_all(chunk).await?;
+    }
+    file.flush().await?;
+
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
@@ -0,0 +1,35 @@
+// Shared setup for the integration tests. Not every test binary uses every helper.
+#![allow(dead_code)]
+
+use backend::db::{self, DbPool, Manifest};
+use std::fs;
+use std::path::Path;
+
+// Where a test keeps its database and files: target/tmp_test_<name>
+pub fn test_dir(name: &str) -> String {
+    format!("target/tmp_test_{}", name)
+}
+
+// The test's directory, emptied of anything a previous run left behind
+pub fn fresh_dir(name: &str) -> anyhow::Result<String> {
+    let dir = test_dir(name);
+    if Path::new(&dir).exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+pub fn db_url(dir: &str) -> String {
+    format!("sqlite:{}/test.db?mode=rwc", dir)
+}
+
+// A fresh test directory with the manifest written to <dir>/manifest.toml and the
+// database initialised from it
+pub async fn setup(name: &str, manifest: &str) -> anyhow::Result<(DbPool, Manifest, String)> {
+    let dir = fresh_dir(name)?;
+    let manifest_path = format!("{}/manifest.toml", dir);
+    fs::write(&manifest_path, manifest)?;
+    let (pool, manifest) = db::init_db(&db_url(&dir), &manifest_path).await?;
+    Ok((pool, manifest, dir))
+}
@@ -1,20 +1,12 @@
-use backend::{db, parser};
+use backend::units::{self, UnitSystem};
+use backend::{api, db, dry_run, jobs, migrations, parser, quarantine, uploads};
 use std::fs;
 use std::path::Path;
 
+mod common;
+
 #[tokio::test]
 async fn test_end_to_end_ingestion() -> anyhow::Result<()> {
-    // Setup
-    let test_dir = "target/tmp_test";
-    if Path::new(test_dir).exists() {
-        fs::remove_dir_all(test_dir)?;
-    }
-    fs::create_dir_all(test_dir)?;
-
-    let db_url = format!("sqlite:{}/test.db?mode=rwc", test_dir);
-    let manifest_path = format!("{}/manifest.toml", test_dir);
-    let xml_path = format!("{}/export.xml", test_dir);
-
     // Create Manifest
     let manifest_content = r#"
 [tables.records]
@@ -23,7 +15,8 @@ columns = [
     { name = "step_count", hk_type = "HKQuantityTypeIdentifierStepCount", aggregate = "sum", data_type = "INTEGER" }
 ]
 "#;
-    fs::write(&manifest_path, manifest_content)?;
+    let (pool, manifest, test_dir) = common::setup("end_to_end", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
 
     // Create Dummy XML
     let xml_content = r#"
@@ -35,22 +28,34 @@ columns = [
 "#;
     fs::write(&xml_path, xml_content)?;
 
-    // Initialize DB
-    let (pool, manifest) = db::init_db(&db_url, &manifest_path).await?;
-
     // Ingest
     let count =
         parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
     assert_eq!(count, 3);
 
     // Verify Data
-    let records = db::query_table(&pool, "records", 100, None, None, None).await?;
+    let records = db::query_table(
+        &pool,
+        &manifest,
+        "records",
+        100,
+        None,
+        &db::RowFilter::default(),
+    )
+    .await?;
     assert_eq!(records.len(), 3);
 
     // Verify Aggregation (Hourly)
     // 10:00 EST is 15:00 UTC.
     // HR: (60+80)/2 = 70. Steps: 500.
-    let agg = db::aggregate_table(&pool, &manifest, "records", "hour", None, None).await?;
+    let agg = db::aggregate_table(
+        &pool,
+        &manifest,
+        "records",
+        "hour",
+        &db::RowFilter::default(),
+    )
+    .await?;
     // Check if we have the bucket
     // Note: Result is a Vec<Value>. We need to find the right bucket.
     // Since we only have one hour of data, it should be the first row.
@@ -80,3 +85,2069 @@ columns = [
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_record_provenance() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.records]
+provenance = ["source_name", "source_version", "device", "unit"]
+columns = [
+    { name = "step_count", hk_type = "HKQuantityTypeIdentifierStepCount", aggregate = "sum", data_type = "INTEGER" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("provenance", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // Identical samples from the phone and the watch must both survive deduplication
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="iPhone" sourceVersion="17.2" device="&lt;&lt;HKDevice: 0x283e5e260&gt;, name:iPhone, manufacturer:Apple Inc., model:iPhone, hardware:iPhone15,2, software:17.2&gt;" unit="count" creationDate="2024-01-01 10:10:00 -0500" startDate="2024-01-01 10:10:00 -0500" endDate="2024-01-01 10:15:00 -0500" value="500"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Apple Watch" sourceVersion="10.2" unit="count" creationDate="2024-01-01 10:10:00 -0500" startDate="2024-01-01 10:10:00 -0500" endDate="2024-01-01 10:15:00 -0500" value="500"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+
+    let all = db::query_table(
+        &pool,
+        &manifest,
+        "records",
+        100,
+        None,
+        &db::RowFilter::default(),
+    )
+    .await?;
+    assert_eq!(all.len(), 2);
+
+    let filter = db::RowFilter {
+        source: Some("iPhone".to_string()),
+        ..Default::default()
+    };
+    let phone = db::query_table(&pool, &manifest, "records", 100, None, &filter).await?;
+    assert_eq!(phone.len(), 1);
+    assert_eq!(phone[0]["source_version"], "17.2");
+    assert_eq!(phone[0]["unit"], "count");
+    // The per-export object address is stripped from the device description
+    assert_eq!(
+        phone[0]["device"],
+        "name:iPhone, manufacturer:Apple Inc., model:iPhone, hardware:iPhone15,2, software:17.2"
+    );
+
+    let agg = db::aggregate_table(&pool, &manifest, "records", "day", &filter).await?;
+    assert_eq!(agg[0]["step_count"].as_i64(), Some(500));
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_source_priority_aggregation() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[source_priority]
+default = ["Watch", "iPhone"]
+
+[tables.records]
+provenance = ["source_name"]
+columns = [
+    { name = "step_count", hk_type = "HKQuantityTypeIdentifierStepCount", aggregate = "sum", data_type = "INTEGER" }
+]
+
+[tables.sleep]
+provenance = ["source_name"]
+columns = [
+    { name = "stage", hk_type = "HKCategoryTypeIdentifierSleepAnalysis", data_type = "INTEGER", role = "sleep_stage", categories = [
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepUnspecified", code = 1, label = "Asleep" },
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepCore", code = 3, label = "Core" },
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepDeep", code = 4, label = "Deep" }
+    ] }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("priority", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // Watch and phone both count the same 10 minutes; the phone alone covers 10:10-10:15.
+    // The last walk runs past midnight. The phone tracks 00:00-02:00 as plain sleep, the
+    // watch stages 00:30-02:30.
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Jane's Apple Watch" creationDate="2024-01-01 10:10:00 +0000" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:10:00 +0000" value="500"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Jane's iPhone" creationDate="2024-01-01 10:10:00 +0000" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:10:00 +0000" value="480"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Jane's iPhone" creationDate="2024-01-01 10:15:00 +0000" startDate="2024-01-01 10:05:00 +0000" endDate="2024-01-01 10:15:00 +0000" value="200"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Jane's Apple Watch" creationDate="2024-01-02 00:10:00 +0000" startDate="2024-01-01 23:50:00 +0000" endDate="2024-01-02 00:10:00 +0000" value="300"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Jane's iPhone" creationDate="2024-01-03 07:00:00 +0000" startDate="2024-01-03 00:00:00 +0000" endDate="2024-01-03 02:00:00 +0000" value="HKCategoryValueSleepAnalysisAsleepUnspecified"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Jane's Apple Watch" creationDate="2024-01-03 07:00:00 +0000" startDate="2024-01-03 00:30:00 +0000" endDate="2024-01-03 01:30:00 +0000" value="HKCategoryValueSleepAnalysisAsleepCore"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Jane's Apple Watch" creationDate="2024-01-03 07:00:00 +0000" startDate="2024-01-03 01:30:00 +0000" endDate="2024-01-03 02:30:00 +0000" value="HKCategoryValueSleepAnalysisAsleepDeep"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+
+    let agg = db::aggregate_table(
+        &pool,
+        &manifest,
+        "records",
+        "day",
+        &db::RowFilter::default(),
+    )
+    .await?;
+    // The walk past midnight is split between the days, though nothing starts on the 2nd
+    assert_eq!(agg[0]["time_bucket"], "2024-01-02");
+    assert_eq!(agg[0]["step_count"].as_i64(), Some(150));
+    // 500 from the watch + the non-overlapping half of the second phone sample + 150
+    assert_eq!(agg[1]["step_count"].as_i64(), Some(750));
+
+    // Only the part inside the range counts
+    let filter = db::RowFilter {
+        start: Some("2024-01-01T23:55:00+00:00".to_string()),
+        ..Default::default()
+    };
+    let agg = db::aggregate_table(&pool, &manifest, "records", "day", &filter).await?;
+    assert_eq!(agg[0]["step_count"].as_i64(), Some(150));
+    assert_eq!(agg[1]["step_count"].as_i64(), Some(75));
+
+    // A single-source view is left untouched
+    let filter = db::RowFilter {
+        source: Some("Jane's iPhone".to_string()),
+        ..Default::default()
+    };
+    let agg = db::aggregate_table(&pool, &manifest, "records", "day", &filter).await?;
+    assert_eq!(agg[0]["step_count"].as_i64(), Some(680));
+
+    // The phone's sleep only counts before the watch starts staging
+    let sleep = db::get_sleep_summary(&pool, &manifest, "2024-01-03").await?;
+    assert_eq!(sleep["breakdown"]["Asleep"].as_f64(), Some(1800.0));
+    assert_eq!(sleep["breakdown"]["Core"].as_f64(), Some(3600.0));
+    assert_eq!(sleep["breakdown"]["Deep"].as_f64(), Some(3600.0));
+    assert_eq!(sleep["total_sleep_hours"].as_f64(), Some(2.5));
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unit_normalization() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.body]
+provenance = ["unit", "source_unit"]
+columns = [
+    { name = "weight_kg", hk_type = "HKQuantityTypeIdentifierBodyMass", aggregate = "avg", data_type = "REAL", unit = "kg" },
+    { name = "glucose", hk_type = "HKQuantityTypeIdentifierBloodGlucose", aggregate = "avg", data_type = "REAL", unit = "mg/dL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("units", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // A locale switch halfway through: kg, then lb, then a unit we cannot interpret
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="kg" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000" value="70"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="lb" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000" value="165"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="furlong" creationDate="2024-01-03 08:00:00 +0000" startDate="2024-01-03 08:00:00 +0000" endDate="2024-01-03 08:00:00 +0000" value="1"/>
+ <Record type="HKQuantityTypeIdentifierBloodGlucose" unit="mmol&lt;180.1558800000541&gt;/L" creationDate="2024-01-01 09:00:00 +0000" startDate="2024-01-01 09:00:00 +0000" endDate="2024-01-01 09:00:00 +0000" value="5.5"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    let count =
+        parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    assert_eq!(count, 3);
+
+    let filter = db::RowFilter::default();
+    let mut rows = db::query_table(&pool, &manifest, "body", 100, None, &filter).await?;
+    // Newest first: 165 lb, the glucose reading, then 70 kg
+    assert_eq!(rows.len(), 3);
+    assert!((rows[0]["weight_kg"].as_f64().unwrap() - 74.842741).abs() < 1e-6);
+    assert!((rows[1]["glucose"].as_f64().unwrap() - 99.085734).abs() < 1e-6);
+    assert_eq!(rows[2]["weight_kg"].as_f64(), Some(70.0));
+    // The stored unit is the one the value is in; the export's unit is kept beside it
+    let units = |row: &serde_json::Value| (row["unit"].clone(), row["source_unit"].clone());
+    assert_eq!(units(&rows[0]), ("kg".into(), "lb".into()));
+    assert_eq!(
+        units(&rows[1]),
+        ("mg/dL".into(), "mmol<180.1558800000541>/L".into())
+    );
+    assert_eq!(units(&rows[2]), ("kg".into(), "kg".into()));
+
+    units::convert_rows_for_display(&manifest, "body", &mut rows, UnitSystem::Imperial, false);
+    let lbs = rows[0]["weight_kg"].as_f64().unwrap();
+    assert!((lbs - 165.0).abs() < 1e-3);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_correlation_records() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.blood_pressure]
+provenance = ["source_name"]
+correlation_type = "HKCorrelationTypeIdentifierBloodPressure"
+columns = [
+    { name = "systolic", hk_type = "HKQuantityTypeIdentifierBloodPressureSystolic", extraction_source = "correlation_member", data_type = "REAL", unit = "mmHg" },
+    { name = "diastolic", hk_type = "HKQuantityTypeIdentifierBloodPressureDiastolic", extraction_source = "correlation_member", data_type = "REAL", unit = "mmHg" }
+]
+
+[tables.nutrition]
+columns = [
+    { name = "energy_kcal", hk_type = "HKQuantityTypeIdentifierDietaryEnergyConsumed", data_type = "REAL", unit = "kcal" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("correlation", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // Children may carry their own metadata, and Food is not mapped as a correlation so
+    // its members are still picked up as plain records
+    let xml_content = r#"
+<HealthData>
+ <Correlation type="HKCorrelationTypeIdentifierBloodPressure" sourceName="Omron" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000">
+  <MetadataEntry key="HKWasUserEntered" value="0"/>
+  <Record type="HKQuantityTypeIdentifierBloodPressureDiastolic" sourceName="Omron" unit="mmHg" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000" value="79"/>
+  <Record type="HKQuantityTypeIdentifierBloodPressureSystolic" sourceName="Omron" unit="mmHg" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000" value="121">
+   <MetadataEntry key="HKWasUserEntered" value="0"/>
+  </Record>
+ </Correlation>
+ <Correlation type="HKCorrelationTypeIdentifierBloodPressure" sourceName="Omron" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000">
+  <Record type="HKQuantityTypeIdentifierBloodPressureSystolic" sourceName="Omron" unit="mmHg" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000" value="118"/>
+  <Record type="HKQuantityTypeIdentifierBloodPressureDiastolic" sourceName="Omron" unit="mmHg" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000" value="76"/>
+ </Correlation>
+ <Record type="HKQuantityTypeIdentifierBloodPressureSystolic" sourceName="Omron" unit="mmHg" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000" value="118"/>
+ <Correlation type="HKCorrelationTypeIdentifierFood" sourceName="MyFitnessPal" creationDate="2024-01-01 12:00:00 +0000" startDate="2024-01-01 12:00:00 +0000" endDate="2024-01-01 12:00:00 +0000">
+  <Record type="HKQuantityTypeIdentifierDietaryEnergyConsumed" sourceName="MyFitnessPal" unit="kJ" creationDate="2024-01-01 12:00:00 +0000" startDate="2024-01-01 12:00:00 +0000" endDate="2024-01-01 12:00:00 +0000" value="2092"/>
+ </Correlation>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    let count =
+        parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    assert_eq!(count, 3);
+
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "blood_pressure", 100, None, &filter).await?;
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["systolic"].as_f64(), Some(118.0));
+    assert_eq!(rows[0]["diastolic"].as_f64(), Some(76.0));
+    assert_eq!(rows[1]["systolic"].as_f64(), Some(121.0));
+    assert_eq!(rows[1]["diastolic"].as_f64(), Some(79.0));
+    assert_eq!(rows[1]["source_name"], "Omron");
+
+    let rows = db::query_table(&pool, &manifest, "nutrition", 100, None, &filter).await?;
+    assert_eq!(rows.len(), 1);
+    assert!((rows[0]["energy_kcal"].as_f64().unwrap() - 500.0).abs() < 1e-6);
+
+    // Re-ingesting the same export must not duplicate readings
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    let rows = db::query_table(&pool, &manifest, "blood_pressure", 100, None, &filter).await?;
+    assert_eq!(rows.len(), 2);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_record_metadata() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", aggregate = "avg", data_type = "REAL" },
+    { name = "was_user_entered", hk_type = "HKWasUserEntered", extraction_source = "metadata_value", data_type = "INTEGER" },
+    { name = "motion_context", hk_type = "HKMetadataKeyHeartRateMotionContext", extraction_source = "metadata_value", data_type = "INTEGER" }
+]
+
+[tables.nutrition]
+columns = [
+    { name = "water_ml", hk_type = "HKQuantityTypeIdentifierDietaryWater", data_type = "REAL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("metadata", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000" value="60">
+  <MetadataEntry key="HKMetadataKeyHeartRateMotionContext" value="1"/>
+ </Record>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 09:00:00 +0000" startDate="2024-01-01 09:00:00 +0000" endDate="2024-01-01 09:00:00 +0000" value="100">
+  <MetadataEntry key="HKWasUserEntered" value="1"/>
+ </Record>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 10:00:00 +0000" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:00:00 +0000" value="70"/>
+ <Record type="HKQuantityTypeIdentifierDietaryWater" creationDate="2024-01-01 10:00:00 +0000" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:00:00 +0000" value="250"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    let count =
+        parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    assert_eq!(count, 4);
+
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "vitals", 100, None, &filter).await?;
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[1]["was_user_entered"].as_i64(), Some(1));
+    assert_eq!(rows[2]["motion_context"].as_i64(), Some(1));
+
+    let filter = db::RowFilter {
+        exclude_user_entered: true,
+        ..Default::default()
+    };
+    let measured = db::query_table(&pool, &manifest, "vitals", 100, None, &filter).await?;
+    assert_eq!(measured.len(), 2);
+    assert!(measured.iter().all(|r| r["heart_rate"] != 100.0));
+
+    let agg = db::aggregate_table(&pool, &manifest, "vitals", "day", &filter).await?;
+    assert_eq!(agg[0]["heart_rate"].as_f64(), Some(65.0));
+
+    // Tables without the flag cannot honour the filter
+    assert!(
+        db::query_table(&pool, &manifest, "nutrition", 100, None, &filter)
+            .await
+            .is_err()
+    );
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_beat_to_beat_hrv() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[beat_to_beat]
+target_table = "hrv_beats"
+
+[tables.vitals]
+columns = [
+    { name = "hrv_sdnn", hk_type = "HKQuantityTypeIdentifierHeartRateVariabilitySDNN", data_type = "REAL", unit = "ms" },
+    { name = "hrv_rmssd", hk_type = "HKQuantityTypeIdentifierHeartRateVariabilitySDNN", extraction_source = "beat_rmssd", data_type = "REAL" },
+    { name = "hrv_pnn50", hk_type = "HKQuantityTypeIdentifierHeartRateVariabilitySDNN", extraction_source = "beat_pnn50", data_type = "REAL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("hrv_beats", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // RR: first beat nominal, then 1000, 900 and 1000 ms; the last beat follows a gap
+    // and must not enter the successive differences
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierHeartRateVariabilitySDNN" unit="ms" creationDate="2024-01-01 19:53:00 +0100" startDate="2024-01-01 19:52:00 +0100" endDate="2024-01-01 19:53:00 +0100" value="45.5">
+  <HeartRateVariabilityMetadataList>
+   <InstantaneousBeatsPerMinute bpm="60" time="7:52:15.00 PM"/>
+   <InstantaneousBeatsPerMinute bpm="60" time="7:52:16.00 PM"/>
+   <InstantaneousBeatsPerMinute bpm="67" time="7:52:16.90 PM"/>
+   <InstantaneousBeatsPerMinute bpm="60" time="7:52:17.90 PM"/>
+   <InstantaneousBeatsPerMinute bpm="60" time="7:52:30.00 PM"/>
+  </HeartRateVariabilityMetadataList>
+ </Record>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "vitals", 100, None, &filter).await?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0]["hrv_sdnn"].as_f64(), Some(45.5));
+    assert!((rows[0]["hrv_rmssd"].as_f64().unwrap() - 100.0).abs() < 1e-6);
+    assert!((rows[0]["hrv_pnn50"].as_f64().unwrap() - 100.0).abs() < 1e-6);
+
+    let uuid = rows[0]["uuid"].as_str().unwrap().to_string();
+    let series = db::get_rr_series(&pool, &manifest, &uuid).await?;
+    assert_eq!(series["beat_count"], 5);
+    let beats = series["beats"].as_array().unwrap();
+    assert_eq!(beats[0]["timestamp"], "2024-01-01T18:52:15.000Z");
+    assert_eq!(beats[0]["continuous"], false);
+    assert!((beats[2]["rr_ms"].as_f64().unwrap() - 900.0).abs() < 1e-6);
+    assert_eq!(beats[4]["continuous"], false);
+    assert_eq!(beats[4]["rr_ms"].as_f64(), Some(1000.0));
+
+    // Re-ingesting keeps one row per beat
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM hrv_beats")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(count.0, 5);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_category_values() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.sleep]
+columns = [
+    { name = "sleep_stage", hk_type = "HKCategoryTypeIdentifierSleepAnalysis", data_type = "INTEGER", categories = [
+        { hk_value = "HKCategoryValueSleepAnalysisInBed", code = 0, label = "Bed" },
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepCore", code = 3, label = "Light" },
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepDeep", code = 4, label = "Deep" },
+    ] }
+]
+
+[tables.events]
+columns = [
+    { name = "stand_hour", hk_type = "HKCategoryTypeIdentifierAppleStandHour", data_type = "INTEGER", categories = [
+        { hk_value = "HKCategoryValueAppleStandHourStood", code = 0, label = "Stood" },
+        { hk_value = "HKCategoryValueAppleStandHourIdle", code = 1, label = "Idle" },
+    ] }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("categories", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // The REM stage is not in the manifest and must be rejected rather than stored as text
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 01:00:00 +0000" endDate="2024-01-02 02:00:00 +0000" value="HKCategoryValueSleepAnalysisAsleepCore"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 02:00:00 +0000" endDate="2024-01-02 02:30:00 +0000" value="HKCategoryValueSleepAnalysisAsleepDeep"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 02:30:00 +0000" endDate="2024-01-02 03:00:00 +0000" value="HKCategoryValueSleepAnalysisAsleepREM"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 03:00:00 +0000" endDate="2024-01-02 04:00:00 +0000" value="3"/>
+ <Record type="HKCategoryTypeIdentifierAppleStandHour" creationDate="2024-01-02 10:00:00 +0000" startDate="2024-01-02 09:00:00 +0000" endDate="2024-01-02 10:00:00 +0000" value="HKCategoryValueAppleStandHourStood"/>
+ <Record type="HKCategoryTypeIdentifierAppleStandHour" creationDate="2024-01-02 11:00:00 +0000" startDate="2024-01-02 10:00:00 +0000" endDate="2024-01-02 11:00:00 +0000" value="HKCategoryValueAppleStandHourIdle"/>
+ <Record type="HKCategoryTypeIdentifierAppleStandHour" creationDate="2024-01-02 12:00:00 +0000" startDate="2024-01-02 11:00:00 +0000" endDate="2024-01-02 12:00:00 +0000" value="HKCategoryValueAppleStandHourStood"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    let count =
+        parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    assert_eq!(count, 6);
+
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "sleep", 100, None, &filter).await?;
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[1]["sleep_stage"].as_i64(), Some(4));
+
+    let sleep = db::get_sleep_summary(&pool, &manifest, "2024-01-02").await?;
+    assert_eq!(sleep["breakdown"]["Light"].as_f64(), Some(7200.0));
+    assert_eq!(sleep["breakdown"]["Deep"].as_f64(), Some(1800.0));
+
+    let stand = db::get_category_summary(&pool, &manifest, "events", "stand_hour", &filter).await?;
+    assert_eq!(stand["breakdown"]["Stood"]["count"], 2);
+    assert_eq!(stand["breakdown"]["Idle"]["count"], 1);
+    assert_eq!(stand["breakdown"]["Idle"]["total_seconds"], 3600);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_analysis_roles() -> anyhow::Result<()> {
+    // Nothing is named vitals or sleep, and no column has the hrv role
+    let manifest_content = r#"
+[tables.cardio]
+columns = [
+    { name = "bpm", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL", role = "heart_rate" },
+    { name = "rhr", hk_type = "HKQuantityTypeIdentifierRestingHeartRate", data_type = "REAL", role = "resting_hr" }
+]
+
+[tables.nights]
+columns = [
+    { name = "stage", hk_type = "HKCategoryTypeIdentifierSleepAnalysis", data_type = "INTEGER", role = "sleep_stage", categories = [
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepDeep", code = 4, label = "Deep" }
+    ] }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("analysis_roles", manifest_content).await?;
+    let db_url = common::db_url(&test_dir);
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-02 09:00:00 +0000" startDate="2024-01-02 09:00:00 +0000" endDate="2024-01-02 09:00:00 +0000" value="80"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 02:00:00 +0000" endDate="2024-01-02 02:30:00 +0000" value="HKCategoryValueSleepAnalysisAsleepDeep"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+
+    let trends = db::get_biometric_trends(
+        &pool,
+        &manifest,
+        "2024-01-01T00:00:00+00:00",
+        "2024-01-03T00:00:00+00:00",
+    )
+    .await?;
+    assert_eq!(trends["bpm_avg"], 70.0);
+    assert_eq!(trends["bpm_max"], 80.0);
+
+    let sleep = db::get_sleep_summary(&pool, &manifest, "2024-01-02").await?;
+    assert_eq!(sleep["breakdown"]["Deep"].as_f64(), Some(1800.0));
+
+    let err = db::get_recovery_analysis(&pool, &manifest)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Analysis role 'hrv' is not configured"),
+        "{}",
+        err
+    );
+    pool.close().await;
+
+    // A role belongs to one column
+    let duplicate = format!("{}/duplicate.toml", test_dir);
+    fs::write(
+        &duplicate,
+        manifest_content.replace("role = \"resting_hr\"", "role = \"heart_rate\""),
+    )?;
+    let err = db::init_db(&db_url, &duplicate).await.unwrap_err();
+    assert!(err.to_string().contains("Role 'heart_rate'"), "{}", err);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_schema_migrations() -> anyhow::Result<()> {
+    let v1 = format!(
+        r#"
+[settings]
+backup_dir = "{}/backups"
+
+[tables.vitals]
+provenance = ["source_name"]
+columns = [
+    {{ field_name = "hr", hk_identifier = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }},
+    {{ field_name = "spo2", hk_identifier = "HKQuantityTypeIdentifierOxygenSaturation", data_type = "REAL" }},
+    {{ field_name = "steps", hk_identifier = "HKQuantityTypeIdentifierStepCount", data_type = "REAL" }},
+    {{ field_name = "rhr", hk_identifier = "HKQuantityTypeIdentifierRestingHeartRate", data_type = "REAL" }},
+    {{ field_name = "effort", data_type = "REAL", expression = "hr * 0.1" }}
+]
+
+[tables.activity]
+provenance = ["source_name"]
+columns = [
+    {{ field_name = "energy", hk_identifier = "HKQuantityTypeIdentifierActiveEnergyBurned", data_type = "REAL" }}
+]
+"#,
+        common::test_dir("migrations")
+    );
+    let (pool, manifest, test_dir) = common::setup("migrations", &v1).await?;
+    let db_url = common::db_url(&test_dir);
+    let manifest_path = format!("{}/manifest.toml", test_dir);
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierOxygenSaturation" sourceName="Watch" creationDate="2024-01-02 08:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 08:00:00 +0000" value="0.97"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="Phone" creationDate="2024-01-02 09:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 09:00:00 +0000" value="1200"/>
+ <Record type="HKQuantityTypeIdentifierRestingHeartRate" sourceName="Watch" creationDate="2024-01-02 23:00:00 +0000" startDate="2024-01-02 00:00:00 +0000" endDate="2024-01-02 23:00:00 +0000" value="52"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    assert!(migrations::plan(&pool, &manifest).await?.is_current());
+    pool.close().await;
+
+    // Renamed, retyped, dropped, recomputed and moved columns
+    let v2 = v1
+        .replace("\"hr\"", "\"heart_rate\"")
+        .replace("hr * 0.1", "heart_rate * 2")
+        .replace(
+            "    { field_name = \"spo2\", hk_identifier = \"HKQuantityTypeIdentifierOxygenSaturation\", data_type = \"REAL\" },\n",
+            "",
+        )
+        .replace(
+            "HKQuantityTypeIdentifierStepCount\", data_type = \"REAL\"",
+            "HKQuantityTypeIdentifierStepCount\", data_type = \"INTEGER\"",
+        )
+        .replace(
+            "    { field_name = \"rhr\", hk_identifier = \"HKQuantityTypeIdentifierRestingHeartRate\", data_type = \"REAL\" },\n",
+            "",
+        )
+        .replace(
+            "ActiveEnergyBurned\", data_type = \"REAL\" }",
+            "ActiveEnergyBurned\", data_type = \"REAL\" },\n    { field_name = \"resting\", hk_identifier = \"HKQuantityTypeIdentifierRestingHeartRate\", data_type = \"REAL\" }",
+        );
+    fs::write(&manifest_path, &v2)?;
+
+    // Startup only adds columns; the plan waits to be applied
+    let (pool, manifest) = db::init_db(&db_url, &manifest_path).await?;
+    let plan = migrations::plan(&pool, &manifest).await?;
+    assert_eq!(plan.from_version, Some(1));
+    assert!(!plan.is_current());
+    let actions: Vec<(&str, &str)> = plan
+        .steps
+        .iter()
+        .map(|s| (s.table.as_str(), s.action))
+        .collect();
+    assert_eq!(actions, vec![("activity", "move"), ("vitals", "rebuild")]);
+    let changes = &plan.steps[1].changes;
+    for change in [
+        "rename hr to heart_rate",
+        "drop spo2",
+        "drop rhr (moved to activity.resting)",
+        "change steps from REAL to INTEGER",
+        "recompute effort",
+        "recompute the uuid of heart_rate rows",
+    ] {
+        assert!(changes.iter().any(|c| c == change), "{:?}", changes);
+    }
+    let (spo2,): (Option<f64>,) = sqlx::query_as("SELECT MAX(spo2) FROM vitals")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(spo2, Some(0.97));
+
+    // Ingested before the migration is applied, straight into the renamed column
+    let later_path = format!("{}/export_later.xml", test_dir);
+    fs::write(
+        &later_path,
+        r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" creationDate="2024-01-03 08:00:00 +0000" startDate="2024-01-03 08:00:00 +0000" endDate="2024-01-03 08:00:00 +0000" value="70"/>
+</HealthData>
+"#,
+    )?;
+    parser::parse_and_ingest(Path::new(&later_path), &pool, &manifest, None::<fn(usize)>).await?;
+
+    let applied = migrations::apply(&pool, &manifest).await?;
+    assert_eq!(applied.version, 2);
+    assert_eq!(applied.steps, 2);
+    assert!(Path::new(applied.backup_path.as_deref().unwrap()).exists());
+
+    let heart_rates: Vec<(f64, f64, String)> = sqlx::query_as(
+        "SELECT heart_rate, effort, source_name FROM vitals WHERE heart_rate IS NOT NULL ORDER BY start_date",
+    )
+    .fetch_all(&pool)
+    .await?;
+    assert_eq!(
+        heart_rates,
+        vec![
+            (60.0, 120.0, "Watch".to_string()),
+            (70.0, 140.0, "Watch".to_string())
+        ]
+    );
+    let (steps_type,): (String,) =
+        sqlx::query_as("SELECT typeof(steps) FROM vitals WHERE steps IS NOT NULL")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(steps_type, "integer");
+    assert!(sqlx::query("SELECT spo2 FROM vitals")
+        .fetch_all(&pool)
+        .await
+        .is_err());
+    let (rows,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM vitals")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(rows, 4); // the resting heart rate left
+    let (resting, source): (f64, String) =
+        sqlx::query_as("SELECT resting, source_name FROM activity")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!((resting, source.as_str()), (52.0, "Watch"));
+
+    // Moved and renamed rows were given the uuids ingestion computes now, so importing the
+    // same exports again stores nothing new
+    for path in [&xml_path, &later_path] {
+        let report = parser::parse_and_ingest_with_options(
+            Path::new(path),
+            &pool,
+            &manifest,
+            parser::IngestOptions::default(),
+            None::<fn(usize)>,
+        )
+        .await?;
+        assert_eq!(report.inserted, 0, "{}", path);
+    }
+    let counts: Vec<(i64,)> =
+        sqlx::query_as("SELECT COUNT(*) FROM vitals UNION ALL SELECT COUNT(*) FROM activity")
+            .fetch_all(&pool)
+            .await?;
+    assert_eq!(counts, vec![(4,), (1,)]);
+
+    assert!(migrations::plan(&pool, &manifest).await?.is_current());
+    let versions = migrations::list_versions(&pool).await?;
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0].backup_path, applied.backup_path);
+    pool.close().await;
+
+    // Adding a column needs no migration and is recorded at startup
+    let v3 = v2.replace(
+        "data_type = \"INTEGER\" }",
+        "data_type = \"INTEGER\" },\n    { field_name = \"vo2\", hk_identifier = \"HKQuantityTypeIdentifierVO2Max\", data_type = \"REAL\" }",
+    );
+    fs::write(&manifest_path, &v3)?;
+    let (pool, manifest) = db::init_db(&db_url, &manifest_path).await?;
+    assert!(migrations::plan(&pool, &manifest).await?.is_current());
+    assert_eq!(migrations::list_versions(&pool).await?[0].version, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dry_run_analysis() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.records]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" },
+    { name = "vo2_max", hk_type = "HKQuantityTypeIdentifierVO2Max", data_type = "REAL" },
+    { name = "hrv_rmssd", hk_type = "HKQuantityTypeIdentifierHeartRateVariabilitySDNN", data_type = "REAL", extraction_source = "beat_rmssd" },
+    { name = "sleep_stage", hk_type = "HKCategoryTypeIdentifierSleepAnalysis", data_type = "INTEGER", categories = [
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepCore", code = 3, label = "Light" },
+    ] }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("dry_run", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let xml_content = r#"
+<HealthData>
+ <ExportDate value="2024-01-03 08:00:00 +0000"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Watch" unit="count/min" creationDate="2024-01-02 10:00:00 +0000" startDate="2024-01-02 10:00:00 +0000" endDate="2024-01-02 10:01:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" sourceName="Strap" unit="count/min" creationDate="2024-01-01 09:00:00 +0000" startDate="2024-01-01 09:00:00 +0000" endDate="2024-01-01 09:01:00 +0000" value="70"/>
+ <Record type="HKQuantityTypeIdentifierHeartRateVariabilitySDNN" sourceName="Watch" unit="ms" creationDate="2024-01-02 10:00:00 +0000" startDate="2024-01-02 10:00:00 +0000" endDate="2024-01-02 10:01:00 +0000" value="45"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" sourceName="iPhone" unit="count" creationDate="2024-01-02 10:00:00 +0000" startDate="2024-01-02 10:00:00 +0000" endDate="2024-01-02 10:05:00 +0000" value="500"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Watch" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 01:00:00 +0000" endDate="2024-01-02 02:00:00 +0000" value="HKCategoryValueSleepAnalysisAsleepCore"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" sourceName="Watch" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 02:00:00 +0000" endDate="2024-01-02 03:00:00 +0000" value="HKCategoryValueSleepAnalysisAsleepREM"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    let report = dry_run::dry_run(Path::new(&xml_path), &manifest).await?;
+
+    assert_eq!(
+        report.export_date.as_deref(),
+        Some("2024-01-03T08:00:00+00:00")
+    );
+    let summary = |hk_type: &str| report.types.iter().find(|t| t.hk_type == hk_type).unwrap();
+    let heart_rate = summary("HKQuantityTypeIdentifierHeartRate");
+    assert_eq!(heart_rate.count, 2);
+    assert_eq!(
+        heart_rate.first_date.as_deref(),
+        Some("2024-01-01T09:00:00+00:00")
+    );
+    assert_eq!(
+        heart_rate.last_date.as_deref(),
+        Some("2024-01-02T10:00:00+00:00")
+    );
+    assert_eq!(
+        heart_rate.sources.iter().collect::<Vec<_>>(),
+        ["Strap", "Watch"]
+    );
+    assert_eq!(heart_rate.units.iter().collect::<Vec<_>>(), ["count/min"]);
+    assert_eq!(heart_rate.tables, ["records"]);
+
+    let sleep = summary("HKCategoryTypeIdentifierSleepAnalysis");
+    assert_eq!(sleep.categories.len(), 2);
+    assert_eq!(report.unknown_categories.len(), 1);
+    assert_eq!(
+        report.unknown_categories[0].value,
+        "HKCategoryValueSleepAnalysisAsleepREM"
+    );
+
+    // A column that only derives a statistic from beats does not store the record itself,
+    // so the dry run reports its type as unmapped, as ingestion would drop it
+    assert_eq!(
+        report.unmapped_types,
+        [
+            "HKQuantityTypeIdentifierHeartRateVariabilitySDNN",
+            "HKQuantityTypeIdentifierStepCount"
+        ]
+    );
+    assert_eq!(report.unmatched_columns.len(), 1);
+    assert_eq!(report.unmatched_columns[0].column, "vo2_max");
+
+    // Nothing was written
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "records", 100, None, &filter).await?;
+    assert!(rows.is_empty());
+
+    // Ingesting stores exactly the mapped types: both heart rates and the known sleep stage,
+    // the other one is quarantined
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&xml_path),
+        &pool,
+        &manifest,
+        parser::IngestOptions::default(),
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!((report.inserted, report.quarantined), (3, 1));
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_me_profile() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.workouts]
+columns = [
+    { name = "session_id", hk_attribute = "startDate", data_type = "DATETIME", is_primary_key = true, extraction_source = "attribute" }
+]
+
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("profile", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let xml_content = r#"
+<HealthData>
+ <Me HKCharacteristicTypeIdentifierDateOfBirth="1984-06-15" HKCharacteristicTypeIdentifierBiologicalSex="HKBiologicalSexFemale" HKCharacteristicTypeIdentifierBloodType="HKBloodTypeNotSet" HKCharacteristicTypeIdentifierFitzpatrickSkinType="HKFitzpatrickSkinTypeIII"/>
+ <Workout workoutActivityType="HKWorkoutActivityTypeRunning" duration="30" creationDate="2024-06-15 08:30:00 +0000" startDate="2024-06-15 08:00:00 +0000" endDate="2024-06-15 08:30:00 +0000">
+ </Workout>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-06-15 08:10:00 +0000" startDate="2024-06-15 08:10:00 +0000" endDate="2024-06-15 08:10:00 +0000" value="170"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+
+    let profile = db::load_profile(&pool).await?.expect("profile imported");
+    assert_eq!(profile.date_of_birth.as_deref(), Some("1984-06-15"));
+    assert_eq!(profile.biological_sex.as_deref(), Some("Female"));
+    assert_eq!(profile.blood_type, None);
+    assert_eq!(profile.skin_type.as_deref(), Some("III"));
+
+    // 40 on the day of the workout: 208 - 0.7 * 40 = 180, so 170 bpm is 94%
+    let session_id: (String,) = sqlx::query_as("SELECT session_id FROM workouts")
+        .fetch_one(&pool)
+        .await?;
+    let intensity = db::get_workout_intensity(&pool, &manifest, &session_id.0).await?;
+    assert_eq!(intensity["max_hr_source"], "age");
+    assert!((intensity["max_hr_used"].as_f64().unwrap() - 180.0).abs() < 1e-9);
+    assert_eq!(intensity["zones"]["Z5_Anaerobic"], 1);
+
+    assert_eq!(
+        db::age_at("1984-06-15", "2024-06-14T23:00:00+00:00"),
+        Some(39)
+    );
+
+    // A malformed <Me> is quarantined and keeps the stored profile, the rest is ingested
+    let broken_path = format!("{}/broken.xml", test_dir);
+    fs::write(
+        &broken_path,
+        r#"<HealthData>
+ <Me HKCharacteristicTypeIdentifierDateOfBirth="1990&bogus;" HKCharacteristicTypeIdentifierBiologicalSex="HKBiologicalSexMale"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-06-16 08:10:00 +0000" startDate="2024-06-16 08:10:00 +0000" endDate="2024-06-16 08:10:00 +0000" value="65"/>
+</HealthData>
+"#,
+    )?;
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&broken_path),
+        &pool,
+        &manifest,
+        parser::IngestOptions::default(),
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!((report.inserted, report.quarantined), (1, 1));
+    let (element, reason): (String, String) =
+        sqlx::query_as("SELECT element, reason FROM quarantine")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(element, "Me");
+    assert!(reason.contains("HKCharacteristicTypeIdentifierDateOfBirth"));
+    let profile = db::load_profile(&pool).await?.expect("profile kept");
+    assert_eq!(profile.date_of_birth.as_deref(), Some("1984-06-15"));
+    assert_eq!(profile.biological_sex.as_deref(), Some("Female"));
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_workout_events() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[workout_events]
+target_table = "workout_events"
+
+[tables.workouts]
+columns = [
+    { name = "session_id", hk_attribute = "startDate", data_type = "DATETIME", is_primary_key = true, extraction_source = "attribute" },
+    { name = "indoor", hk_identifier = "HKIndoorWorkout", data_type = "INTEGER", extraction_source = "metadata_value" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("workout_events", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // 40 minutes elapsed: paused 10:10-10:15, auto-paused 10:20-10:22 and again from 10:38 to the end
+    let xml_content = r#"
+<HealthData>
+ <Workout workoutActivityType="HKWorkoutActivityTypeRunning" duration="40" durationUnit="min" creationDate="2024-06-15 10:40:00 +0000" startDate="2024-06-15 10:00:00 +0000" endDate="2024-06-15 10:40:00 +0000">
+  <MetadataEntry key="HKIndoorWorkout" value="0"/>
+  <WorkoutEvent type="HKWorkoutEventTypeSegment" date="2024-06-15 10:00:00 +0000" duration="300" durationUnit="s">
+   <MetadataEntry key="HKIndoorWorkout" value="1"/>
+  </WorkoutEvent>
+  <WorkoutEvent type="HKWorkoutEventTypePause" date="2024-06-15 10:10:00 +0000"/>
+  <WorkoutEvent type="HKWorkoutEventTypeResume" date="2024-06-15 10:15:00 +0000"/>
+  <WorkoutEvent type="HKWorkoutEventTypeMotionPaused" date="2024-06-15 10:20:00 +0000"/>
+  <WorkoutEvent type="HKWorkoutEventTypeMotionResumed" date="2024-06-15 10:22:00 +0000"/>
+  <WorkoutEvent type="HKWorkoutEventTypeLap" date="2024-06-15 10:25:00 +0000" duration="25" durationUnit="min"/>
+  <WorkoutEvent type="HKWorkoutEventTypeMarker" date="soon"/>
+  <WorkoutEvent type="HKWorkoutEventTypeMotionPaused" date="2024-06-15 10:38:00 +0000"/>
+ </Workout>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&xml_path),
+        &pool,
+        &manifest,
+        parser::IngestOptions::default(),
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!(report.tables["workout_events"], 7);
+    assert_eq!(report.quarantined, 1);
+
+    let details = db::get_workout_details(&pool, &manifest, "2024-06-15 10:00:00 +0000").await?;
+    // An event's own metadata is not mistaken for the workout's
+    assert_eq!(details["indoor"], 0);
+
+    let events = details["events"].as_array().unwrap();
+    let types: Vec<&str> = events
+        .iter()
+        .map(|e| e["event_type"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        types,
+        [
+            "Segment",
+            "Pause",
+            "Resume",
+            "MotionPaused",
+            "MotionResumed",
+            "Lap",
+            "MotionPaused"
+        ]
+    );
+    assert_eq!(events[0]["duration_minutes"], 5.0);
+    assert_eq!(events[0]["metadata"]["HKIndoorWorkout"], "1");
+    assert_eq!(events[1]["date"], "2024-06-15T10:10:00+00:00");
+
+    assert_eq!(details["elapsed_minutes"], 40.0);
+    assert_eq!(details["paused_minutes"], 9.0);
+    assert_eq!(details["moving_minutes"], 31.0);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multisport_workout_activities() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[workout_activities]
+target_table = "workout_activities"
+
+[workout_events]
+target_table = "workout_events"
+
+[tables.workouts]
+columns = [
+    { name = "session_id", hk_attribute = "startDate", data_type = "DATETIME", is_primary_key = true, extraction_source = "attribute" },
+    { name = "active_calories", hk_identifier = "HKQuantityTypeIdentifierActiveEnergyBurned", data_type = "REAL", unit = "kcal", extraction_source = "statistics_sum" },
+    { name = "distance_km", hk_identifier = "HKQuantityTypeIdentifierDistanceWalkingRunning", data_type = "REAL", unit = "km", extraction_source = "statistics_sum" },
+    { name = "avg_hr", hk_identifier = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL", unit = "count/min", extraction_source = "statistics_average" },
+    { name = "min_hr", hk_identifier = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL", unit = "count/min", extraction_source = "statistics_min" },
+    { name = "max_hr", hk_identifier = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL", unit = "count/min", extraction_source = "statistics_max" },
+    { name = "indoor", hk_identifier = "HKIndoorWorkout", data_type = "INTEGER", extraction_source = "metadata_value" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("workout_activities", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // The workout reports its calories and heart rate, but neither its distance nor its maximum
+    // heart rate; the third leg has a bad date
+    let xml_content = r#"
+<HealthData>
+ <Workout workoutActivityType="HKWorkoutActivityTypeSwimBikeRun" duration="90" durationUnit="min" creationDate="2024-07-01 09:30:00 +0000" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 09:30:00 +0000">
+  <MetadataEntry key="HKIndoorWorkout" value="0"/>
+  <WorkoutActivity uuid="A1" workoutActivityType="HKWorkoutActivityTypeSwimming" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 08:30:00 +0000" duration="1800" durationUnit="s">
+   <MetadataEntry key="HKIndoorWorkout" value="1"/>
+   <WorkoutEvent type="HKWorkoutEventTypeLap" date="2024-07-01 08:10:00 +0000"/>
+   <WorkoutStatistics type="HKQuantityTypeIdentifierActiveEnergyBurned" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 08:30:00 +0000" sum="300" unit="kcal"/>
+   <WorkoutStatistics type="HKQuantityTypeIdentifierHeartRate" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 08:30:00 +0000" average="138" minimum="92" maximum="160" unit="count/min"/>
+  </WorkoutActivity>
+  <WorkoutActivity uuid="A2" startDate="2024-07-01 08:35:00 +0000" endDate="2024-07-01 09:30:00 +0000" duration="55" durationUnit="min">
+   <WorkoutStatistics type="HKQuantityTypeIdentifierActiveEnergyBurned" startDate="2024-07-01 08:35:00 +0000" endDate="2024-07-01 09:30:00 +0000" sum="500" unit="kcal"/>
+   <WorkoutStatistics type="HKQuantityTypeIdentifierDistanceWalkingRunning" startDate="2024-07-01 08:35:00 +0000" endDate="2024-07-01 09:30:00 +0000" sum="12000" unit="m"/>
+   <WorkoutStatistics type="HKQuantityTypeIdentifierHeartRate" startDate="2024-07-01 08:35:00 +0000" endDate="2024-07-01 09:30:00 +0000" average="151" minimum="120" maximum="175" unit="count/min"/>
+  </WorkoutActivity>
+  <WorkoutActivity uuid="A3" startDate="later" endDate="2024-07-01 09:30:00 +0000"/>
+  <WorkoutStatistics type="HKQuantityTypeIdentifierActiveEnergyBurned" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 09:30:00 +0000" sum="820" unit="kcal"/>
+  <WorkoutStatistics type="HKQuantityTypeIdentifierHeartRate" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 09:30:00 +0000" average="146" minimum="95" unit="count/min"/>
+ </Workout>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&xml_path),
+        &pool,
+        &manifest,
+        parser::IngestOptions::default(),
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!(report.tables["workout_activities"], 2);
+    assert_eq!(report.tables["workout_events"], 1);
+    assert_eq!(report.quarantined, 1);
+
+    let details = db::get_workout_details(&pool, &manifest, "2024-07-01 08:00:00 +0000").await?;
+    // The workout's own statistic wins over its legs; a missing total is summed over them
+    assert_eq!(details["active_calories"], 820.0);
+    assert_eq!(details["distance_km"], 12.0);
+    assert_eq!(details["avg_hr"], 146.0);
+    assert_eq!(details["min_hr"], 95.0);
+    assert_eq!(details["max_hr"], 175.0);
+    assert_eq!(details["indoor"], 0);
+
+    let activities = details["activities"].as_array().unwrap();
+    assert_eq!(activities.len(), 2);
+    assert_eq!(activities[0]["activity_index"], 0);
+    assert_eq!(activities[0]["uuid"], "A1");
+    assert_eq!(
+        activities[0]["activity_type"],
+        "HKWorkoutActivityTypeSwimming"
+    );
+    assert_eq!(activities[0]["duration_minutes"], 30.0);
+    assert_eq!(activities[0]["active_calories"], 300.0);
+    assert_eq!(activities[0]["indoor"], 1);
+    assert_eq!(activities[0]["avg_hr"], 138.0);
+    assert_eq!(activities[0]["max_hr"], 160.0);
+    assert_eq!(activities[0]["distance_km"], serde_json::Value::Null);
+    assert_eq!(activities[1]["activity_type"], serde_json::Value::Null);
+    assert_eq!(activities[1]["start_date"], "2024-07-01T08:35:00+00:00");
+    assert_eq!(activities[1]["distance_km"], 12.0);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_element_mapping() -> anyhow::Result<()> {
+    // Workouts under another name and key, audiograms without a key, no activity summaries
+    let manifest_content = r#"
+[tables.sessions]
+element = "Workout"
+provenance = ["source_name"]
+columns = [
+    { name = "started", hk_attribute = "startDate", data_type = "TEXT", is_primary_key = true },
+    { name = "sport", hk_attribute = "workoutActivityType", data_type = "TEXT" },
+    { name = "calories", hk_identifier = "HKQuantityTypeIdentifierActiveEnergyBurned", data_type = "REAL", unit = "kcal", extraction_source = "statistics_sum" }
+]
+
+[tables.hearing]
+element = "Audiogram"
+columns = [
+    { name = "audiogram_type", hk_attribute = "type", data_type = "TEXT" },
+    { name = "environment", hk_identifier = "HKAudiogramEnvironment", data_type = "TEXT", extraction_source = "metadata_value" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("element_mapping", manifest_content).await?;
+    let db_url = common::db_url(&test_dir);
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let xml_content = r#"
+<HealthData>
+ <ActivitySummary dateComponents="2024-07-01" activeEnergyBurned="500"/>
+ <Workout workoutActivityType="HKWorkoutActivityTypeCycling" duration="30" durationUnit="min" sourceName="Watch" creationDate="2024-07-01 08:30:00 +0000" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 08:30:00 +0000">
+  <WorkoutStatistics type="HKQuantityTypeIdentifierActiveEnergyBurned" startDate="2024-07-01 08:00:00 +0000" endDate="2024-07-01 08:30:00 +0000" sum="1046" unit="kJ"/>
+ </Workout>
+ <Audiogram type="HKDataTypeIdentifierAudiogram" sourceName="Mimi" creationDate="2024-07-02 09:00:00 +0000" startDate="2024-07-02 09:00:00 +0000" endDate="2024-07-02 09:10:00 +0000">
+  <MetadataEntry key="HKAudiogramEnvironment" value="Quiet"/>
+  <SensitivityPoint frequencyValue="1000" frequencyUnit="Hz" leftEarValue="20" leftEarUnit="dBHL"/>
+ </Audiogram>
+ <Audiogram type="HKDataTypeIdentifierAudiogram" creationDate="2024-07-03 09:00:00 +0000" startDate="yesterday" endDate="2024-07-03 09:10:00 +0000"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    for _ in 0..2 {
+        parser::parse_and_ingest(Path::new(&xml_path), &pool, &manifest, None::<fn(usize)>).await?;
+    }
+
+    let details = db::get_workout_details(&pool, &manifest, "2024-07-01 08:00:00 +0000").await?;
+    assert_eq!(details["sport"], "HKWorkoutActivityTypeCycling");
+    assert_eq!(details["source_name"], "Watch");
+    assert_eq!(details["start_date"], "2024-07-01T08:00:00+00:00");
+    assert!((details["calories"].as_f64().unwrap() - 250.0).abs() < 0.01);
+
+    // Keyed by content, so ingesting twice keeps one row; the bad date is quarantined
+    let rows: Vec<(String, String, String, String)> =
+        sqlx::query_as("SELECT uuid, audiogram_type, environment, start_date FROM hearing")
+            .fetch_all(&pool)
+            .await?;
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].1, "HKDataTypeIdentifierAudiogram");
+    assert_eq!(rows[0].2, "Quiet");
+    assert_eq!(rows[0].3, "2024-07-02T09:00:00+00:00");
+    let (quarantined,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM quarantine WHERE element = 'Audiogram'")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(quarantined, 2);
+    pool.close().await;
+
+    // Only one table may read <Workout>
+    let duplicate = format!("{}/duplicate.toml", test_dir);
+    fs::write(
+        &duplicate,
+        format!("{}\n[tables.workouts]\ncolumns = []\n", manifest_content),
+    )?;
+    let err = db::init_db(&db_url, &duplicate).await.unwrap_err();
+    assert!(err.to_string().contains("<Workout>"), "{}", err);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_incremental_ingestion() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" },
+    { name = "resting_hr", hk_type = "HKQuantityTypeIdentifierRestingHeartRate", data_type = "REAL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("incremental", manifest_content).await?;
+    let first_path = format!("{}/export_1.xml", test_dir);
+    let second_path = format!("{}/export_2.xml", test_dir);
+
+    let first = r#"
+<HealthData>
+ <ExportDate value="2024-01-02 00:00:00 +0000"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 09:00:00 +0000" startDate="2024-01-01 09:00:00 +0000" endDate="2024-01-01 09:00:00 +0000" value="65"/>
+ <Record type="HKQuantityTypeIdentifierRestingHeartRate" creationDate="2024-01-01 23:00:00 +0000" startDate="2024-01-01 20:00:00 +0000" endDate="2024-01-01 23:00:00 +0000" value="52"/>
+</HealthData>
+"#;
+    // The full history again, one new sample, one old sample synced late, and one from another
+    // device created before the first export but missing from it
+    let second = r#"
+<HealthData>
+ <ExportDate value="2024-01-09 00:00:00 +0000"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 09:00:00 +0000" startDate="2024-01-01 09:00:00 +0000" endDate="2024-01-01 09:00:00 +0000" value="65"/>
+ <Record type="HKQuantityTypeIdentifierRestingHeartRate" creationDate="2024-01-01 23:00:00 +0000" startDate="2024-01-01 20:00:00 +0000" endDate="2024-01-01 23:00:00 +0000" value="52"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-05 10:00:00 +0000" startDate="2024-01-01 07:00:00 +0000" endDate="2024-01-01 07:00:00 +0000" value="58"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 10:00:00 +0000" startDate="2024-01-01 08:30:00 +0000" endDate="2024-01-01 08:30:00 +0000" value="61"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-08 10:00:00 +0000" startDate="2024-01-08 10:00:00 +0000" endDate="2024-01-08 10:00:00 +0000" value="70"/>
+</HealthData>
+"#;
+    fs::write(&first_path, first)?;
+    fs::write(&second_path, second)?;
+
+    let incremental = parser::IngestOptions {
+        incremental: true,
+        ..Default::default()
+    };
+
+    // Nothing to compare against yet
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&first_path),
+        &pool,
+        &manifest,
+        incremental,
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!(
+        (report.processed, report.inserted, report.skipped),
+        (3, 3, 0)
+    );
+
+    let watermarks = db::load_watermarks(&pool)
+        .await?
+        .expect("watermarks recorded");
+    assert_eq!(
+        watermarks.export_date.as_deref(),
+        Some("2024-01-02T00:00:00+00:00")
+    );
+    let heart_rate = (
+        "vitals".to_string(),
+        "HKQuantityTypeIdentifierHeartRate".to_string(),
+    );
+    let resting = (
+        "vitals".to_string(),
+        "HKQuantityTypeIdentifierRestingHeartRate".to_string(),
+    );
+    // Each type keeps its own, although both share the table
+    assert_eq!(watermarks.types[&heart_rate], "2024-01-01T09:00:00+00:00");
+    assert_eq!(watermarks.types[&resting], "2024-01-01T20:00:00+00:00");
+
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&second_path),
+        &pool,
+        &manifest,
+        incremental,
+        None::<fn(usize)>,
+    )
+    .await?;
+    // Only the rows already stored are skipped, however old the others are
+    assert_eq!(
+        (report.processed, report.inserted, report.skipped),
+        (6, 3, 3)
+    );
+
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "vitals", 100, None, &filter).await?;
+    assert_eq!(rows.len(), 6);
+    let watermarks = db::load_watermarks(&pool).await?.expect("watermarks");
+    assert_eq!(watermarks.types[&heart_rate], "2024-01-08T10:00:00+00:00");
+    assert_eq!(watermarks.types[&resting], "2024-01-01T20:00:00+00:00");
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_incremental_new_types() -> anyhow::Result<()> {
+    // Steps are not mapped yet and REM sleep has no code, so it is quarantined
+    let manifest_content = r#"
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }
+]
+
+[tables.sleep]
+columns = [
+    { name = "stage", hk_type = "HKCategoryTypeIdentifierSleepAnalysis", data_type = "INTEGER", categories = [
+        { hk_value = "HKCategoryValueSleepAnalysisAsleepDeep", code = 4, label = "Deep" }
+    ] }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("incremental_types", manifest_content).await?;
+    let db_url = common::db_url(&test_dir);
+    let manifest_path = format!("{}/manifest.toml", test_dir);
+    let first_path = format!("{}/export_1.xml", test_dir);
+    let second_path = format!("{}/export_2.xml", test_dir);
+
+    let records = r#"
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-03 08:00:00 +0000" startDate="2024-01-03 08:00:00 +0000" endDate="2024-01-03 08:00:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierStepCount" creationDate="2024-01-02 09:00:00 +0000" startDate="2024-01-02 08:00:00 +0000" endDate="2024-01-02 09:00:00 +0000" value="1200"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 01:00:00 +0000" endDate="2024-01-02 02:00:00 +0000" value="HKCategoryValueSleepAnalysisAsleepREM"/>
+ <Record type="HKCategoryTypeIdentifierSleepAnalysis" creationDate="2024-01-02 07:00:00 +0000" startDate="2024-01-02 03:00:00 +0000" endDate="2024-01-02 04:00:00 +0000" value="HKCategoryValueSleepAnalysisAsleepDeep"/>
+"#;
+    fs::write(
+        &first_path,
+        format!(
+            "<HealthData>\n <ExportDate value=\"2024-01-05 00:00:00 +0000\"/>{}</HealthData>",
+            records
+        ),
+    )?;
+    fs::write(
+        &second_path,
+        format!(
+            "<HealthData>\n <ExportDate value=\"2024-01-10 00:00:00 +0000\"/>{}</HealthData>",
+            records
+        ),
+    )?;
+    let incremental = parser::IngestOptions {
+        incremental: true,
+        ..Default::default()
+    };
+
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&first_path),
+        &pool,
+        &manifest,
+        incremental,
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!((report.processed, report.quarantined), (2, 1));
+    let watermarks = db::load_watermarks(&pool).await?.expect("watermarks");
+    assert!(!watermarks.types.contains_key(&(
+        "sleep".to_string(),
+        "HKCategoryTypeIdentifierSleepAnalysis".to_string()
+    )));
+    pool.close().await;
+
+    // Map steps, give REM a code and import the next export incrementally
+    let manifest_content = manifest_content
+        .replace(
+            "data_type = \"REAL\" }",
+            "data_type = \"REAL\" },\n    { name = \"steps\", hk_type = \"HKQuantityTypeIdentifierStepCount\", data_type = \"REAL\" }",
+        )
+        .replace(
+            "label = \"Deep\" }",
+            "label = \"Deep\" },\n        { hk_value = \"HKCategoryValueSleepAnalysisAsleepREM\", code = 5, label = \"REM\" }",
+        );
+    fs::write(&manifest_path, manifest_content)?;
+    let (pool, manifest) = db::init_db(&db_url, &manifest_path).await?;
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&second_path),
+        &pool,
+        &manifest,
+        incremental,
+        None::<fn(usize)>,
+    )
+    .await?;
+    // The heart rate and the deep sleep were stored the first time
+    assert_eq!(
+        (
+            report.processed,
+            report.inserted,
+            report.skipped,
+            report.quarantined
+        ),
+        (4, 2, 2, 0)
+    );
+    let (steps,): (f64,) = sqlx::query_as("SELECT steps FROM vitals WHERE steps IS NOT NULL")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(steps, 1200.0);
+    let (stages,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM sleep")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(stages, 2);
+
+    // Now every type has a watermark
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&second_path),
+        &pool,
+        &manifest,
+        incremental,
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!(
+        (report.processed, report.inserted, report.skipped),
+        (4, 0, 4)
+    );
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ingest_pipeline() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[settings]
+batch_size = 2
+
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("pipeline", manifest_content).await?;
+    let broken_path = format!("{}/broken.xml", test_dir);
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let records = |day: usize, count: usize| -> String {
+        (0..count)
+            .map(|i| {
+                format!(
+                    " <Record type=\"HKQuantityTypeIdentifierHeartRate\" startDate=\"2024-01-{3:02} {0:02}:{1:02}:00 +0000\" endDate=\"2024-01-{3:02} {0:02}:{1:02}:00 +0000\" value=\"{2}\"/>\n",
+                    i / 60,
+                    i % 60,
+                    60 + i % 40,
+                    day
+                )
+            })
+            .collect()
+    };
+    // Five good records, then XML the reader cannot get past
+    fs::write(
+        &broken_path,
+        format!(
+            "<HealthData>\n{} </Workout>\n</HealthData>\n",
+            records(1, 5)
+        ),
+    )?;
+    fs::write(
+        &xml_path,
+        format!("<HealthData>\n{}</HealthData>\n", records(2, 200)),
+    )?;
+
+    let count = |pool: &db::DbPool| {
+        let pool = pool.clone();
+        async move {
+            let (n,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM vitals")
+                .fetch_one(&pool)
+                .await?;
+            anyhow::Ok(n)
+        }
+    };
+
+    // In strict mode a parse error after two batches were sent fails the ingestion, but the
+    // batches already handed to the writer stay committed
+    let strict = parser::IngestOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let result = parser::parse_and_ingest_with_options(
+        Path::new(&broken_path),
+        &pool,
+        &manifest,
+        strict,
+        None::<fn(usize)>,
+    )
+    .await;
+    assert!(result.unwrap_err().to_string().contains("Malformed XML"));
+    assert_eq!(count(&pool).await?, 4);
+
+    // Otherwise everything before the error is written and the error is reported
+    let report = parser::parse_and_ingest_with_options(
+        Path::new(&broken_path),
+        &pool,
+        &manifest,
+        parser::IngestOptions::default(),
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert!(report.parse_error.is_some());
+    assert_eq!((report.processed, report.inserted), (5, 1));
+    assert_eq!(count(&pool).await?, 5);
+    // Neither attempt read the whole export, so no watermark was recorded
+    let (ingestions,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM ingestions")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(ingestions, 0);
+
+    // A slow writer holds the parser back: no more than PIPELINE_DEPTH batches ever queue
+    // up. The job is cancelled while they are queued; the writer stops after the batch in
+    // hand and the parser, blocked on the full channel, gives up.
+    jobs::create_job(
+        &pool,
+        "job-1",
+        "ingest",
+        &xml_path,
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    let job = jobs::IngestJob {
+        id: "job-1".to_string(),
+        input_path: xml_path.clone(),
+        options: parser::IngestOptions::default(),
+        checkpoint: jobs::Checkpoint::default(),
+        cancel: Default::default(),
+    };
+    let queued = std::sync::Mutex::new(Vec::new());
+    let report = parser::run_ingest_job(
+        &job,
+        &pool,
+        &manifest,
+        Some(|p: &parser::IngestProgress| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let mut queued = queued.lock().unwrap();
+            queued.push(p.queued_batches);
+            if queued.len() == 3 {
+                job.cancel.cancel(false);
+            }
+        }),
+    )
+    .await?;
+    assert!(report.cancelled);
+    // By the second batch the parser has long caught up and waits on the full channel
+    let queued = queued.into_inner().unwrap();
+    assert!(queued[0] <= parser::PIPELINE_DEPTH);
+    assert_eq!(queued[1..], [parser::PIPELINE_DEPTH; 2]);
+    assert_eq!((report.processed, report.inserted), (6, 6));
+    assert_eq!(count(&pool).await?, 11);
+    let job = jobs::get_job(&pool, "job-1").await?.unwrap();
+    assert_eq!(job.records_processed, 6);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_malformed_nested_elements() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }
+]
+
+[tables.workouts]
+columns = [
+    { name = "session_id", hk_attribute = "startDate", data_type = "DATETIME", is_primary_key = true, extraction_source = "attribute" },
+    { name = "indoor", hk_identifier = "HKIndoorWorkout", data_type = "INTEGER", extraction_source = "metadata_value" }
+]
+
+[tables.blood_pressure]
+correlation_type = "HKCorrelationTypeIdentifierBloodPressure"
+columns = [
+    { name = "systolic", hk_type = "HKQuantityTypeIdentifierBloodPressureSystolic", extraction_source = "correlation_member", data_type = "REAL", unit = "mmHg" },
+    { name = "diastolic", hk_type = "HKQuantityTypeIdentifierBloodPressureDiastolic", extraction_source = "correlation_member", data_type = "REAL", unit = "mmHg" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("malformed_nested", manifest_content).await?;
+    let record = |hour: u32| {
+        format!(
+            " <Record type=\"HKQuantityTypeIdentifierHeartRate\" startDate=\"2024-01-01 {0:02}:00:00 +0000\" endDate=\"2024-01-01 {0:02}:00:00 +0000\" value=\"60\"/>\n",
+            hour
+        )
+    };
+    // Malformed XML inside a <Workout> and inside a <Correlation> member, each between two
+    // records
+    let broken = [
+        (
+            "workout",
+            r#" <Workout workoutActivityType="HKWorkoutActivityTypeRunning" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:30:00 +0000">
+  <MetadataEntry key="HKIndoorWorkout" value="0"/>
+  <WorkoutEvent type="HKWorkoutEventTypePause" date="2024-01-01 10:10:00 +0000"></Wrong>
+ </Workout>
+"#,
+        ),
+        (
+            "correlation",
+            r#" <Correlation type="HKCorrelationTypeIdentifierBloodPressure" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:00:00 +0000">
+  <Record type="HKQuantityTypeIdentifierBloodPressureSystolic" unit="mmHg" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:00:00 +0000" value="121">
+   <MetadataEntry key="HKWasUserEntered" value="0">
+  </Record>
+ </Correlation>
+"#,
+        ),
+    ];
+    for (hour, (name, element)) in broken.iter().enumerate() {
+        let xml_path = format!("{}/{}.xml", test_dir, name);
+        let hour = hour as u32 * 2;
+        fs::write(
+            &xml_path,
+            format!(
+                "<HealthData>\n{}{}{}</HealthData>\n",
+                record(hour),
+                element,
+                record(hour + 1)
+            ),
+        )?;
+
+        // Strict mode fails the ingestion
+        let strict = parser::IngestOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let result = parser::parse_and_ingest_with_options(
+            Path::new(&xml_path),
+            &pool,
+            &manifest,
+            strict,
+            None::<fn(usize)>,
+        )
+        .await;
+        assert!(result.unwrap_err().to_string().contains("Malformed XML"));
+
+        // Otherwise the record before the broken element is kept and the error reported
+        let report = parser::parse_and_ingest_with_options(
+            Path::new(&xml_path),
+            &pool,
+            &manifest,
+            parser::IngestOptions::default(),
+            None::<fn(usize)>,
+        )
+        .await?;
+        assert!(report
+            .parse_error
+            .as_deref()
+            .is_some_and(|e| e.starts_with("Malformed XML at byte")));
+        assert_eq!((report.processed, report.inserted), (1, 1));
+    }
+
+    let (vitals,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM vitals")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(vitals, 2);
+    for table in ["workouts", "blood_pressure"] {
+        let (n,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(n, 0);
+    }
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resume_interrupted_job() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[settings]
+batch_size = 2
+
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("resume", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let mut xml_content =
+        String::from("<HealthData>\n <ExportDate value=\"2024-01-02 00:00:00 +0000\"/>\n");
+    for hour in 0..4 {
+        xml_content.push_str(&format!(
+            " <Record type=\"HKQuantityTypeIdentifierHeartRate\" creationDate=\"2024-01-01 0{0}:00:00 +0000\" startDate=\"2024-01-01 0{0}:00:00 +0000\" endDate=\"2024-01-01 0{0}:00:00 +0000\" value=\"6{0}\"/>\n",
+            hour
+        ));
+    }
+    xml_content.push_str("</HealthData>\n");
+    fs::write(&xml_path, &xml_content)?;
+    // Byte position right after the second record
+    let second_end = xml_content.match_indices("/>").nth(2).unwrap().0 + 2;
+
+    // A crash after the first batch committed: the job is still "processing" and its
+    // checkpoint points past the rows that made it in
+    let request = serde_json::json!({ "file_path": xml_path, "incremental": false });
+    jobs::create_job(
+        &pool,
+        "job-1",
+        "ingest",
+        &xml_path,
+        parser::IngestOptions::default(),
+        &request,
+        Some("req-42"),
+    )
+    .await?;
+    sqlx::query(
+        "UPDATE jobs SET checkpoint_offset = ?, export_date = '2024-01-02T00:00:00+00:00', records_processed = 2, records_new = 2, table_counts = '{\"vitals\":2}' WHERE id = 'job-1'",
+    )
+    .bind(second_end as i64)
+    .execute(&pool)
+    .await?;
+
+    let interrupted = jobs::interrupted_jobs(&pool).await?;
+    assert_eq!(interrupted.len(), 1);
+    assert_eq!(interrupted[0].id, "job-1");
+    assert_eq!(interrupted[0].checkpoint.offset, second_end as u64);
+
+    let updates = std::sync::Mutex::new(Vec::new());
+    let report = parser::run_ingest_job(
+        &interrupted[0],
+        &pool,
+        &manifest,
+        Some(|p: &parser::IngestProgress| updates.lock().unwrap().push(p.clone())),
+    )
+    .await?;
+    assert_eq!((report.processed, report.inserted), (4, 4));
+
+    // Progress counts bytes from the start of the file, including the resumed part
+    let updates = updates.into_inner().unwrap();
+    assert_eq!(updates.len(), 1);
+    let fourth_end = xml_content.match_indices("/>").nth(4).unwrap().0 + 2;
+    assert_eq!(updates[0].bytes_read, fourth_end as u64);
+    assert_eq!(updates[0].total_bytes, Some(xml_content.len() as u64));
+    assert_eq!(updates[0].records_processed, 4);
+    assert_eq!(updates[0].tables["vitals"], 4);
+    let fraction = updates[0].fraction().unwrap();
+    assert!(fraction > 0.9 && fraction < 1.0);
+    assert_eq!(
+        report.export_date.as_deref(),
+        Some("2024-01-02T00:00:00+00:00")
+    );
+
+    // Only the records after the checkpoint were parsed again
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "vitals", 100, None, &filter).await?;
+    let values: Vec<f64> = rows
+        .iter()
+        .filter_map(|r| r["heart_rate"].as_f64())
+        .collect();
+    assert_eq!(values, vec![63.0, 62.0]);
+
+    // The final batch checkpointed the end of the document
+    let offset: (i64, i64) =
+        sqlx::query_as("SELECT checkpoint_offset, records_processed FROM jobs WHERE id = 'job-1'")
+            .fetch_one(&pool)
+            .await?;
+    assert_eq!(offset, (xml_content.len() as i64, 4));
+
+    jobs::finish_job(&pool, "job-1", "completed", None).await?;
+    assert!(jobs::interrupted_jobs(&pool).await?.is_empty());
+
+    // The finished job stays in the history with its request and per-table counts
+    let job = jobs::get_job(&pool, "job-1").await?.unwrap();
+    assert_eq!(job.status, "completed");
+    assert_eq!(job.request_id.as_deref(), Some("req-42"));
+    assert_eq!(job.request, Some(request));
+    assert_eq!(job.table_counts.unwrap()["vitals"], 4);
+    assert!(job.finished_at.is_some());
+
+    jobs::create_job(
+        &pool,
+        "job-2",
+        "external_import",
+        "test_export",
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    jobs::finish_job(&pool, "job-2", "failed", Some("boom")).await?;
+
+    let (all, total) = jobs::list_jobs(&pool, &jobs::JobFilter::default(), 50, 0).await?;
+    assert_eq!(total, 2);
+    assert_eq!(all[0].id, "job-2");
+    let filter = jobs::JobFilter {
+        status: Some("failed".to_string()),
+        ..Default::default()
+    };
+    let (failed, total) = jobs::list_jobs(&pool, &filter, 50, 0).await?;
+    assert_eq!(total, 1);
+    assert_eq!(failed[0].error.as_deref(), Some("boom"));
+    let (page, total) = jobs::list_jobs(&pool, &jobs::JobFilter::default(), 1, 1).await?;
+    assert_eq!((page.len(), total), (1, 2));
+    assert_eq!(page[0].id, "job-1");
+
+    // An ECG/route import cut short by a crash cannot be resumed, so startup fails it
+    jobs::create_job(
+        &pool,
+        "job-3",
+        "external_import",
+        "electrocardiograms",
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    let state = std::sync::Arc::new(api::AppState::new(pool.clone(), manifest.clone()));
+    api::resume_interrupted_jobs(&state).await?;
+    let job = jobs::get_job(&pool, "job-3").await?.unwrap();
+    assert_eq!(job.status, "failed");
+    assert_eq!(
+        job.error.as_deref(),
+        Some("Interrupted by a server restart")
+    );
+    assert!(job.finished_at.is_some());
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancel_job_with_rollback() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[settings]
+batch_size = 3
+
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }
+]
+
+[tables.readings]
+element = "Reading"
+columns = [
+    { name = "reading_id", hk_attribute = "id", data_type = "INTEGER", is_primary_key = true },
+    { name = "level", hk_attribute = "level", data_type = "REAL" }
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("cancel", manifest_content).await?;
+    let earlier_path = format!("{}/earlier.xml", test_dir);
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    let record = |hour: u32| {
+        format!(
+            " <Record type=\"HKQuantityTypeIdentifierHeartRate\" creationDate=\"2024-01-01 0{0}:00:00 +0000\" startDate=\"2024-01-01 0{0}:00:00 +0000\" endDate=\"2024-01-01 0{0}:00:00 +0000\" value=\"6{0}\"/>\n",
+            hour
+        )
+    };
+    fs::write(
+        &earlier_path,
+        format!("<HealthData>\n{}</HealthData>\n", record(0)),
+    )?;
+    let records: String = (0..4).map(record).collect();
+    fs::write(
+        &xml_path,
+        format!(
+            "<HealthData>\n <ExportDate value=\"2024-01-02 00:00:00 +0000\"/>\n{}</HealthData>\n",
+            records
+        ),
+    )?;
+
+    parser::parse_and_ingest(
+        Path::new(&earlier_path),
+        &pool,
+        &manifest,
+        None::<fn(usize)>,
+    )
+    .await?;
+
+    // Cancelled before it starts, the job still commits its first batch and stops there
+    jobs::create_job(
+        &pool,
+        "job-1",
+        "ingest",
+        &xml_path,
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    let job = jobs::IngestJob {
+        id: "job-1".to_string(),
+        input_path: xml_path.clone(),
+        options: parser::IngestOptions::default(),
+        checkpoint: jobs::Checkpoint::default(),
+        cancel: Default::default(),
+    };
+    job.cancel.cancel(true);
+    let report =
+        parser::run_ingest_job(&job, &pool, &manifest, None::<fn(&parser::IngestProgress)>).await?;
+    assert!(report.cancelled);
+    assert_eq!((report.processed, report.inserted), (3, 2));
+    // The batch's rowids are journaled together
+    let (journal,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM job_row_ids")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(journal, 1);
+
+    // Rolling back removes the job's own rows but keeps the ones imported before and after
+    let later_path = format!("{}/later.xml", test_dir);
+    fs::write(
+        &later_path,
+        format!("<HealthData>\n{}</HealthData>\n", record(5)),
+    )?;
+    parser::parse_and_ingest(Path::new(&later_path), &pool, &manifest, None::<fn(usize)>).await?;
+    assert_eq!(jobs::rollback_job(&pool, "job-1").await?, 2);
+    jobs::finish_job(&pool, "job-1", "cancelled", None).await?;
+    let filter = db::RowFilter::default();
+    let rows = db::query_table(&pool, &manifest, "vitals", 100, None, &filter).await?;
+    let values: Vec<f64> = rows
+        .iter()
+        .filter_map(|r| r["heart_rate"].as_f64())
+        .collect();
+    assert_eq!(values, vec![65.0, 60.0]);
+
+    let job = jobs::get_job(&pool, "job-1").await?.unwrap();
+    assert_eq!(job.status, "cancelled");
+    assert!(job.rolled_back);
+
+    // A cancelled import leaves no watermark behind, only the two complete imports do
+    let (ingestions,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM ingestions")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(ingestions, 2);
+    let (journal,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM job_row_ids")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(journal, 0);
+
+    // The rowid of an INTEGER PRIMARY KEY is the key itself, so another import's row can sit
+    // between two of the job's. Only the job's own rows are taken back.
+    let other_path = format!("{}/other.xml", test_dir);
+    fs::write(
+        &other_path,
+        "<HealthData>\n <Reading id=\"20\" level=\"2\"/>\n</HealthData>\n",
+    )?;
+    parser::parse_and_ingest(Path::new(&other_path), &pool, &manifest, None::<fn(usize)>).await?;
+    let readings_path = format!("{}/readings.xml", test_dir);
+    fs::write(
+        &readings_path,
+        "<HealthData>\n <Reading id=\"10\" level=\"1\"/>\n <Reading id=\"30\" level=\"3\"/>\n</HealthData>\n",
+    )?;
+    jobs::create_job(
+        &pool,
+        "job-2",
+        "ingest",
+        &readings_path,
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    let job = jobs::IngestJob {
+        id: "job-2".to_string(),
+        input_path: readings_path.clone(),
+        options: parser::IngestOptions::default(),
+        checkpoint: jobs::Checkpoint::default(),
+        cancel: Default::default(),
+    };
+    job.cancel.cancel(true);
+    let report =
+        parser::run_ingest_job(&job, &pool, &manifest, None::<fn(&parser::IngestProgress)>).await?;
+    assert_eq!(report.inserted, 2);
+    assert_eq!(jobs::rollback_job(&pool, "job-2").await?, 2);
+    let readings: Vec<(i64,)> = sqlx::query_as("SELECT reading_id FROM readings")
+        .fetch_all(&pool)
+        .await?;
+    assert_eq!(readings, vec![(20,)]);
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_quarantine_and_strict_mode() -> anyhow::Result<()> {
+    let manifest_content = r#"
+[tables.vitals]
+columns = [
+    { name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" },
+    { name = "body_mass", hk_type = "HKQuantityTypeIdentifierBodyMass", data_type = "REAL", unit = "kg" },
+]
+"#;
+    let (pool, manifest, test_dir) = common::setup("quarantine", manifest_content).await?;
+    let xml_path = format!("{}/export.xml", test_dir);
+
+    // Three records that cannot be stored faithfully, then XML the reader cannot recover from
+    let xml_content = r#"
+<HealthData>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 10:00:00 +0000" startDate="2024-01-01 10:00:00 +0000" endDate="2024-01-01 10:00:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 11:00:00 +0000" startDate="2024-01-01 11:00:00 +0000" endDate="2024-01-01 11:00:00 +0000" value="abc"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 12:00:00 +0000" startDate="yesterday" endDate="2024-01-01 12:00:00 +0000" value="61"/>
+ <Record type="HKQuantityTypeIdentifierBodyMass" unit="stone" creationDate="2024-01-01 13:00:00 +0000" startDate="2024-01-01 13:00:00 +0000" endDate="2024-01-01 13:00:00 +0000" value="11"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 14:00:00 +0000" startDate="2024-01-01 14:00:00 +0000" endDate="2024-01-01 14:00:00 +0000" value="62"/>
+ <Broken></Wrong>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 15:00:00 +0000" startDate="2024-01-01 15:00:00 +0000" endDate="2024-01-01 15:00:00 +0000" value="63"/>
+</HealthData>
+"#;
+    fs::write(&xml_path, xml_content)?;
+
+    jobs::create_job(
+        &pool,
+        "job-q",
+        "ingest",
+        &xml_path,
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    let job = jobs::IngestJob {
+        id: "job-q".to_string(),
+        input_path: xml_path.clone(),
+        options: parser::IngestOptions::default(),
+        checkpoint: jobs::Checkpoint::default(),
+        cancel: Default::default(),
+    };
+    let report =
+        parser::run_ingest_job(&job, &pool, &manifest, None::<fn(&parser::IngestProgress)>).await?;
+    assert_eq!((report.processed, report.quarantined), (2, 3));
+    assert!(report
+        .parse_error
+        .as_deref()
+        .is_some_and(|e| e.starts_with("Malformed XML at byte")));
+    // A file that was cut short must not move the incremental watermarks
+    assert!(db::load_watermarks(&pool).await?.is_none());
+
+    let (records, total) = quarantine::list_quarantine(&pool, "job-q", 100, 0).await?;
+    assert_eq!(total, 3);
+    let reasons: Vec<&str> = records.iter().map(|r| r.reason.as_str()).collect();
+    assert_eq!(
+        reasons,
+        [
+            "non-numeric value 'abc' for a REAL column",
+            "unparseable startDate 'yesterday'",
+            "cannot convert unit 'stone' to 'kg'"
+        ]
+    );
+    // Each offset leads straight to the quarantined element
+    for record in &records {
+        let at = xml_content[record.byte_offset as usize..].trim_start();
+        assert!(at.starts_with(record.raw.trim_end_matches("/>").trim_end_matches('>')));
+    }
+    assert_eq!(records[0].element, "Record");
+    assert_eq!(
+        records[2].hk_type.as_deref(),
+        Some("HKQuantityTypeIdentifierBodyMass")
+    );
+    let counts = quarantine::quarantine_counts(&pool, "job-q").await?;
+    assert_eq!(counts[0].count, 2);
+
+    let stored = jobs::get_job(&pool, "job-q").await?.unwrap();
+    assert_eq!(stored.records_quarantined, 3);
+
+    // Rolling the job back discards its quarantine as well
+    jobs::rollback_job(&pool, "job-q").await?;
+    assert_eq!(
+        quarantine::list_quarantine(&pool, "job-q", 100, 0).await?.1,
+        0
+    );
+
+    // Strict mode fails on the first bad record instead
+    let strict = parser::IngestOptions {
+        strict: true,
+        ..Default::default()
+    };
+    let err = parser::parse_and_ingest_with_options(
+        Path::new(&xml_path),
+        &pool,
+        &manifest,
+        strict,
+        None::<fn(usize)>,
+    )
+    .await
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("non-numeric value 'abc' for a REAL column"));
+
+    pool.close().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_upload_spooling() -> anyhow::Result<()> {
+    let test_dir = &common::fresh_dir("uploads")?;
+    let dir = Path::new(test_dir).join("uploads");
+    let chunks =
+        || futures::stream::iter(["<HealthData>", "</HealthData>"].map(Ok::<_, std::io::Error>));
+    // sha256 of "<HealthData></HealthData>"
+    let sha256 = {
+        use sha2::Digest;
+        format!("{:x}", sha2::Sha256::digest(b"<HealthData></HealthData>"))
+    };
+
+    // Directories in the client's name are dropped
+    let upload = uploads::store_upload(
+        &dir,
+        "../../etc/export.xml",
+        chunks(),
+        1024,
+        Some(&sha256.to_uppercase()),
+    )
+    .await?;
+    assert_eq!(upload.file_name, "export.xml");
+    assert_eq!(upload.kind, uploads::UploadKind::Export);
+    assert_eq!((upload.size, upload.sha256.as_str()), (25, sha256.as_str()));
+    assert_eq!(upload.path.parent(), Some(dir.as_path()));
+    assert_eq!(
+        fs::read_to_string(&upload.path)?,
+        "<HealthData></HealthData>"
+    );
+
+    assert_eq!(
+        uploads::classify("workout.GPX")?.1,
+        uploads::UploadKind::Route
+    );
+    assert!(uploads::classify("notes.txt").is_err());
+    assert!(uploads::classify("..").is_err());
+
+    // Oversized and corrupted uploads leave nothing behind
+    assert!(
+        uploads::store_upload(&dir, "export.xml", chunks(), 20, None)
+            .await
+            .is_err()
+    );
+    assert!(
+        uploads::store_upload(&dir, "export.xml", chunks(), 1024, Some("00ff"))
+            .await
+            .is_err()
+    );
+    assert_eq!(fs::read_dir(&dir)?.count(), 1);
+
+    // Server-side paths are confined to the upload directory and the import_dirs
+    let imports = Path::new(test_dir).join("imports");
+    fs::create_dir_all(&imports)?;
+    fs::write(imports.join("export.xml"), "<HealthData></HealthData>")?;
+    fs::write(Path::new(test_dir).join("secret.xml"), "<HealthData/>")?;
+    let manifest: db::Manifest = toml::from_str(&format!(
+        "[settings]\nupload_dir = \"{}/uploads\"\nimport_dirs = [\"{}/imports\"]\n[tables]\n",
+        test_dir, test_dir
+    ))?;
+    let allowed = |path: &Path| uploads::allowed_input_path(&manifest, &path.to_string_lossy());
+    assert!(allowed(&upload.path).is_ok());
+    assert!(allowed(&imports.join("export.xml")).is_ok());
+    assert!(allowed(&Path::new(test_dir).join("secret.xml")).is_err());
+    assert!(allowed(&imports.join("../secret.xml")).is_err());
+    assert!(allowed(&imports.join("missing.xml")).is_err());
+
+    // Imported uploads are deleted, files given by path are not
+    uploads::discard_upload(&manifest, &imports.join("export.xml")).await;
+    assert!(imports.join("export.xml").exists());
+    uploads::discard_upload(&manifest, &upload.path).await;
+    assert!(!upload.path.exists());
+
+    Ok(())
+}
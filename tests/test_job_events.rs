@@ -0,0 +1,232 @@
+use axum::body::{to_bytes, Body};
+use axum::http::{header, Request, StatusCode};
+use axum::Router;
+use backend::api::{self, AppState, JobEvent, JobStatus};
+use backend::{jobs, parser};
+use std::fs;
+use std::sync::Arc;
+use tower::ServiceExt;
+
+mod common;
+
+fn processing(progress: usize) -> JobEvent {
+    JobEvent::Progress(JobStatus::Processing {
+        progress,
+        total: None,
+        percent: None,
+        bytes_read: None,
+        total_bytes: None,
+        records_per_second: None,
+        eta_seconds: None,
+        tables: Default::default(),
+    })
+}
+
+// (event name, data) of every event in a complete SSE body; keep-alive comments are skipped
+fn sse_events(body: &[u8]) -> Vec<(String, serde_json::Value)> {
+    String::from_utf8_lossy(body)
+        .split("\n\n")
+        .filter_map(|block| {
+            let mut name = None;
+            let mut data = None;
+            for line in block.lines() {
+                if let Some(value) = line.strip_prefix("event: ") {
+                    name = Some(value.to_string());
+                } else if let Some(value) = line.strip_prefix("data: ") {
+                    data = serde_json::from_str(value).ok();
+                }
+            }
+            Some((name?, data?))
+        })
+        .collect()
+}
+
+async fn get(app: &Router, uri: &str) -> anyhow::Result<axum::response::Response> {
+    let request = Request::get(uri).body(Body::empty())?;
+    Ok(app.clone().oneshot(request).await?)
+}
+
+async fn post_json(
+    app: &Router,
+    uri: &str,
+    body: serde_json::Value,
+) -> anyhow::Result<(StatusCode, Vec<u8>)> {
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))?;
+    let response = app.clone().oneshot(request).await?;
+    let status = response.status();
+    Ok((
+        status,
+        to_bytes(response.into_body(), usize::MAX).await?.to_vec(),
+    ))
+}
+
+#[tokio::test]
+async fn test_job_events_stream() -> anyhow::Result<()> {
+    let test_dir = common::test_dir("job_events");
+    let import_dir = format!("{}/imports", test_dir);
+    let manifest_content = format!(
+        r#"
+[settings]
+import_dirs = ["{}"]
+upload_dir = "{}/uploads"
+
+[tables.vitals]
+columns = [
+    {{ name = "heart_rate", hk_type = "HKQuantityTypeIdentifierHeartRate", data_type = "REAL" }}
+]
+"#,
+        import_dir, test_dir
+    );
+    let (pool, manifest, test_dir) = common::setup("job_events", &manifest_content).await?;
+    let manifest_path = format!("{}/manifest.toml", test_dir);
+    let xml_path = format!("{}/export.xml", import_dir);
+    fs::create_dir_all(&import_dir)?;
+    fs::write(
+        &xml_path,
+        r#"<HealthData>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 08:00:00 +0000" startDate="2024-01-01 08:00:00 +0000" endDate="2024-01-01 08:00:00 +0000" value="60"/>
+ <Record type="HKQuantityTypeIdentifierHeartRate" creationDate="2024-01-01 08:01:00 +0000" startDate="2024-01-01 08:01:00 +0000" endDate="2024-01-01 08:01:00 +0000" value="62"/>
+</HealthData>
+"#,
+    )?;
+
+    let state = Arc::new(AppState::new(pool.clone(), manifest));
+    let app = api::router(Arc::clone(&state));
+
+    // A subscriber gets the events in the order they were published, the final one last.
+    // It falls more than the channel's capacity behind, so the oldest progress is skipped
+    // rather than failing the stream.
+    jobs::create_job(
+        &pool,
+        "live",
+        "ingest",
+        &xml_path,
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    state.register_job("live", None);
+    let response = get(&app, "/api/ingest/live/events").await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "text/event-stream"
+    );
+    for progress in 1..=300 {
+        state.publish("live", processing(progress));
+    }
+    state.publish(
+        "live",
+        JobEvent::Warning("1 records quarantined".to_string()),
+    );
+    jobs::finish_job(&pool, "live", "completed", None).await?;
+    state.unregister_job("live").await;
+
+    let events = sse_events(&to_bytes(response.into_body(), usize::MAX).await?);
+    let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+    assert!(names.len() < 302);
+    assert_eq!(names[names.len() - 2..], ["warning", "finished"]);
+    assert!(names[..names.len() - 2].iter().all(|n| *n == "progress"));
+    let progress: Vec<u64> = events
+        .iter()
+        .filter_map(|(_, data)| data["progress"].as_u64())
+        .collect();
+    assert!(progress[0] > 1);
+    assert!(progress.windows(2).all(|w| w[1] == w[0] + 1));
+    assert_eq!(progress.last(), Some(&300));
+    assert_eq!(events.last().unwrap().1["status"], "completed");
+
+    // A late subscriber starts from the latest progress
+    jobs::create_job(
+        &pool,
+        "late",
+        "ingest",
+        &xml_path,
+        parser::IngestOptions::default(),
+        &serde_json::Value::Null,
+        None,
+    )
+    .await?;
+    state.register_job("late", None);
+    state.publish("late", processing(1));
+    state.publish("late", processing(2));
+    let response = get(&app, "/api/ingest/late/events").await?;
+    state.publish("late", processing(3));
+    jobs::finish_job(&pool, "late", "failed", Some("disk full")).await?;
+    state.unregister_job("late").await;
+    let events = sse_events(&to_bytes(response.into_body(), usize::MAX).await?);
+    let summary: Vec<(&str, &serde_json::Value)> = events
+        .iter()
+        .map(|(name, data)| (name.as_str(), &data["progress"]))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("progress", &serde_json::json!(2)),
+            ("progress", &serde_json::json!(3)),
+            ("finished", &serde_json::Value::Null)
+        ]
+    );
+    assert_eq!(events[2].1["error"], "disk full");
+
+    // A real ingestion through the API: its stream ends with the outcome, and once the job
+    // is done a new subscriber only gets that final event
+    let (status, body) = post_json(
+        &app,
+        "/ingest",
+        serde_json::json!({ "file_path": xml_path }),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    let job_id = serde_json::from_slice::<serde_json::Value>(&body)?["job_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let uri = format!("/api/ingest/{}/events", job_id);
+    let response = get(&app, &uri).await?;
+    let events = sse_events(&to_bytes(response.into_body(), usize::MAX).await?);
+    let (name, data) = events.last().unwrap();
+    assert_eq!(
+        (name.as_str(), &data["status"]),
+        ("finished", &"completed".into())
+    );
+    assert_eq!(data["records_new"], 2);
+
+    let response = get(&app, &uri).await?;
+    let events = sse_events(&to_bytes(response.into_body(), usize::MAX).await?);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].0, "finished");
+    assert_eq!(events[0].1["records_processed"], 2);
+
+    // The external import scans the configured import directories, not a fixed folder
+    let (status, body) = post_json(&app, "/api/import/external", serde_json::Value::Null).await?;
+    assert_eq!(status, StatusCode::OK);
+    let job_id = serde_json::from_slice::<serde_json::Value>(&body)?["job_id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let response = get(&app, &format!("/api/ingest/{}/events", job_id)).await?;
+    let events = sse_events(&to_bytes(response.into_body(), usize::MAX).await?);
+    assert_eq!(events.last().unwrap().1["status"], "completed");
+    let job = jobs::get_job(&pool, &job_id).await?.unwrap();
+    assert_eq!(job.job_type, "external_import");
+    assert_eq!(job.input_path.as_deref(), Some(import_dir.as_str()));
+
+    // Unknown jobs and paths outside the import directories are refused
+    let response = get(&app, "/api/ingest/unknown/events").await?;
+    let body = to_bytes(response.into_body(), usize::MAX).await?;
+    assert_eq!(body, "Job ID unknown not found");
+    let (_, body) = post_json(
+        &app,
+        "/ingest",
+        serde_json::json!({ "file_path": manifest_path }),
+    )
+    .await?;
+    assert!(String::from_utf8_lossy(&body).contains("outside the upload directory"));
+
+    pool.close().await;
+    Ok(())
+}
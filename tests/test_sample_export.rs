@@ -96,7 +96,13 @@ async fn test_sample_export_ingestion() -> anyhow::Result<()> {
 
     // 5. Ingest External Files
     println!("Scanning external files in {:?}", base_dir);
-    importer::run_external_import(base_dir, &pool, &manifest).await?;
+    importer::run_external_import(
+        base_dir,
+        &pool,
+        &manifest,
+        None::<fn(&importer::ImportedFile)>,
+    )
+    .await?;
 
     // 6. Verification
 
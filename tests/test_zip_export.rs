@@ -0,0 +1,131 @@
+use backend::{db, parser};
+use sqlx::Row;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+#[tokio::test]
+async fn test_zip_export_ingestion() -> anyhow::Result<()> {
+    // Setup Paths
+    let base_dir = Path::new("target/test_zip_export");
+    if base_dir.exists() {
+        fs::remove_dir_all(base_dir)?;
+    }
+    fs::create_dir_all(base_dir)?;
+
+    // 1. Build an export.zip with the same layout the iPhone produces
+    let zip_path = base_dir.join("export.zip");
+    {
+        let mut zip = ZipWriter::new(File::create(&zip_path)?);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        zip.start_file("apple_health_export/export.xml", options)?;
+        writeln!(zip, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(zip, "<HealthData locale=\"en_US\">")?;
+        writeln!(zip, " <ExportDate value=\"2024-01-01 12:00:00 -0500\"/>")?;
+        for i in 0..20 {
+            writeln!(
+                zip,
+                " <Record type=\"HKQuantityTypeIdentifierHeartRate\" sourceName=\"Watch\" unit=\"count/min\" creationDate=\"2024-01-01 10:{0:02}:00 -0500\" startDate=\"2024-01-01 10:{0:02}:00 -0500\" endDate=\"2024-01-01 10:{0:02}:00 -0500\" value=\"{1}\"/>",
+                i,
+                60 + i
+            )?;
+        }
+        writeln!(zip, "</HealthData>")?;
+
+        // Clinical records sit next to export.xml and must not be picked up instead of it
+        zip.start_file("apple_health_export/export_cda.xml", options)?;
+        writeln!(zip, "<ClinicalDocument/>")?;
+
+        // Not UTF-8, so it cannot be read; the other files are still imported
+        zip.start_file(
+            "apple_health_export/electrocardiograms/ecg_2023-12-31.csv",
+            options,
+        )?;
+        zip.write_all(b"Recorded Date,\xff\xfe\n")?;
+
+        zip.start_file(
+            "apple_health_export/electrocardiograms/ecg_2024-01-01.csv",
+            options,
+        )?;
+        writeln!(zip, "Recorded Date,\"2024-01-01 12:00:00 -0500\"")?;
+        writeln!(zip, "Sample Rate,512 Hz")?;
+        writeln!(zip, "Classification,Sinus Rhythm")?;
+        for i in 0..10 {
+            writeln!(zip, "{}", (i as f64).sin())?;
+        }
+
+        zip.start_file(
+            "apple_health_export/workout-routes/route_2024-01-01.gpx",
+            options,
+        )?;
+        writeln!(zip, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        writeln!(zip, "<gpx version=\"1.1\"><trk><trkseg>")?;
+        writeln!(zip, "<trkpt lat=\"37.7749\" lon=\"-122.4194\"><ele>10.0</ele><time>2024-01-01T12:00:00Z</time></trkpt>")?;
+        writeln!(zip, "<trkpt lat=\"37.7750\" lon=\"-122.4195\"><ele>11.0</ele><time>2024-01-01T12:00:05Z</time></trkpt>")?;
+        writeln!(zip, "</trkseg></trk></gpx>")?;
+
+        zip.finish()?;
+    }
+
+    // Initialize DB
+    let test_db_path = base_dir.join("test.db");
+    let db_url = format!("sqlite:{}?mode=rwc", test_db_path.display());
+    let (pool, manifest) = db::init_db(&db_url, "metrics_manifest.toml").await?;
+
+    // 2. Ingest straight from the archive, side-car files included
+    let report = parser::parse_and_ingest_with_options(
+        &zip_path,
+        &pool,
+        &manifest,
+        parser::IngestOptions::default(),
+        None::<fn(usize)>,
+    )
+    .await?;
+    assert_eq!(report.processed, 20);
+    let side_cars: Vec<(&str, bool)> = report
+        .side_cars
+        .iter()
+        .map(|f| (f.file_name.as_str(), f.error.is_some()))
+        .collect();
+    assert_eq!(
+        side_cars,
+        vec![
+            ("ecg_2023-12-31.csv", true),
+            ("ecg_2024-01-01.csv", false),
+            ("route_2024-01-01.gpx", false)
+        ]
+    );
+
+    // 3. Verification
+    let vitals_count: i64 = sqlx::query("SELECT count(*) FROM vitals")
+        .fetch_one(&pool)
+        .await?
+        .get(0);
+    assert_eq!(vitals_count, 20);
+
+    let ecg_file: String = sqlx::query("SELECT file_name FROM ecg_recordings")
+        .fetch_one(&pool)
+        .await?
+        .get(0);
+    assert_eq!(ecg_file, "ecg_2024-01-01.csv");
+
+    let route_count: i64 = sqlx::query("SELECT count(*) FROM route_points")
+        .fetch_one(&pool)
+        .await?
+        .get(0);
+    assert_eq!(route_count, 2);
+
+    // Re-importing the same archive must not duplicate side-car files
+    parser::parse_and_ingest(&zip_path, &pool, &manifest, None::<fn(usize)>).await?;
+    let route_count: i64 = sqlx::query("SELECT count(*) FROM route_points")
+        .fetch_one(&pool)
+        .await?
+        .get(0);
+    assert_eq!(route_count, 2);
+
+    pool.close().await;
+    Ok(())
+}